@@ -0,0 +1,440 @@
+use clap::Command;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::config::{read_table, user_config_path};
+use crate::metadata::discover_project_toml;
+use crate::output::CommandReport;
+
+// Aliases referring to other aliases are followed this deep at most
+const MAX_ALIAS_DEPTH: usize = 16;
+
+// Either `ship = "deploy web --dry-run"` or `ship = ["deploy", "web"]`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum AliasValue {
+    Line(String),
+    Args(Vec<String>),
+}
+
+impl AliasValue {
+    pub fn args(&self) -> Result<Vec<String>, String> {
+        match self {
+            AliasValue::Line(line) => split_args(line),
+            AliasValue::Args(args) => Ok(args.clone()),
+        }
+    }
+}
+
+// The `[aliases]` table and `default_command` of a config file
+#[derive(Deserialize, Default)]
+struct AliasTables {
+    #[serde(default)]
+    aliases: BTreeMap<String, AliasValue>,
+    default_command: Option<AliasValue>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Alias {
+    pub name: String,
+    pub args: Vec<String>,
+    // File the alias was defined in
+    pub source: PathBuf,
+}
+
+#[derive(Serialize, Default)]
+pub struct Aliases {
+    pub aliases: BTreeMap<String, Alias>,
+    // Only set when run inside a project directory
+    pub default_command: Option<Alias>,
+}
+
+impl CommandReport for Aliases {
+    fn print_human(&self) {
+        if self.aliases.is_empty() && self.default_command.is_none() {
+            println!("No aliases defined");
+            return;
+        }
+        for alias in self.aliases.values() {
+            println!(
+                "{:<16} {:<40} {}",
+                alias.name,
+                alias.args.join(" "),
+                alias.source.display()
+            );
+        }
+        if let Some(default) = &self.default_command {
+            println!(
+                "{:<16} {:<40} {}",
+                "(default)",
+                default.args.join(" "),
+                default.source.display()
+            );
+        }
+    }
+}
+
+// Split a command line the way a shell would for plain words: whitespace
+// separates arguments, single quotes keep everything, double quotes keep
+// everything but backslash escapes
+pub fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('"'), '\\') | (None, '\\') => match chars.next() {
+                Some(escaped) => current.push(escaped),
+                None => return Err(format!("Trailing backslash in `{}`", line)),
+            },
+            (Some(_), c) => current.push(c),
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                in_word = true;
+                continue;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    args.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+                continue;
+            }
+            (None, c) => current.push(c),
+        }
+        in_word = true;
+    }
+
+    if quote.is_some() {
+        return Err(format!("Unterminated quote in `{}`", line));
+    }
+    if in_word {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+fn read_tables(path: &Path) -> Result<AliasTables, Box<dyn Error>> {
+    toml::Value::Table(read_table(path)?)
+        .try_into::<AliasTables>()
+        .map_err(|err| format!("Invalid aliases in {}: {}", path.display(), err).into())
+}
+
+// Aliases from the user config, then the project .cat.toml above the
+// current directory, which wins when both define a name. Names of real
+// subcommands are rejected.
+pub fn load_aliases(no_user_config: bool, cli: &Command) -> Result<Aliases, Box<dyn Error>> {
+    // Paired with whether the file is a project rather than the user config
+    let mut sources = Vec::new();
+    if !no_user_config {
+        if let Some(path) = user_config_path() {
+            sources.push((path, false));
+        }
+    }
+    // Only a single project counts, as with deploy
+    if let Ok(mut projects) = discover_project_toml(&env::current_dir()?) {
+        if projects.len() == 1 {
+            sources.extend(projects.pop().map(|path| (path, true)));
+        }
+    }
+
+    let subcommands: HashSet<&str> = cli
+        .get_subcommands()
+        .flat_map(|command| std::iter::once(command.get_name()).chain(command.get_all_aliases()))
+        .collect();
+    let mut aliases = Aliases::default();
+
+    for (path, project) in &sources {
+        // A project toml that does not parse is left to the command loading
+        // it, which reports where it is wrong
+        let tables = match read_tables(path) {
+            Ok(tables) => tables,
+            Err(_) if *project => continue,
+            Err(err) => return Err(err),
+        };
+        for (name, value) in tables.aliases {
+            if subcommands.contains(name.as_str()) {
+                return Err(format!(
+                    "Alias {} in {} has the name of a command",
+                    name,
+                    path.display()
+                )
+                .into());
+            }
+            let args = value
+                .args()
+                .map_err(|err| format!("Alias {} in {}: {}", name, path.display(), err))?;
+            let alias = Alias {
+                name: name.clone(),
+                args,
+                source: path.clone(),
+            };
+            aliases.aliases.insert(name, alias);
+        }
+
+        if let Some(value) = tables.default_command {
+            let args = value
+                .args()
+                .map_err(|err| format!("default_command in {}: {}", path.display(), err))?;
+            aliases.default_command = Some(Alias {
+                name: "default_command".to_string(),
+                args,
+                source: path.clone(),
+            });
+        }
+    }
+
+    // Outside a project running the binary bare prints help as before
+    if !sources.iter().any(|(_, is_project)| *is_project) {
+        aliases.default_command = None;
+    }
+
+    Ok(aliases)
+}
+
+// Index of the first argument that is not a global option or its value
+pub fn command_position(args: &[String], cli: &Command) -> Option<usize> {
+    let takes_value = |arg: &str| {
+        cli.get_arguments().any(|option| {
+            option.get_action().takes_values()
+                && (option
+                    .get_long()
+                    .map(|long| format!("--{}", long))
+                    .as_deref()
+                    == Some(arg)
+                    || option
+                        .get_short()
+                        .map(|short| format!("-{}", short))
+                        .as_deref()
+                        == Some(arg))
+        })
+    };
+
+    let mut i = 1;
+    while i < args.len() {
+        let arg = &args[i];
+        if !arg.starts_with('-') {
+            return Some(i);
+        }
+        if arg == "--" {
+            return (i + 1 < args.len()).then_some(i + 1);
+        }
+        i += match !arg.contains('=') && takes_value(arg) {
+            true => 2,
+            false => 1,
+        };
+    }
+
+    None
+}
+
+// Replace an alias in the command position with its arguments, following
+// aliases of aliases, or add the default command when there is none
+pub fn expand_args(
+    mut args: Vec<String>,
+    aliases: &Aliases,
+    cli: &Command,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let position = match command_position(&args, cli) {
+        Some(position) => position,
+        None => {
+            let asks_for_help = args
+                .iter()
+                .any(|arg| matches!(arg.as_str(), "-h" | "--help" | "-V" | "--version"));
+            match (&aliases.default_command, asks_for_help) {
+                (Some(default), false) => {
+                    args.extend(default.args.iter().cloned());
+                    match command_position(&args, cli) {
+                        Some(position) => position,
+                        None => return Ok(args),
+                    }
+                }
+                _ => return Ok(args),
+            }
+        }
+    };
+
+    let mut seen = Vec::new();
+    while let Some(alias) = aliases.aliases.get(&args[position]) {
+        if seen.contains(&alias.name) || seen.len() >= MAX_ALIAS_DEPTH {
+            seen.push(alias.name.clone());
+            return Err(format!("Alias {} refers back to itself", seen.join(" -> ")).into());
+        }
+        seen.push(alias.name.clone());
+        if alias.args.is_empty() {
+            return Err(format!(
+                "Alias {} in {} is empty",
+                alias.name,
+                alias.source.display()
+            )
+            .into());
+        }
+        args.splice(position..=position, alias.args.iter().cloned());
+    }
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, ArgAction};
+
+    fn cli() -> Command {
+        Command::new("admin")
+            .arg(Arg::new("config").long("config"))
+            .arg(
+                Arg::new("verbose")
+                    .short('v')
+                    .long("verbose")
+                    .action(ArgAction::Count),
+            )
+            .subcommand(Command::new("deploy"))
+            .subcommand(Command::new("seed"))
+    }
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(str::to_string).collect()
+    }
+
+    fn aliases(defined: &[(&str, &str)], default_command: Option<&str>) -> Aliases {
+        let alias = |name: &str, line: &str| Alias {
+            name: name.to_string(),
+            args: split_args(line).unwrap(),
+            source: PathBuf::from(".cat.toml"),
+        };
+        Aliases {
+            aliases: defined
+                .iter()
+                .map(|(name, line)| (name.to_string(), alias(name, line)))
+                .collect(),
+            default_command: default_command.map(|line| alias("default_command", line)),
+        }
+    }
+
+    #[test]
+    fn split_args_on_whitespace() {
+        assert_eq!(
+            split_args("  deploy  web\t--dry-run "),
+            Ok(args("deploy web --dry-run"))
+        );
+        assert_eq!(split_args(""), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn split_args_keeps_quoted_values_together() {
+        assert_eq!(
+            split_args(r#"seed --index "my places" --query 'name:"Paris"'"#),
+            Ok(vec![
+                "seed".to_string(),
+                "--index".to_string(),
+                "my places".to_string(),
+                "--query".to_string(),
+                r#"name:"Paris""#.to_string(),
+            ])
+        );
+        // Quotes inside a word join it, empty quotes are an empty argument
+        assert_eq!(
+            split_args(r#"--env=pro"duction site" '' x"#),
+            Ok(vec![
+                "--env=production site".to_string(),
+                String::new(),
+                "x".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn split_args_escapes() {
+        assert_eq!(
+            split_args(r#"a\ b "say \"hi\"" 'no \escape'"#),
+            Ok(vec![
+                "a b".to_string(),
+                r#"say "hi""#.to_string(),
+                r"no \escape".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn split_args_refuses_unfinished_lines() {
+        assert!(split_args(r#"deploy "web"#).is_err());
+        assert!(split_args("deploy 'web").is_err());
+        assert!(split_args(r"deploy web\").is_err());
+    }
+
+    #[test]
+    fn alias_expands_in_the_command_position() {
+        let aliases = aliases(
+            &[("ship", r#"deploy --site "my blog" --env production"#)],
+            None,
+        );
+        let expanded = expand_args(
+            args("admin -v --config ci.toml ship --dry-run"),
+            &aliases,
+            &cli(),
+        );
+        assert_eq!(
+            expanded.unwrap(),
+            vec![
+                "admin",
+                "-v",
+                "--config",
+                "ci.toml",
+                "deploy",
+                "--site",
+                "my blog",
+                "--env",
+                "production",
+                "--dry-run",
+            ]
+        );
+    }
+
+    #[test]
+    fn alias_after_the_command_is_left_alone() {
+        let aliases = aliases(&[("ship", "deploy")], None);
+        let expanded = expand_args(args("admin seed ship"), &aliases, &cli()).unwrap();
+        assert_eq!(expanded, args("admin seed ship"));
+    }
+
+    #[test]
+    fn aliases_of_aliases_are_followed() {
+        let aliases = aliases(
+            &[
+                ("ship", "prod --dry-run"),
+                ("prod", "deploy --env production"),
+            ],
+            None,
+        );
+        let expanded = expand_args(args("admin ship"), &aliases, &cli()).unwrap();
+        assert_eq!(expanded, args("admin deploy --env production --dry-run"));
+    }
+
+    #[test]
+    fn recursive_aliases_are_refused() {
+        let aliases = aliases(&[("ship", "prod"), ("prod", "ship --dry-run")], None);
+        let err = expand_args(args("admin ship"), &aliases, &cli()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Alias ship -> prod -> ship refers back to itself"
+        );
+    }
+
+    #[test]
+    fn default_command_runs_without_a_subcommand() {
+        let aliases = aliases(&[("ship", "deploy --env production")], Some("ship"));
+        let expanded = expand_args(args("admin -v"), &aliases, &cli()).unwrap();
+        assert_eq!(expanded, args("admin -v deploy --env production"));
+
+        let help = expand_args(args("admin --help"), &aliases, &cli()).unwrap();
+        assert_eq!(help, args("admin --help"));
+    }
+}
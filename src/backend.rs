@@ -0,0 +1,745 @@
+use elasticsearch::{
+    cluster::ClusterHealthParts,
+    http::StatusCode,
+    indices::{
+        IndicesCreateParts, IndicesDeleteParts, IndicesExistsParts, IndicesPutMappingParts,
+        IndicesRefreshParts,
+    },
+    BulkOperation, BulkParts, CountParts, Elasticsearch, MgetParts,
+};
+use flate2::{write::GzEncoder, Compression};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// A document to index, keyed by its geonameid
+#[derive(Debug, Clone)]
+pub struct BulkDocument {
+    pub id: String,
+    pub body: Value,
+}
+
+// Outcome of a single operation inside a bulk request
+#[derive(Debug, Clone)]
+pub struct BulkItemResult {
+    pub id: String,
+    pub status: u16,
+    pub error: Option<Value>,
+}
+
+impl BulkItemResult {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    // Rejections from a full queue or an overloaded node are worth retrying,
+    // mapping and document errors are not
+    pub fn is_retryable(&self) -> bool {
+        self.status == 429 || self.status >= 500
+    }
+}
+
+// The operations the seed pipeline needs from a search engine
+#[allow(async_fn_in_trait)]
+pub trait SearchBackend {
+    // Create the index if missing, returning true when it was created
+    async fn ensure_index(&self, index: &str) -> Result<bool, Box<dyn Error>>;
+
+    async fn put_mapping(&self, index: &str, mapping: Value) -> Result<(), Box<dyn Error>>;
+
+    async fn delete_index(&self, index: &str) -> Result<(), Box<dyn Error>>;
+
+    // Index the documents, returning a result per document in request order
+    async fn bulk(
+        &self,
+        index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>>;
+
+    // Merge partial documents into existing ones, a result per document
+    async fn bulk_update(
+        &self,
+        index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>>;
+
+    // Current values of `fields` for whichever of `ids` exist
+    async fn get_fields(
+        &self,
+        index: &str,
+        ids: &[String],
+        fields: &[&str],
+    ) -> Result<HashMap<String, Value>, Box<dyn Error>>;
+
+    async fn count(&self, index: &str) -> Result<u64, Box<dyn Error>>;
+
+    async fn refresh(&self, index: &str) -> Result<(), Box<dyn Error>>;
+
+    // Nodes in the cluster that hold data
+    async fn data_nodes(&self) -> Result<u64, Box<dyn Error>>;
+}
+
+// Pair the items of a bulk response with the ids they were sent for
+async fn bulk_results(
+    ids: Vec<String>,
+    response: elasticsearch::http::response::Response,
+    operation: &str,
+) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+    let status = response.status_code();
+    let body = response.json::<Value>().await?;
+    tracing::debug!(
+        "Bulk {} of {} documents answered {}, errors {}",
+        operation,
+        ids.len(),
+        status,
+        body["errors"]
+    );
+
+    // A rejected request has no items, every document shares its status
+    if !status.is_success() {
+        return Ok(ids
+            .into_iter()
+            .map(|id| BulkItemResult {
+                id,
+                status: status.as_u16(),
+                error: Some(body["error"].clone()),
+            })
+            .collect());
+    }
+
+    let items = body["items"].as_array().cloned().unwrap_or_default();
+    Ok(ids
+        .into_iter()
+        .zip(items)
+        .map(|(id, item)| {
+            let result = &item[operation];
+            BulkItemResult {
+                id,
+                status: result["status"].as_u64().unwrap_or(500) as u16,
+                error: result.get("error").cloned(),
+            }
+        })
+        .collect())
+}
+
+pub struct ElasticsearchBackend {
+    client: Elasticsearch,
+}
+
+impl ElasticsearchBackend {
+    pub fn new(client: Elasticsearch) -> Self {
+        ElasticsearchBackend { client }
+    }
+
+    pub fn client(&self) -> &Elasticsearch {
+        &self.client
+    }
+
+    pub async fn index_exists(&self, index: &str) -> Result<bool, Box<dyn Error>> {
+        let exists_response = self
+            .client
+            .indices()
+            .exists(IndicesExistsParts::Index(&[index]))
+            .send()
+            .await?;
+
+        Ok(exists_response.status_code() != StatusCode::NOT_FOUND)
+    }
+}
+
+impl SearchBackend for ElasticsearchBackend {
+    async fn ensure_index(&self, index: &str) -> Result<bool, Box<dyn Error>> {
+        if self.index_exists(index).await? {
+            return Ok(false);
+        }
+
+        let create_index_response = self
+            .client
+            .indices()
+            .create(IndicesCreateParts::Index(index))
+            .send()
+            .await?;
+
+        tracing::debug!(
+            "Create index {} answered {}",
+            index,
+            create_index_response.status_code()
+        );
+        if !create_index_response.status_code().is_success() {
+            return Err(format!("Could not create index {}", index).into());
+        }
+
+        Ok(true)
+    }
+
+    async fn put_mapping(&self, index: &str, mapping: Value) -> Result<(), Box<dyn Error>> {
+        let apply_mapping_response = self
+            .client
+            .indices()
+            .put_mapping(IndicesPutMappingParts::Index(&[index]))
+            .body(mapping)
+            .send()
+            .await?;
+
+        tracing::debug!(
+            "Put mapping for {} answered {}",
+            index,
+            apply_mapping_response.status_code()
+        );
+        if apply_mapping_response.status_code() != StatusCode::OK {
+            return Err(format!("Could not update mapping for index {}", index).into());
+        }
+
+        Ok(())
+    }
+
+    async fn delete_index(&self, index: &str) -> Result<(), Box<dyn Error>> {
+        let response = self
+            .client
+            .indices()
+            .delete(IndicesDeleteParts::Index(&[index]))
+            .send()
+            .await?;
+        if !response.status_code().is_success() {
+            return Err(format!("Could not delete index {}", index).into());
+        }
+
+        Ok(())
+    }
+
+    async fn bulk(
+        &self,
+        index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        let ids: Vec<String> = documents.iter().map(|doc| doc.id.clone()).collect();
+        let operations: Vec<BulkOperation<Value>> = documents
+            .into_iter()
+            .map(|doc| BulkOperation::index(doc.body).id(doc.id).into())
+            .collect();
+
+        tracing::debug!("Sending {} bulk operations to {}", operations.len(), index);
+        let response = self
+            .client
+            .bulk(BulkParts::Index(index))
+            .body(operations)
+            .send()
+            .await?;
+
+        bulk_results(ids, response, "index").await
+    }
+
+    async fn bulk_update(
+        &self,
+        index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        let ids: Vec<String> = documents.iter().map(|doc| doc.id.clone()).collect();
+        let operations: Vec<BulkOperation<Value>> = documents
+            .into_iter()
+            .map(|doc| BulkOperation::update(doc.id, json!({ "doc": doc.body })).into())
+            .collect();
+
+        tracing::debug!("Sending {} bulk operations to {}", operations.len(), index);
+        let response = self
+            .client
+            .bulk(BulkParts::Index(index))
+            .body(operations)
+            .send()
+            .await?;
+
+        bulk_results(ids, response, "update").await
+    }
+
+    async fn get_fields(
+        &self,
+        index: &str,
+        ids: &[String],
+        fields: &[&str],
+    ) -> Result<HashMap<String, Value>, Box<dyn Error>> {
+        let response = self
+            .client
+            .mget(MgetParts::Index(index))
+            ._source_includes(fields)
+            .body(json!({ "ids": ids }))
+            .send()
+            .await?;
+
+        if !response.status_code().is_success() {
+            return Err(format!(
+                "Could not fetch documents from {}: {}",
+                index,
+                response.status_code()
+            )
+            .into());
+        }
+
+        let body = response.json::<Value>().await?;
+        Ok(body["docs"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|doc| doc["found"].as_bool().unwrap_or(false))
+            .filter_map(|doc| Some((doc["_id"].as_str()?.to_string(), doc["_source"].clone())))
+            .collect())
+    }
+
+    async fn count(&self, index: &str) -> Result<u64, Box<dyn Error>> {
+        let response = self
+            .client
+            .count(CountParts::Index(&[index]))
+            .send()
+            .await?;
+
+        let body = response.json::<Value>().await?;
+        body["count"]
+            .as_u64()
+            .ok_or_else(|| format!("Could not count documents in {}", index).into())
+    }
+
+    async fn refresh(&self, index: &str) -> Result<(), Box<dyn Error>> {
+        self.client
+            .indices()
+            .refresh(IndicesRefreshParts::Index(&[index]))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn data_nodes(&self) -> Result<u64, Box<dyn Error>> {
+        let response = self
+            .client
+            .cluster()
+            .health(ClusterHealthParts::None)
+            .send()
+            .await?;
+
+        let body = response.json::<Value>().await?;
+        body["number_of_data_nodes"]
+            .as_u64()
+            .ok_or_else(|| "Could not read cluster health".into())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryIndex {
+    pub mapping: Option<Value>,
+    pub documents: HashMap<String, Value>,
+}
+
+// In-memory stand-in for a cluster. Item statuses for upcoming bulk calls
+// can be queued up front to simulate partial failures and rejections.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    pub indices: Mutex<HashMap<String, MemoryIndex>>,
+    pub bulk_calls: Mutex<Vec<usize>>,
+    planned_statuses: Mutex<VecDeque<Vec<u16>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        MemoryBackend::default()
+    }
+
+    // Statuses for the documents of the next bulk call, in order. Documents
+    // past the end of the list succeed.
+    pub fn plan_bulk_statuses(&self, statuses: Vec<u16>) {
+        self.planned_statuses.lock().unwrap().push_back(statuses);
+    }
+
+    pub fn document(&self, index: &str, id: &str) -> Option<Value> {
+        self.indices
+            .lock()
+            .unwrap()
+            .get(index)
+            .and_then(|idx| idx.documents.get(id).cloned())
+    }
+}
+
+impl SearchBackend for MemoryBackend {
+    async fn ensure_index(&self, index: &str) -> Result<bool, Box<dyn Error>> {
+        let mut indices = self.indices.lock().unwrap();
+        if indices.contains_key(index) {
+            return Ok(false);
+        }
+        indices.insert(index.to_string(), MemoryIndex::default());
+
+        Ok(true)
+    }
+
+    async fn put_mapping(&self, index: &str, mapping: Value) -> Result<(), Box<dyn Error>> {
+        let mut indices = self.indices.lock().unwrap();
+        let idx = indices
+            .get_mut(index)
+            .ok_or_else(|| format!("Could not update mapping for index {}", index))?;
+        idx.mapping = Some(mapping);
+
+        Ok(())
+    }
+
+    async fn delete_index(&self, index: &str) -> Result<(), Box<dyn Error>> {
+        self.indices.lock().unwrap().remove(index);
+        Ok(())
+    }
+
+    async fn bulk(
+        &self,
+        index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        self.bulk_calls.lock().unwrap().push(documents.len());
+        let statuses = self
+            .planned_statuses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_default();
+
+        let mut indices = self.indices.lock().unwrap();
+        let idx = indices.entry(index.to_string()).or_default();
+
+        Ok(documents
+            .into_iter()
+            .enumerate()
+            .map(|(position, doc)| {
+                let status = statuses.get(position).copied().unwrap_or(201);
+                let result = BulkItemResult {
+                    id: doc.id.clone(),
+                    status,
+                    error: None,
+                };
+                if result.is_success() {
+                    idx.documents.insert(doc.id, doc.body);
+                    result
+                } else {
+                    BulkItemResult {
+                        error: Some(json!({"type": "simulated_failure", "status": status})),
+                        ..result
+                    }
+                }
+            })
+            .collect())
+    }
+
+    async fn bulk_update(
+        &self,
+        index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        self.bulk_calls.lock().unwrap().push(documents.len());
+        let mut indices = self.indices.lock().unwrap();
+        let idx = indices.entry(index.to_string()).or_default();
+
+        Ok(documents
+            .into_iter()
+            .map(|doc| match idx.documents.get_mut(&doc.id) {
+                Some(existing) => {
+                    if let (Some(existing), Some(fields)) =
+                        (existing.as_object_mut(), doc.body.as_object())
+                    {
+                        existing.extend(fields.clone());
+                    }
+                    BulkItemResult {
+                        id: doc.id,
+                        status: 200,
+                        error: None,
+                    }
+                }
+                None => BulkItemResult {
+                    id: doc.id,
+                    status: 404,
+                    error: Some(json!({"type": "document_missing_exception"})),
+                },
+            })
+            .collect())
+    }
+
+    async fn get_fields(
+        &self,
+        index: &str,
+        ids: &[String],
+        fields: &[&str],
+    ) -> Result<HashMap<String, Value>, Box<dyn Error>> {
+        let indices = self.indices.lock().unwrap();
+        let Some(idx) = indices.get(index) else {
+            return Ok(HashMap::new());
+        };
+
+        Ok(ids
+            .iter()
+            .filter_map(|id| {
+                let document = idx.documents.get(id)?;
+                let selected: serde_json::Map<String, Value> = fields
+                    .iter()
+                    .filter_map(|field| Some((field.to_string(), document.get(*field)?.clone())))
+                    .collect();
+                Some((id.clone(), Value::Object(selected)))
+            })
+            .collect())
+    }
+
+    async fn count(&self, index: &str) -> Result<u64, Box<dyn Error>> {
+        Ok(self
+            .indices
+            .lock()
+            .unwrap()
+            .get(index)
+            .map(|idx| idx.documents.len() as u64)
+            .unwrap_or_default())
+    }
+
+    async fn refresh(&self, _index: &str) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn data_nodes(&self) -> Result<u64, Box<dyn Error>> {
+        Ok(1)
+    }
+}
+
+// Accepts everything and keeps nothing but counts, for seed --dry-run.
+// Nothing is written. With a cluster to check against, whether an index
+// exists is asked for real, so the dry run knows which indices it would
+// create.
+#[derive(Default)]
+pub struct DryRunBackend {
+    cluster: Option<ElasticsearchBackend>,
+    documents: Mutex<HashMap<String, u64>>,
+}
+
+impl DryRunBackend {
+    pub fn new() -> Self {
+        DryRunBackend::default()
+    }
+
+    pub fn checking(cluster: ElasticsearchBackend) -> Self {
+        DryRunBackend {
+            cluster: Some(cluster),
+            ..DryRunBackend::default()
+        }
+    }
+}
+
+impl SearchBackend for DryRunBackend {
+    async fn ensure_index(&self, index: &str) -> Result<bool, Box<dyn Error>> {
+        let exists = match &self.cluster {
+            Some(cluster) => cluster.index_exists(index).await?,
+            None => false,
+        };
+        let mut documents = self.documents.lock().unwrap();
+        Ok(documents.insert(index.to_string(), 0).is_none() && !exists)
+    }
+
+    async fn put_mapping(&self, _index: &str, _mapping: Value) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn delete_index(&self, index: &str) -> Result<(), Box<dyn Error>> {
+        self.documents.lock().unwrap().remove(index);
+        Ok(())
+    }
+
+    async fn bulk(
+        &self,
+        index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        *self
+            .documents
+            .lock()
+            .unwrap()
+            .entry(index.to_string())
+            .or_default() += documents.len() as u64;
+
+        Ok(documents
+            .into_iter()
+            .map(|doc| BulkItemResult {
+                id: doc.id,
+                status: 201,
+                error: None,
+            })
+            .collect())
+    }
+
+    async fn bulk_update(
+        &self,
+        index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        self.bulk(index, documents).await
+    }
+
+    async fn get_fields(
+        &self,
+        _index: &str,
+        _ids: &[String],
+        _fields: &[&str],
+    ) -> Result<HashMap<String, Value>, Box<dyn Error>> {
+        Ok(HashMap::new())
+    }
+
+    async fn count(&self, index: &str) -> Result<u64, Box<dyn Error>> {
+        Ok(self
+            .documents
+            .lock()
+            .unwrap()
+            .get(index)
+            .copied()
+            .unwrap_or_default())
+    }
+
+    async fn refresh(&self, _index: &str) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    // There is no cluster to size the dump against
+    async fn data_nodes(&self) -> Result<u64, Box<dyn Error>> {
+        Err("no cluster in a dry run".into())
+    }
+}
+
+enum NdjsonWriter {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl NdjsonWriter {
+    fn write_line(&mut self, value: &Value) -> io::Result<()> {
+        let out: &mut dyn Write = match self {
+            NdjsonWriter::Plain(out) => out,
+            NdjsonWriter::Gzip(out) => out,
+        };
+        serde_json::to_writer(&mut *out, value)?;
+        out.write_all(b"\n")
+    }
+}
+
+// Writes the bulk request bodies to a file instead of a cluster, to be
+// loaded later with `curl --data-binary @file .../_bulk`. Gzip compressed
+// when the path ends in .gz.
+pub struct NdjsonBackend {
+    path: PathBuf,
+    writer: Mutex<NdjsonWriter>,
+    documents: Mutex<HashMap<String, u64>>,
+}
+
+impl NdjsonBackend {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        let writer = match path.extension().is_some_and(|ext| ext == "gz") {
+            true => NdjsonWriter::Gzip(GzEncoder::new(file, Compression::default())),
+            false => NdjsonWriter::Plain(file),
+        };
+        Ok(NdjsonBackend {
+            path: path.to_path_buf(),
+            writer: Mutex::new(writer),
+            documents: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Flush everything written and return the size of the file
+    pub fn finish(self) -> io::Result<u64> {
+        match self.writer.into_inner().unwrap() {
+            NdjsonWriter::Plain(mut out) => out.flush()?,
+            NdjsonWriter::Gzip(out) => out.finish()?.flush()?,
+        }
+        Ok(fs::metadata(&self.path)?.len())
+    }
+
+    fn write(
+        &self,
+        index: &str,
+        operation: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        let mut writer = self.writer.lock().unwrap();
+        let mut results = Vec::with_capacity(documents.len());
+        for doc in documents {
+            writer.write_line(&json!({ operation: { "_index": index, "_id": doc.id } }))?;
+            match operation {
+                "update" => writer.write_line(&json!({ "doc": doc.body }))?,
+                _ => writer.write_line(&doc.body)?,
+            }
+            results.push(BulkItemResult {
+                id: doc.id,
+                status: 201,
+                error: None,
+            });
+        }
+        *self
+            .documents
+            .lock()
+            .unwrap()
+            .entry(index.to_string())
+            .or_default() += results.len() as u64;
+
+        Ok(results)
+    }
+}
+
+impl SearchBackend for NdjsonBackend {
+    // Every index is new to the file
+    async fn ensure_index(&self, index: &str) -> Result<bool, Box<dyn Error>> {
+        let mut documents = self.documents.lock().unwrap();
+        Ok(documents.insert(index.to_string(), 0).is_none())
+    }
+
+    // Bulk requests cannot carry a mapping
+    async fn put_mapping(&self, _index: &str, _mapping: Value) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn delete_index(&self, _index: &str) -> Result<(), Box<dyn Error>> {
+        Err("cannot delete an index from an output file".into())
+    }
+
+    async fn bulk(
+        &self,
+        index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        self.write(index, "index", documents)
+    }
+
+    async fn bulk_update(
+        &self,
+        index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        self.write(index, "update", documents)
+    }
+
+    async fn get_fields(
+        &self,
+        _index: &str,
+        _ids: &[String],
+        _fields: &[&str],
+    ) -> Result<HashMap<String, Value>, Box<dyn Error>> {
+        Ok(HashMap::new())
+    }
+
+    async fn count(&self, index: &str) -> Result<u64, Box<dyn Error>> {
+        Ok(self
+            .documents
+            .lock()
+            .unwrap()
+            .get(index)
+            .copied()
+            .unwrap_or_default())
+    }
+
+    async fn refresh(&self, _index: &str) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    // There is no cluster to size the dump against
+    async fn data_nodes(&self) -> Result<u64, Box<dyn Error>> {
+        Err("no cluster when writing to a file".into())
+    }
+}
@@ -0,0 +1,333 @@
+use chrono::{NaiveDate, Utc};
+use futures_util::future::join_all;
+use serde::Serialize;
+use std::error::Error;
+use std::time::Instant;
+
+use crate::backend::{BulkDocument, SearchBackend};
+use crate::cancel::CancelToken;
+use crate::geonames::{AdminLookup, CountryCodes, DocumentOptions, Location};
+use crate::iso3166::ALPHA2_TO_ALPHA3;
+use crate::output::CommandReport;
+use crate::progress::Progress;
+use crate::status;
+
+// Runs within this fraction of the best throughput count as just as good,
+// the smaller settings among them are recommended
+const RECOMMENDATION_TOLERANCE: f64 = 0.05;
+
+const FEATURE_CLASSES: [char; 9] = ['A', 'H', 'L', 'P', 'R', 'S', 'T', 'U', 'V'];
+
+// Small xorshift generator, so the same --seed always gives the same
+// documents without pulling in a random number crate
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Zero would stay zero forever
+        Rng(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn word(&mut self, min: u64, max: u64) -> String {
+        let len = min + self.below(max - min + 1);
+        let mut word: String = (0..len)
+            .map(|_| (b'a' + self.below(26) as u8) as char)
+            .collect();
+        word[..1].make_ascii_uppercase();
+        word
+    }
+}
+
+// A record shaped like a geonames row, with made up names and places
+fn synthetic_location(rng: &mut Rng, id: i64) -> Location {
+    let name = rng.word(4, 12);
+    let alternate_names = (0..rng.below(6))
+        .map(|_| rng.word(4, 12))
+        .collect::<Vec<_>>()
+        .join(",");
+    let (country_code, _) = ALPHA2_TO_ALPHA3[rng.below(ALPHA2_TO_ALPHA3.len() as u64) as usize];
+    // Populations spread over orders of magnitude like real places
+    let population = 10f64.powf(rng.unit() * 7.0) as i64;
+
+    Location {
+        id,
+        ascii_name: name.clone(),
+        name,
+        alternate_names,
+        latitude: rng.unit() * 180.0 - 90.0,
+        longitude: rng.unit() * 360.0 - 180.0,
+        feature_class: Some(FEATURE_CLASSES[rng.below(FEATURE_CLASSES.len() as u64) as usize]),
+        feature_code: "PPL".to_string(),
+        country_code: country_code.to_string(),
+        cc2: String::new(),
+        admin1_code: format!("{:02}", rng.below(20)),
+        admin2_code: String::new(),
+        admin3_code: String::new(),
+        admin4_code: None,
+        population: Some(population),
+        elevation: Some(rng.below(3000) as i64),
+        dem: None,
+        timezone: "Etc/UTC".to_string(),
+        modification_date: NaiveDate::from_ymd_opt(2020, 1, 1).expect("valid date"),
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BenchResult {
+    pub batch_size: usize,
+    pub concurrency: usize,
+    pub documents: u64,
+    pub duration_ms: u64,
+    pub docs_per_sec: f64,
+    pub p95_latency_ms: u64,
+    // Documents the cluster rejected
+    pub errors: u64,
+}
+
+#[derive(Serialize)]
+pub struct BenchReport {
+    pub index: String,
+    pub seed: u64,
+    pub max_docs: u64,
+    // Fastest first
+    pub results: Vec<BenchResult>,
+    // Batch size and concurrency to seed with
+    pub recommended: Option<(usize, usize)>,
+    pub stopped: bool,
+}
+
+impl CommandReport for BenchReport {
+    fn print_human(&self) {
+        println!(
+            "{:>10} {:>11} {:>10} {:>12} {:>10} {:>8}",
+            "batch", "concurrency", "documents", "docs/sec", "p95 ms", "errors"
+        );
+        for result in &self.results {
+            println!(
+                "{:>10} {:>11} {:>10} {:>12.0} {:>10} {:>8}",
+                result.batch_size,
+                result.concurrency,
+                result.documents,
+                result.docs_per_sec,
+                result.p95_latency_ms,
+                result.errors
+            );
+        }
+        match self.recommended {
+            Some((batch_size, concurrency)) => println!(
+                "Recommended: --buffer {} --concurrency {}",
+                batch_size, concurrency
+            ),
+            None => println!("No run finished without errors, nothing to recommend"),
+        }
+        if self.stopped {
+            println!("Interrupted before every combination was measured");
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self.stopped || self.recommended.is_none() {
+            true => 1,
+            false => 0,
+        }
+    }
+}
+
+fn percentile(latencies: &mut [u64], p: f64) -> u64 {
+    if latencies.is_empty() {
+        return 0;
+    }
+    latencies.sort_unstable();
+    latencies[(p / 100.0 * (latencies.len() - 1) as f64).round() as usize]
+}
+
+// The fastest run without errors, preferring less load on the cluster when
+// runs are about as fast
+pub fn recommend(results: &[BenchResult]) -> Option<(usize, usize)> {
+    let clean = || results.iter().filter(|result| result.errors == 0);
+    let best = clean()
+        .map(|result| result.docs_per_sec)
+        .fold(0.0, f64::max);
+    clean()
+        .filter(|result| result.docs_per_sec >= best * (1.0 - RECOMMENDATION_TOLERANCE))
+        .min_by_key(|result| (result.batch_size * result.concurrency, result.concurrency))
+        .map(|result| (result.batch_size, result.concurrency))
+}
+
+async fn measure<B: SearchBackend>(
+    backend: &B,
+    index: &str,
+    documents: Vec<BulkDocument>,
+    batch_size: usize,
+    concurrency: usize,
+) -> Result<BenchResult, Box<dyn Error>> {
+    let total = documents.len() as u64;
+    let mut batches = Vec::new();
+    let mut documents = documents.into_iter().peekable();
+    while documents.peek().is_some() {
+        batches.push(documents.by_ref().take(batch_size).collect::<Vec<_>>());
+    }
+
+    let started = Instant::now();
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    let mut batches = batches.into_iter().peekable();
+    while batches.peek().is_some() {
+        // Up to `concurrency` bulk requests in flight at once
+        let round = batches.by_ref().take(concurrency).map(|batch| async move {
+            let sent = Instant::now();
+            let items = backend.bulk(index, batch).await?;
+            Ok::<_, Box<dyn Error>>((sent.elapsed().as_millis() as u64, items))
+        });
+        for result in join_all(round).await {
+            let (latency, items) = result?;
+            latencies.push(latency);
+            errors += items.iter().filter(|item| !item.is_success()).count() as u64;
+        }
+    }
+    let elapsed = started.elapsed();
+
+    Ok(BenchResult {
+        batch_size,
+        concurrency,
+        documents: total,
+        duration_ms: elapsed.as_millis() as u64,
+        docs_per_sec: total as f64 / elapsed.as_secs_f64().max(0.001),
+        p95_latency_ms: percentile(&mut latencies, 95.0),
+        errors,
+    })
+}
+
+async fn run_grid<B: SearchBackend>(
+    backend: &B,
+    report: &mut BenchReport,
+    batch_sizes: &[usize],
+    concurrency: &[usize],
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> Result<(), Box<dyn Error>> {
+    let options = DocumentOptions::default();
+    backend.ensure_index(&report.index).await?;
+    backend
+        .put_mapping(&report.index, Location::generate_mapping(&options))
+        .await?;
+
+    let combinations: Vec<(usize, usize)> = batch_sizes
+        .iter()
+        .flat_map(|&batch_size| concurrency.iter().map(move |&level| (batch_size, level)))
+        .collect();
+    // --max-docs is shared by every combination
+    let per_run = (report.max_docs / combinations.len() as u64).max(1);
+    let (admin1, admin2, countries) = (
+        AdminLookup::new(),
+        AdminLookup::new(),
+        CountryCodes::default(),
+    );
+    let mut rng = Rng::new(report.seed);
+    let mut next_id = 1;
+
+    let task = progress.start_task("Benchmarking", Some(combinations.len() as u64));
+    for (batch_size, level) in combinations {
+        if cancel.is_cancelled() {
+            task.log("Interrupted, skipping the remaining combinations");
+            report.stopped = true;
+            break;
+        }
+
+        // Generated before the clock starts
+        let documents: Vec<BulkDocument> = (0..per_run)
+            .map(|_| {
+                let location = synthetic_location(&mut rng, next_id);
+                next_id += 1;
+                BulkDocument {
+                    id: location.id.to_string(),
+                    body: location
+                        .generate_elasticsearch_document(&admin1, &admin2, &countries, &options),
+                }
+            })
+            .collect();
+
+        let result = measure(backend, &report.index, documents, batch_size, level).await?;
+        task.log(&format!(
+            "batch {} concurrency {}: {:.0} docs/sec",
+            batch_size, level, result.docs_per_sec
+        ));
+        report.results.push(result);
+        task.update(1);
+    }
+    task.finish("done");
+
+    Ok(())
+}
+
+// Ingest synthetic documents into a throwaway index for every combination
+// of batch size and concurrency. The index is deleted afterwards, also when
+// a run fails.
+pub async fn bench<B: SearchBackend>(
+    backend: &B,
+    batch_sizes: &[usize],
+    concurrency: &[usize],
+    max_docs: u64,
+    seed: u64,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> Result<BenchReport, Box<dyn Error>> {
+    if batch_sizes.contains(&0) || concurrency.contains(&0) {
+        return Err("--batch-sizes and --concurrency must be positive".into());
+    }
+    if batch_sizes.is_empty() || concurrency.is_empty() {
+        return Err("--batch-sizes and --concurrency need at least one value".into());
+    }
+
+    let mut report = BenchReport {
+        index: format!("admin-bench-{}", Utc::now().format("%Y%m%d%H%M%S")),
+        seed,
+        max_docs,
+        results: Vec::new(),
+        recommended: None,
+        stopped: false,
+    };
+    status!("Benchmarking into temporary index {}", report.index);
+
+    let outcome = run_grid(
+        backend,
+        &mut report,
+        batch_sizes,
+        concurrency,
+        progress,
+        cancel,
+    )
+    .await;
+    let deleted = backend.delete_index(&report.index).await;
+    match &deleted {
+        Ok(()) => status!("Deleted temporary index {}", report.index),
+        Err(err) => status!(
+            "Warning: could not delete temporary index {}: {}",
+            report.index,
+            err
+        ),
+    }
+    outcome?;
+    deleted?;
+
+    report
+        .results
+        .sort_by(|a, b| b.docs_per_sec.total_cmp(&a.docs_per_sec));
+    report.recommended = recommend(&report.results);
+    Ok(report)
+}
@@ -0,0 +1,120 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+use crate::status;
+
+// Exit code for a run cut short by Ctrl-C, as shells report for SIGINT
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+// Set once the user asks to stop. Commands check it at points where stopping
+// leaves things consistent rather than being killed mid-operation.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Resolves once `cancel` has been called
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+// Run `stop` once the token is cancelled, until the handle is aborted
+pub fn on_cancel(
+    token: &CancelToken,
+    stop: impl FnOnce() + Send + 'static,
+) -> tokio::task::JoinHandle<()> {
+    let token = token.clone();
+    tokio::spawn(async move {
+        token.cancelled().await;
+        stop();
+    })
+}
+
+// The first Ctrl-C cancels the token, a second one exits straight away
+pub fn install_ctrl_c_handler(token: &CancelToken) {
+    let token = token.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        status!("Interrupted, finishing up. Press Ctrl-C again to exit immediately");
+        token.cancel();
+
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("Interrupted again, exiting");
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn clones_share_the_cancellation() {
+        let token = CancelToken::default();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+
+        token.cancel();
+        assert!(clone.is_cancelled());
+        // Resolves straight away once cancelled
+        tokio::time::timeout(Duration::from_secs(1), clone.cancelled())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_wakes_a_waiting_task() {
+        let token = CancelToken::default();
+        let waiting = tokio::spawn({
+            let token = token.clone();
+            async move { token.cancelled().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        token.cancel();
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn on_cancel_runs_once_cancelled() {
+        let token = CancelToken::default();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let watcher = on_cancel(&token, {
+            let stopped = stopped.clone();
+            move || stopped.store(true, Ordering::SeqCst)
+        });
+        tokio::task::yield_now().await;
+        assert!(!stopped.load(Ordering::SeqCst));
+
+        token.cancel();
+        watcher.await.unwrap();
+        assert!(stopped.load(Ordering::SeqCst));
+    }
+}
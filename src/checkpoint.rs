@@ -0,0 +1,57 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// How far a seed run got, written after every batch and when the run ends
+// so the next one knows where to pick up
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Checkpoint {
+    pub index: String,
+    // The dump being seeded
+    pub source: String,
+    // Records accepted by the backend, in file order
+    pub records: u64,
+    // Rows of the dump read up to the last batch the backend accepted, what
+    // --resume-from last skips
+    #[serde(default)]
+    pub rows: u64,
+    pub saved_at: String,
+    // The run got through the whole source
+    #[serde(default)]
+    pub complete: bool,
+    // Latest modification date seen, for --modified-since-checkpoint
+    #[serde(default)]
+    pub latest_modified: Option<NaiveDate>,
+}
+
+impl Checkpoint {
+    pub fn new(index: &str, source: &str, records: u64) -> Self {
+        Checkpoint {
+            index: index.to_string(),
+            source: source.to_string(),
+            records,
+            rows: 0,
+            saved_at: chrono::Utc::now().to_rfc3339(),
+            complete: false,
+            latest_modified: None,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("Could not read checkpoint {}: {}", path.display(), err))?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+}
+
+// Checkpoint file used for an index when none is given
+pub fn default_path(index: &str) -> PathBuf {
+    PathBuf::from(format!("{}.checkpoint.json", index))
+}
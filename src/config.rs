@@ -0,0 +1,278 @@
+use clap::{Arg, Command};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+use crate::alias::command_position;
+
+pub const DEFAULT_ELASTICSEARCH: &str = "http://localhost:9200";
+
+// Keys understood by this version of the tool. Anything else in the file is
+// kept as-is but warned about so older binaries tolerate newer config files.
+pub const KNOWN_KEYS: [&str; 7] = [
+    "elasticsearch",
+    "ssh_identity",
+    "image_quality",
+    "progress",
+    "log_dir",
+    // Read by the alias module before the command line is parsed
+    "aliases",
+    "default_command",
+];
+
+// Personal defaults loaded from ~/.config/admin-cli/config.toml
+//
+// Precedence, highest first: CLI flags, project .cat.toml, this file,
+// built in defaults.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct UserConfig {
+    pub elasticsearch: Option<String>,
+    pub ssh_identity: Option<PathBuf>,
+    pub image_quality: Option<u8>,
+    pub progress: Option<bool>,
+    // Directory for admin.log when --log-file is not given
+    pub log_dir: Option<PathBuf>,
+}
+
+impl UserConfig {
+    // Resolve the Elasticsearch URL, preferring an explicit flag
+    pub fn elasticsearch_url(&self, flag: &Option<String>) -> String {
+        flag.clone()
+            .or_else(|| self.elasticsearch.clone())
+            .unwrap_or_else(|| DEFAULT_ELASTICSEARCH.to_string())
+    }
+}
+
+// Location of the user config, honouring XDG_CONFIG_HOME when it is set
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+
+    Some(config_dir.join("admin-cli").join("config.toml"))
+}
+
+// Read the raw table from disk, an absent file is an empty table
+pub fn read_table(path: &Path) -> Result<Table, Box<dyn Error>> {
+    if !path.exists() {
+        return Ok(Table::new());
+    }
+
+    let file = fs::read_to_string(path)?;
+    let table = file
+        .parse::<Table>()
+        .map_err(|err| format!("Could not parse {}: {}", path.display(), err))?;
+
+    Ok(table)
+}
+
+pub fn write_table(path: &Path, table: &Table) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, toml::to_string_pretty(table)?)?;
+
+    Ok(())
+}
+
+fn from_table(table: Table, path: &Path) -> Result<UserConfig, Box<dyn Error>> {
+    for key in table.keys() {
+        if !KNOWN_KEYS.contains(&key.as_str()) {
+            eprintln!(
+                "Warning: ignoring unknown key `{}` in {}",
+                key,
+                path.display()
+            );
+        }
+    }
+
+    let config = Value::Table(table)
+        .try_into::<UserConfig>()
+        .map_err(|err| format!("Invalid value in {}: {}", path.display(), err))?;
+
+    Ok(config)
+}
+
+// Load the user config, falling back to defaults when there is no file
+pub fn load_user_config() -> Result<UserConfig, Box<dyn Error>> {
+    match user_config_path() {
+        Some(path) => from_table(read_table(&path)?, &path),
+        None => Ok(UserConfig::default()),
+    }
+}
+
+// Interpret a value given on the command line as a TOML value, falling back
+// to a plain string so `config set elasticsearch http://...` needs no quotes
+fn parse_value(raw: &str) -> Value {
+    match format!("value = {}", raw).parse::<Table>() {
+        Ok(mut table) => table.remove("value").unwrap_or(Value::String(raw.into())),
+        Err(_) => Value::String(raw.into()),
+    }
+}
+
+pub fn get(key: &str) -> Result<(), Box<dyn Error>> {
+    let path = user_config_path().ok_or("Could not determine the user config directory")?;
+    match read_table(&path)?.get(key) {
+        Some(value) => println!("{}", display_value(value)),
+        None => return Err(format!("{} is not set in {}", key, path.display()).into()),
+    }
+
+    Ok(())
+}
+
+pub fn set(key: &str, raw: &str) -> Result<(), Box<dyn Error>> {
+    if !KNOWN_KEYS.contains(&key) {
+        return Err(format!(
+            "Unknown config key {}, expected one of: {}",
+            key,
+            KNOWN_KEYS.join(", ")
+        )
+        .into());
+    }
+
+    let path = user_config_path().ok_or("Could not determine the user config directory")?;
+    let mut table = read_table(&path)?;
+    table.insert(key.to_string(), parse_value(raw));
+
+    // Make sure the file still loads before writing it out
+    from_table(table.clone(), &path)?;
+    write_table(&path, &table)?;
+    println!("Set {} in {}", key, path.display());
+
+    Ok(())
+}
+
+pub fn list() -> Result<(), Box<dyn Error>> {
+    let path = user_config_path().ok_or("Could not determine the user config directory")?;
+    let table = read_table(&path)?;
+
+    println!("# {}", path.display());
+    for (key, value) in table.iter() {
+        println!("{} = {}", key, display_value(value));
+    }
+
+    Ok(())
+}
+
+fn display_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+// Flag defaults from the file given to --config, so long invocations can be
+// kept in a file. Keys are long flag names, with `-` or `_`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub seed: Table,
+    #[serde(default)]
+    pub images: Table,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = fs::read_to_string(path)
+            .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+        toml::from_str(&file).map_err(|err| format!("Invalid {}: {}", path.display(), err).into())
+    }
+
+    // Add the flags of the command being run that the command line does not
+    // give, right after the command name, so they parse and are checked as
+    // if typed. `false` leaves a switch off.
+    pub fn apply(
+        &self,
+        mut args: Vec<String>,
+        cli: &Command,
+        path: &Path,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let position = match command_position(&args, cli) {
+            Some(position) => position,
+            None => return Ok(args),
+        };
+        let (name, table) = match args[position].as_str() {
+            "seed" => ("seed", &self.seed),
+            "images" => ("images", &self.images),
+            _ => return Ok(args),
+        };
+        let subcommand = cli
+            .find_subcommand(name)
+            .ok_or_else(|| format!("No {} command to configure", name))?;
+
+        let mut added = Vec::new();
+        for (key, value) in table {
+            let id = key.replace('-', "_");
+            let arg = subcommand
+                .get_arguments()
+                .find(|arg| arg.get_id() == id.as_str() && arg.get_long().is_some())
+                .ok_or_else(|| {
+                    format!("Unknown flag `{}` in [{}] of {}", key, name, path.display())
+                })?;
+            if is_given(arg, &args[position + 1..]) {
+                continue;
+            }
+
+            let flag = format!("--{}", arg.get_long().expect("checked above"));
+            match value {
+                Value::Boolean(true) => added.push(flag),
+                Value::Boolean(false) => {}
+                Value::Array(items) => {
+                    for item in items {
+                        added.extend([flag.clone(), display_value(item)]);
+                    }
+                }
+                other => added.extend([flag, display_value(other)]),
+            }
+        }
+        args.splice(position + 1..position + 1, added);
+
+        Ok(args)
+    }
+}
+
+// Whether the flag is among the arguments, by any of its names
+fn is_given(arg: &Arg, args: &[String]) -> bool {
+    let longs: Vec<String> = arg
+        .get_long()
+        .into_iter()
+        .chain(arg.get_all_aliases().unwrap_or_default())
+        .map(|long| format!("--{}", long))
+        .collect();
+    let shorts: Vec<String> = arg
+        .get_short()
+        .into_iter()
+        .chain(arg.get_all_short_aliases().unwrap_or_default())
+        .map(|short| format!("-{}", short))
+        .collect();
+
+    args.iter()
+        .take_while(|given| given.as_str() != "--")
+        .any(|given| {
+            longs
+                .iter()
+                .any(|long| given == long || given.starts_with(&format!("{}=", long)))
+                || shorts.iter().any(|short| given.starts_with(short.as_str()))
+                    && !given.starts_with("--")
+        })
+}
+
+// The --config path, found before the command line is parsed since it
+// changes the arguments
+pub fn config_flag(args: &[String]) -> Option<PathBuf> {
+    let mut args = args.iter().take_while(|arg| arg.as_str() != "--");
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
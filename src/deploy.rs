@@ -1,13 +1,307 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
-use std::process::Command;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::time::Instant;
 use toml;
 
+use crate::metadata::BuildConfig;
+use crate::output::CommandReport;
+use crate::status;
+
+// ssh host static sites are deployed to
+pub const DEFAULT_SERVER: &str = "static";
+
+// ssh host API sites are deployed to when their `deploy.server` is not set
+pub const DEFAULT_API_SERVER: &str = "api";
+
+const MB: u64 = 1024 * 1024;
+
+// Room left over after a deploy, so the server is never filled to the brim
+const SPACE_MARGIN_BYTES: u64 = 100 * MB;
+
+#[derive(Debug)]
+pub enum DeployError {
+    Io(io::Error),
+    CommandFailed {
+        command: String,
+        status: ExitStatus,
+    },
+    // A file in an extra_static directory with the same path as trunk output
+    StaticConflict {
+        file: String,
+        source: PathBuf,
+    },
+    // The server does not have room for the deploy
+    InsufficientSpace {
+        server: String,
+        needed: u64,
+        available: u64,
+        releases: Option<u64>,
+    },
+    // df printed something that could not be read
+    UnreadableDf(String),
+    // index.html links to a file the ignore patterns leave out
+    IgnoredReference {
+        file: String,
+        pattern: String,
+    },
+}
+
+impl fmt::Display for DeployError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeployError::Io(err) => write!(f, "{}", err),
+            DeployError::CommandFailed { command, status } => {
+                write!(f, "`{}` failed with {}", command, status)
+            }
+            DeployError::StaticConflict { file, source } => write!(
+                f,
+                "{} from {} would overwrite a file trunk built",
+                file,
+                source.display()
+            ),
+            DeployError::InsufficientSpace {
+                server,
+                needed,
+                available,
+                releases,
+            } => {
+                write!(
+                    f,
+                    "{} has {} MB free but the deploy needs {} MB",
+                    server,
+                    available / MB,
+                    needed / MB
+                )?;
+                match releases {
+                    Some(releases) if releases + available >= *needed => write!(
+                        f,
+                        ", existing deployments use {} MB, try admin clean-old-deployments",
+                        releases / MB
+                    ),
+                    _ => Ok(()),
+                }
+            }
+            DeployError::UnreadableDf(output) => {
+                write!(f, "could not read free space from df: {}", output.trim())
+            }
+            DeployError::IgnoredReference { file, pattern } => write!(
+                f,
+                "index.html references {}, which is ignored by {}",
+                file, pattern
+            ),
+        }
+    }
+}
+
+impl Error for DeployError {}
+
+impl From<io::Error> for DeployError {
+    fn from(err: io::Error) -> Self {
+        DeployError::Io(err)
+    }
+}
+
+#[derive(Serialize)]
+pub struct DeployStage {
+    pub name: String,
+    pub duration_ms: u64,
+}
+
+#[derive(Serialize)]
+pub struct ServerResult {
+    pub server: String,
+    pub remote_path: String,
+    pub permissions_fixed: bool,
+}
+
+// How long each phase of a deploy took, written to --metrics-file
+#[derive(Serialize, Debug, Default)]
+pub struct DeployMetrics {
+    pub build_duration_ms: u64,
+    pub move_duration_ms: u64,
+    pub transfer_duration_ms: u64,
+    pub total_bytes_transferred: u64,
+}
+
+// What `scp_files` sent
+pub struct Transfer {
+    pub duration_ms: u64,
+    pub bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct DeployReport {
+    pub app: String,
+    pub stages: Vec<DeployStage>,
+    pub metrics: DeployMetrics,
+    pub servers: Vec<ServerResult>,
+}
+
+impl CommandReport for DeployReport {
+    fn print_human(&self) {
+        println!("{:<12} {:>10}", "phase", "duration");
+        for stage in &self.stages {
+            println!("{:<12} {:>8}ms", stage.name, stage.duration_ms);
+        }
+        println!("Transferred {} bytes", self.metrics.total_bytes_transferred);
+        for server in &self.servers {
+            println!(
+                "Deployed {} to {}:{}",
+                self.app, server.server, server.remote_path
+            );
+        }
+    }
+}
+
+// Where a static site lives on the server
+pub fn remote_site_dir(static_site_name: &str) -> String {
+    format!("/var/www/{}", static_site_name)
+}
+
+// Versioned deployments are directories under the site directory named by
+// when they were made, with a `current` symlink to the live one
+const RELEASE_TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S";
+
+#[derive(Serialize)]
+pub struct CleanReport {
+    pub server: String,
+    pub site_dir: String,
+    pub current: Option<String>,
+    pub kept: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl CommandReport for CleanReport {
+    fn print_human(&self) {
+        if self.removed.is_empty() {
+            println!(
+                "Nothing to remove in {}:{}, {} deployments kept",
+                self.server,
+                self.site_dir,
+                self.kept.len()
+            );
+            return;
+        }
+        for release in &self.removed {
+            println!("Removed {}:{}/{}", self.server, self.site_dir, release);
+        }
+        println!("Kept {} deployments", self.kept.len());
+    }
+}
+
+// `value` single quoted for the remote shell, so spaces, `;` and `$` in a
+// path stay part of it
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+// Run a command on the server and return what it printed
+fn ssh_output(
+    server: &str,
+    ssh_args: &[&str],
+    remote_command: &str,
+) -> Result<String, DeployError> {
+    let output = Command::new("ssh")
+        .args(ssh_args)
+        .arg(server)
+        .arg(remote_command)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(DeployError::CommandFailed {
+            command: format!("ssh {} \"{}\"", server, remote_command),
+            status: output.status,
+        });
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Releases to delete, oldest first: everything but the newest `keep`, and
+// never the one `current` points to
+pub fn old_releases(entries: &[String], current: Option<&str>, keep: usize) -> Vec<String> {
+    let mut releases: Vec<&String> = entries
+        .iter()
+        .filter(|name| {
+            chrono::NaiveDateTime::parse_from_str(name, RELEASE_TIMESTAMP_FORMAT).is_ok()
+        })
+        .collect();
+    releases.sort();
+
+    let old = releases.len().saturating_sub(keep);
+    releases[..old]
+        .iter()
+        .filter(|name| Some(name.as_str()) != current)
+        .map(|name| name.to_string())
+        .collect()
+}
+
+// Delete all but the newest `keep` versioned deployments of a site
+pub fn clean_old_deployments(
+    server: &str,
+    static_site_name: &str,
+    keep: usize,
+    ssh_args: &[&str],
+) -> Result<CleanReport, DeployError> {
+    let site_dir = remote_site_dir(static_site_name);
+    let entries: Vec<String> = ssh_output(
+        server,
+        ssh_args,
+        &format!("ls -1 {}", shell_quote(&site_dir)),
+    )?
+    .lines()
+    .map(str::to_string)
+    .collect();
+    // A site without a `current` link yet has nothing to protect
+    let current = ssh_output(
+        server,
+        ssh_args,
+        &format!(
+            "readlink {} || true",
+            shell_quote(&format!("{}/current", site_dir))
+        ),
+    )?;
+    let current = Path::new(current.trim())
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string());
+
+    let removed = old_releases(&entries, current.as_deref(), keep);
+    if !removed.is_empty() {
+        let paths: Vec<String> = removed
+            .iter()
+            .map(|release| shell_quote(&format!("{}/{}", site_dir, release)))
+            .collect();
+        status!("Removing {} old deployments", removed.len());
+        ssh_output(server, ssh_args, &format!("rm -rf {}", paths.join(" ")))?;
+    }
+
+    let kept = entries
+        .iter()
+        .filter(|name| {
+            chrono::NaiveDateTime::parse_from_str(name, RELEASE_TIMESTAMP_FORMAT).is_ok()
+                && !removed.contains(name)
+        })
+        .cloned()
+        .collect();
+
+    Ok(CleanReport {
+        server: server.to_string(),
+        site_dir,
+        current,
+        kept,
+        removed,
+    })
+}
+
 #[derive(Deserialize)]
-struct TrunkToml {
+pub(crate) struct TrunkToml {
     build: BuildToml,
 }
 
@@ -18,32 +312,157 @@ struct BuildToml {
     dist: Option<String>,
 }
 
-// Build the trunk app
-pub fn run_trunk(app_dir: &PathBuf) -> Result<(), Box<dyn Error>> {
-    println!("Building trunk app: {}", app_dir.display());
+// Cargo feature flags passed through to trunk
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct TrunkFeatures {
+    pub features: Vec<String>,
+    pub no_default_features: bool,
+}
+
+// Replace `$VAR` and `${VAR}` with the value from the environment, or nothing
+// when it is not set
+pub fn expand_env_vars(value: &str) -> String {
+    let mut expanded = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+
+        let braced = chars.peek() == Some(&'{');
+        if braced {
+            chars.next();
+        }
+        let mut name = String::new();
+        while let Some(&next) = chars.peek() {
+            if !(next.is_ascii_alphanumeric() || next == '_') {
+                break;
+            }
+            name.push(next);
+            chars.next();
+        }
+        if braced && chars.peek() == Some(&'}') {
+            chars.next();
+        }
+
+        match name.is_empty() {
+            true => expanded.push('$'),
+            false => expanded.push_str(&std::env::var(&name).unwrap_or_default()),
+        }
+    }
+
+    expanded
+}
+
+// The site's `build.env` with `overrides` from the command line on top,
+// variables expanded
+pub fn build_env(
+    configured: Option<&HashMap<String, String>>,
+    overrides: &[(String, String)],
+) -> HashMap<String, String> {
+    configured
+        .into_iter()
+        .flatten()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .chain(overrides.iter().cloned())
+        .map(|(key, value)| (key, expand_env_vars(&value)))
+        .collect()
+}
+
+#[derive(Serialize)]
+pub struct BuildReport {
+    pub app: String,
+    pub features: TrunkFeatures,
+    pub public_url: String,
+    // Names only, values can be secrets
+    pub env: Vec<String>,
+    pub duration_ms: u64,
+}
+
+impl CommandReport for BuildReport {
+    fn print_human(&self) {
+        println!("Built {} in {}ms", self.app, self.duration_ms);
+    }
+}
+
+// Public URL trunk builds with unless the flag or the site's `build` table
+// sets one
+pub const DEFAULT_PUBLIC_URL: &str = "/assets/";
+
+// The --public-url flag, else the site's, else DEFAULT_PUBLIC_URL
+pub fn public_url(flag: &Option<String>, build: Option<&BuildConfig>) -> String {
+    flag.clone()
+        .or_else(|| build.and_then(|build| build.public_url.clone()))
+        .unwrap_or_else(|| DEFAULT_PUBLIC_URL.to_string())
+}
+
+// Directory of dist the assets are served from: the last part of the public
+// URL's path, so /assets/ and /blog/assets/ both mean dist/assets, or dist
+// itself for / and ./
+pub fn assets_dir(dist_dir: &Path, public_url: &str) -> PathBuf {
+    // Drop the scheme and host of a full URL
+    let path = match public_url.split_once("://") {
+        Some((_, rest)) => rest.split_once('/').map_or("", |(_, path)| path),
+        None => public_url,
+    };
+    match path.trim_end_matches('/').rsplit('/').next() {
+        None | Some("") | Some(".") => dist_dir.to_path_buf(),
+        Some(last) => dist_dir.join(last),
+    }
+}
+
+// Build the trunk app, returning how long the build took in milliseconds
+pub fn run_trunk(
+    app_dir: &Path,
+    features: &TrunkFeatures,
+    env: &HashMap<String, String>,
+    public_url: &str,
+) -> Result<u64, Box<dyn Error>> {
+    let started = Instant::now();
+    status!("Building trunk app: {}", app_dir.display());
     let mut cmd = Command::new("trunk");
 
     // Move into the project directory
     cmd.current_dir(app_dir);
+    cmd.envs(env);
 
-    // Build the site
-    let result = cmd
-        .arg("build")
+    cmd.arg("build")
         .arg("--release")
         .arg("--public-url")
-        .arg("/assets/")
-        .status()
-        .expect("Failed to build trunk app");
+        .arg(public_url);
+    if !features.features.is_empty() {
+        cmd.arg("--features").arg(features.features.join(","));
+    }
+    if features.no_default_features {
+        cmd.arg("--no-default-features");
+    }
+
+    // Build the site
+    let result = cmd.status().expect("Failed to build trunk app");
 
     if !result.success() {
         return Err("Failed to build trunk app".into());
     }
 
-    Ok(())
+    Ok(started.elapsed().as_millis() as u64)
 }
 
-// Move the generated output files into the correct directories for deployment
-pub fn move_files(project_dir: &PathBuf) -> Result<PathBuf, Box<dyn Error>> {
+// Move the generated output files into the correct directories for deployment.
+// The assets go where `public_url` points, see `assets_dir`, and stay next
+// to index.html when that is dist itself. With `skip_move` the assets
+// directory is only created, for Trunk configs that already put the files in
+// place. With `dry_run` the assets directory is created but the moves are
+// only printed. Returns the dist directory and how long the move took in
+// milliseconds
+pub fn move_files(
+    project_dir: &Path,
+    public_url: &str,
+    skip_move: bool,
+    dry_run: bool,
+) -> Result<(PathBuf, u64), Box<dyn Error>> {
+    let started = Instant::now();
     // Get the output of the build
     let dist_dir = project_dir
         .join(
@@ -54,7 +473,17 @@ pub fn move_files(project_dir: &PathBuf) -> Result<PathBuf, Box<dyn Error>> {
         )
         .canonicalize()?;
 
-    let assets_dir = dist_dir.clone().join("assets");
+    let assets_dir = assets_dir(&dist_dir, public_url);
+    if assets_dir == dist_dir {
+        status!("Public URL {} serves assets from the dist root", public_url);
+        return Ok((dist_dir, started.elapsed().as_millis() as u64));
+    }
+
+    if skip_move {
+        fs::create_dir_all(&assets_dir)?;
+        status!("Created assets directory: {}", &assets_dir.display());
+        return Ok((dist_dir, started.elapsed().as_millis() as u64));
+    }
 
     // Arrange files in the correct directories
     // index.html
@@ -62,16 +491,20 @@ pub fn move_files(project_dir: &PathBuf) -> Result<PathBuf, Box<dyn Error>> {
     //   *.wasm
     //   *.js
     //   *.css
-    Command::new("nu")
-        .arg("-c")
-        .arg(format!(
-            "mkdir {}",
-            &assets_dir.to_str().expect("Failed to create assets path")
-        ))
-        .status()
-        .expect("Failed to create assets directory");
+    if dry_run {
+        fs::create_dir_all(&assets_dir)?;
+    } else {
+        Command::new("nu")
+            .arg("-c")
+            .arg(format!(
+                "mkdir {}",
+                &assets_dir.to_str().expect("Failed to create assets path")
+            ))
+            .status()
+            .expect("Failed to create assets directory");
+    }
 
-    println!("Created assets directory: {}", &assets_dir.display());
+    status!("Created assets directory: {}", &assets_dir.display());
 
     let moveable_file_types: Vec<&OsStr> = vec!["wasm", "js", "css"]
         .into_iter()
@@ -80,29 +513,379 @@ pub fn move_files(project_dir: &PathBuf) -> Result<PathBuf, Box<dyn Error>> {
 
     // Move all the css, wasm, and js files into the created assets directory
     for entry in fs::read_dir(&dist_dir)?
-        .into_iter()
         .filter(|f| f.is_ok())
         .map(|f| f.unwrap().path())
         .filter(|f| moveable_file_types.contains(&f.extension().unwrap_or_default()))
         .collect::<Vec<PathBuf>>()
     {
-        fs::rename(&entry, assets_dir.join(&entry.file_name().unwrap()))?;
+        let destination = assets_dir.join(entry.file_name().unwrap());
+        if dry_run {
+            status!(
+                "Would move: {} \u{2192} {}",
+                entry.display(),
+                destination.display()
+            );
+            continue;
+        }
+        fs::rename(&entry, destination)?;
+    }
+
+    if dry_run {
+        return Ok((dist_dir, started.elapsed().as_millis() as u64));
     }
 
-    println!(
+    status!(
         "Moved js, css, and wasm addets to {}",
         &assets_dir.display()
     );
 
-    Ok(dist_dir.clone())
+    Ok((dist_dir, started.elapsed().as_millis() as u64))
+}
+
+// Every file under `dir` by its `/` separated path relative to `root`.
+// Symlinks are followed, a directory already seen is skipped so links back
+// up the tree do not loop forever.
+pub fn walk_files(
+    root: &Path,
+    dir: &Path,
+    seen: &mut HashSet<PathBuf>,
+    files: &mut Vec<(String, PathBuf)>,
+) -> Result<(), DeployError> {
+    if !seen.insert(dir.canonicalize()?) {
+        status!("Skipping {}, a symlink cycle", dir.display());
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        // metadata follows symlinks, unlike the entry's file type
+        if fs::metadata(&path)?.is_dir() {
+            walk_files(root, &path, seen, files)?;
+            continue;
+        }
+        let relative = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .components()
+            .map(|part| part.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        files.push((relative, path));
+    }
+
+    Ok(())
+}
+
+// Left out of every deploy on top of the site's `ignore` list
+pub const DEFAULT_IGNORE: [&str; 5] = [".DS_Store", "Thumbs.db", "*~", "*.swp", ".git"];
+
+// `*` and `?` wildcards, matched against the whole text
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was and how much of the text it has taken
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+pub struct IgnorePatterns {
+    patterns: Vec<String>,
+}
+
+impl IgnorePatterns {
+    pub fn new(site_patterns: &[String]) -> Self {
+        IgnorePatterns {
+            patterns: DEFAULT_IGNORE
+                .iter()
+                .map(|pattern| pattern.to_string())
+                .chain(site_patterns.iter().cloned())
+                .collect(),
+        }
+    }
+
+    // The first pattern matching a `/` separated path. Patterns with a `/`
+    // match the whole path, others any single part of it, so `.git` also
+    // leaves out everything inside a .git directory.
+    pub fn matching(&self, relative: &str) -> Option<&str> {
+        self.patterns
+            .iter()
+            .find(|pattern| match pattern.contains('/') {
+                true => glob_match(pattern, relative),
+                false => relative.split('/').any(|part| glob_match(pattern, part)),
+            })
+            .map(String::as_str)
+    }
+}
+
+// Files left out of a deploy, with the pattern that matched each
+#[derive(Default)]
+pub struct IgnoredFiles {
+    pub files: Vec<(String, String)>,
+}
+
+impl IgnoredFiles {
+    pub fn by_pattern(&self) -> BTreeMap<&str, usize> {
+        let mut counts = BTreeMap::new();
+        for (_, pattern) in &self.files {
+            *counts.entry(pattern.as_str()).or_default() += 1;
+        }
+        counts
+    }
+}
+
+// Local paths in the src and href attributes of an html page, relative to
+// the site root
+fn local_references(html: &str) -> Vec<String> {
+    let mut references = Vec::new();
+    for attribute in ["src=", "href="] {
+        for (start, _) in html.match_indices(attribute) {
+            let rest = &html[start + attribute.len()..];
+            let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+                continue;
+            };
+            let Some(end) = rest[1..].find(quote) else {
+                continue;
+            };
+            let value = &rest[1..end + 1];
+            // Other hosts, fragments and schemes such as mailto: or data:
+            if value.starts_with("//") || value.starts_with('#') || value.contains(':') {
+                continue;
+            }
+            let path = value.split(['?', '#']).next().unwrap_or_default();
+            let path = path.trim_start_matches("./").trim_start_matches('/');
+            if !path.is_empty() {
+                references.push(path.to_string());
+            }
+        }
+    }
+    references
+}
+
+// Remove ignored files from the staged dist, after checking index.html does
+// not link to any of them, including extra_static files already skipped.
+// With `dry_run` they are only counted.
+pub fn apply_ignore(
+    dist_dir: &Path,
+    ignore: &IgnorePatterns,
+    dry_run: bool,
+    ignored: &mut IgnoredFiles,
+) -> Result<(), DeployError> {
+    let mut files = Vec::new();
+    walk_files(dist_dir, dist_dir, &mut HashSet::new(), &mut files)?;
+    let mut to_remove = Vec::new();
+    for (relative, path) in files {
+        if let Some(pattern) = ignore.matching(&relative) {
+            ignored.files.push((relative, pattern.to_string()));
+            to_remove.push(path);
+        }
+    }
+
+    if let Ok(html) = fs::read_to_string(dist_dir.join("index.html")) {
+        for reference in local_references(&html) {
+            if let Some((file, pattern)) = ignored.files.iter().find(|(file, _)| *file == reference)
+            {
+                return Err(DeployError::IgnoredReference {
+                    file: file.clone(),
+                    pattern: pattern.clone(),
+                });
+            }
+        }
+    }
+
+    for (pattern, count) in ignored.by_pattern() {
+        status!("Excluded {} files matching {}", count, pattern);
+    }
+    if !dry_run {
+        for path in to_remove {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+// A file copied into dist from an extra_static directory
+#[derive(Serialize)]
+pub struct StaticFile {
+    pub path: String,
+    pub source_dir: PathBuf,
+}
+
+// Copy the site's extra_static directories into dist, relative to the app
+// directory and keeping their layout. Later directories win over earlier
+// ones, but nothing may replace trunk output. Ignored files are skipped and
+// added to `ignored`. With `dry_run` the copies are only printed.
+pub fn copy_extra_static(
+    app_dir: &Path,
+    dist_dir: &Path,
+    extra_static: &[PathBuf],
+    ignore: &IgnorePatterns,
+    dry_run: bool,
+    ignored: &mut IgnoredFiles,
+) -> Result<Vec<StaticFile>, DeployError> {
+    if extra_static.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut built = Vec::new();
+    walk_files(dist_dir, dist_dir, &mut HashSet::new(), &mut built)?;
+    let built: HashSet<String> = built.into_iter().map(|(relative, _)| relative).collect();
+
+    let mut staged: BTreeMap<String, (PathBuf, PathBuf)> = BTreeMap::new();
+    for source_dir in extra_static {
+        let dir = app_dir.join(source_dir);
+        let mut files = Vec::new();
+        walk_files(&dir, &dir, &mut HashSet::new(), &mut files)?;
+        for (relative, path) in files {
+            if let Some(pattern) = ignore.matching(&relative) {
+                ignored.files.push((relative, pattern.to_string()));
+                continue;
+            }
+            staged.insert(relative, (source_dir.clone(), path));
+        }
+    }
+
+    for (relative, (source_dir, path)) in &staged {
+        // Left over from an earlier copy when trunk did not clean dist
+        let same = || fs::read(dist_dir.join(relative)).ok() == fs::read(path).ok();
+        if built.contains(relative) && !same() {
+            return Err(DeployError::StaticConflict {
+                file: relative.clone(),
+                source: source_dir.clone(),
+            });
+        }
+    }
+
+    let mut copied = Vec::with_capacity(staged.len());
+    for (relative, (source_dir, path)) in staged {
+        let destination = dist_dir.join(&relative);
+        if dry_run {
+            status!(
+                "Would copy: {} \u{2192} {} (from {})",
+                path.display(),
+                destination.display(),
+                source_dir.display()
+            );
+        } else {
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&path, &destination)?;
+        }
+        copied.push(StaticFile {
+            path: relative,
+            source_dir,
+        });
+    }
+
+    Ok(copied)
+}
+
+// Free bytes from `df -Pk` output. The POSIX format is the same on Linux and
+// the BSDs, though a long filesystem name can wrap onto its own line, so the
+// fields are counted across lines.
+pub fn parse_df_available(output: &str) -> Option<u64> {
+    let mut lines = output
+        .lines()
+        .skip_while(|line| !line.starts_with("Filesystem"));
+    lines.next()?;
+    let fields: Vec<&str> = lines.flat_map(str::split_whitespace).collect();
+    let available_kb: u64 = fields.get(3)?.parse().ok()?;
+
+    Some(available_kb * 1024)
+}
+
+// Make sure the server has room for `dist_size` more bytes before copying.
+// Until the old files are replaced both copies are on disk, so twice the
+// size is needed, plus a margin.
+pub fn check_remote_space(
+    server: &str,
+    site_dir: &str,
+    dist_size: u64,
+    ssh_args: &[&str],
+) -> Result<(), DeployError> {
+    // The site directory does not exist before the first deploy
+    let parent = Path::new(site_dir)
+        .parent()
+        .map(|parent| parent.to_string_lossy().to_string())
+        .unwrap_or_else(|| "/".to_string());
+    let df = ssh_output(
+        server,
+        ssh_args,
+        &format!(
+            "df -Pk {} 2>/dev/null || df -Pk {}",
+            shell_quote(site_dir),
+            shell_quote(&parent)
+        ),
+    )?;
+    let available = parse_df_available(&df).ok_or_else(|| DeployError::UnreadableDf(df.clone()))?;
+
+    let needed = dist_size * 2 + SPACE_MARGIN_BYTES;
+    if available >= needed {
+        return Ok(());
+    }
+
+    let releases = ssh_output(
+        server,
+        ssh_args,
+        &format!("du -sk {} 2>/dev/null", shell_quote(site_dir)),
+    )
+    .ok()
+    .and_then(|du| du.split_whitespace().next()?.parse::<u64>().ok())
+    .map(|kb| kb * 1024);
+    Err(DeployError::InsufficientSpace {
+        server: server.to_string(),
+        needed,
+        available,
+        releases,
+    })
+}
+
+// Total size of the files under `dir`
+pub fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        total += match meta.is_dir() {
+            true => dir_size(&entry.path())?,
+            false => meta.len(),
+        };
+    }
+    Ok(total)
 }
 
 pub fn scp_files(
-    dist_dir: &PathBuf,
+    dist_dir: &Path,
     server: &str,
     static_site_name: &str,
-) -> Result<(), Box<dyn Error>> {
-    let static_site_dir = PathBuf::from(format!("/var/www/{}", static_site_name));
+    identity: Option<&Path>,
+) -> Result<Transfer, Box<dyn Error>> {
+    let started = Instant::now();
+    let static_site_dir = PathBuf::from(remote_site_dir(static_site_name));
     let output_dir = match dist_dir.to_str().unwrap().starts_with("\\\\") {
         true => dist_dir
             .to_str()
@@ -119,13 +902,471 @@ pub fn scp_files(
         false => dist_dir.to_str().expect("dist_dir to be a str"),
     };
 
-    dbg!(output_dir);
+    let mut cmd = Command::new("scp");
+    if let Some(identity) = identity {
+        cmd.arg("-i").arg(identity);
+    }
+
+    let destination = format!("{}:{}", &server, &static_site_dir.display());
+    cmd.arg("-r").arg(output_dir).arg(&destination);
+    run_status(cmd, format!("scp -r {} {}", output_dir, destination))?;
+
+    Ok(Transfer {
+        duration_ms: started.elapsed().as_millis() as u64,
+        bytes: dir_size(dist_dir)?,
+    })
+}
+
+// Where an API site's binary lives on its server
+pub fn remote_api_dir(api_site_name: &str) -> String {
+    format!("/opt/{}", api_site_name)
+}
+
+#[derive(Deserialize)]
+struct CargoArtifactTarget {
+    name: String,
+}
+
+// A line of `cargo build --message-format=json`
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    target: Option<CargoArtifactTarget>,
+    executable: Option<PathBuf>,
+}
+
+// Build an API site with `cargo build --release`, returning the path of
+// `binary` as cargo reports it, so workspaces and CARGO_TARGET_DIR need no
+// guessing, and how long the build took in milliseconds
+pub fn cargo_build_release(
+    app_dir: &Path,
+    binary: &str,
+    features: &TrunkFeatures,
+    env: &HashMap<String, String>,
+) -> Result<(PathBuf, u64), Box<dyn Error>> {
+    let started = Instant::now();
+    status!("Building cargo binary {}: {}", binary, app_dir.display());
+    let mut cmd = Command::new("cargo");
+    cmd.current_dir(app_dir)
+        .envs(env)
+        .args([
+            "build",
+            "--release",
+            "--message-format=json-render-diagnostics",
+        ])
+        .arg("--bin")
+        .arg(binary);
+    if !features.features.is_empty() {
+        cmd.arg("--features").arg(features.features.join(","));
+    }
+    if features.no_default_features {
+        cmd.arg("--no-default-features");
+    }
+
+    let output = cmd.stderr(std::process::Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(DeployError::CommandFailed {
+            command: format!("cargo build --release --bin {}", binary),
+            status: output.status,
+        }
+        .into());
+    }
+
+    let executable = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| serde_json::from_str::<CargoMessage>(line).ok())
+        .filter(|message| message.reason == "compiler-artifact")
+        .filter(|message| {
+            message
+                .target
+                .as_ref()
+                .is_some_and(|target| target.name == binary)
+        })
+        .find_map(|message| message.executable)
+        .ok_or_else(|| format!("cargo did not report building binary {}", binary))?;
+
+    Ok((executable, started.elapsed().as_millis() as u64))
+}
 
-    Command::new("scp")
-        .arg("-r")
-        .arg(output_dir)
-        .arg(format!("{}:{}", &server, &static_site_dir.display()))
+fn run_status(mut cmd: Command, command: String) -> Result<(), DeployError> {
+    let status = cmd.status()?;
+    if !status.success() {
+        return Err(DeployError::CommandFailed { command, status });
+    }
+    Ok(())
+}
+
+// Copy an API binary to its server next to the running one, then move it
+// into place and restart its systemd service. The move is atomic, so the
+// service never starts from a half copied file. A `<service>.service` unit
+// given as `unit` is installed first.
+pub fn scp_api_binary(
+    executable: &Path,
+    unit: Option<&Path>,
+    server: &str,
+    api_site_name: &str,
+    service: &str,
+    identity: Option<&Path>,
+) -> Result<Transfer, DeployError> {
+    let started = Instant::now();
+    let remote_dir = remote_api_dir(api_site_name);
+    let staged = format!("{}/{}.new", remote_dir, api_site_name);
+    let ssh_args: Vec<&OsStr> = match identity {
+        Some(identity) => vec![OsStr::new("-i"), identity.as_os_str()],
+        None => vec![],
+    };
+
+    let mkdir = format!("mkdir -p {}", shell_quote(&remote_dir));
+    let mut cmd = Command::new("ssh");
+    cmd.args(&ssh_args).arg(server).arg(&mkdir);
+    run_status(cmd, format!("ssh {} \"{}\"", server, mkdir))?;
+
+    let mut cmd = Command::new("scp");
+    cmd.args(&ssh_args)
+        .arg(executable)
+        .arg(format!("{}:{}", server, staged));
+    run_status(
+        cmd,
+        format!("scp {} {}:{}", executable.display(), server, staged),
+    )?;
+    let mut bytes = fs::metadata(executable)?.len();
+
+    let mut remote_command = format!(
+        "chmod 755 {staged} && mv {staged} {executable}",
+        staged = shell_quote(&staged),
+        executable = shell_quote(&format!("{}/{}", remote_dir, api_site_name))
+    );
+    if let Some(unit) = unit {
+        let staged_unit = format!("{}/{}.service", remote_dir, service);
+        let mut cmd = Command::new("scp");
+        cmd.args(&ssh_args)
+            .arg(unit)
+            .arg(format!("{}:{}", server, staged_unit));
+        run_status(
+            cmd,
+            format!("scp {} {}:{}", unit.display(), server, staged_unit),
+        )?;
+        bytes += fs::metadata(unit)?.len();
+        remote_command.push_str(&format!(
+            " && sudo install -m 644 {} {} && sudo systemctl daemon-reload",
+            shell_quote(&staged_unit),
+            shell_quote(&format!("/etc/systemd/system/{}.service", service))
+        ));
+    }
+    remote_command.push_str(&format!(
+        " && sudo systemctl restart {}",
+        shell_quote(service)
+    ));
+
+    status!("Restarting {} on {}", service, server);
+    let mut cmd = Command::new("ssh");
+    cmd.args(&ssh_args).arg(server).arg(&remote_command);
+    run_status(cmd, format!("ssh {} \"{}\"", server, remote_command))?;
+
+    Ok(Transfer {
+        duration_ms: started.elapsed().as_millis() as u64,
+        bytes,
+    })
+}
+
+// Make the deployed files world readable and directories traversable so the
+// web server does not answer 403 when scp kept restrictive permissions
+pub fn fix_remote_permissions(
+    server: &str,
+    remote_path: &str,
+    ssh_args: &[&str],
+) -> Result<(), DeployError> {
+    let remote_command = format!("chmod -R go+rX {}", shell_quote(remote_path));
+    let status = Command::new("ssh")
+        .args(ssh_args)
+        .arg(server)
+        .arg(&remote_command)
         .status()?;
 
+    if !status.success() {
+        return Err(DeployError::CommandFailed {
+            command: format!("ssh {} \"{}\"", server, remote_command),
+            status,
+        });
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deploy_report_json_shape() {
+        let report = DeployReport {
+            app: "whynotcats".to_string(),
+            stages: ["build", "move", "transfer"]
+                .iter()
+                .zip([1200, 15, 800])
+                .map(|(name, duration_ms)| DeployStage {
+                    name: name.to_string(),
+                    duration_ms,
+                })
+                .collect(),
+            metrics: DeployMetrics {
+                build_duration_ms: 1200,
+                move_duration_ms: 15,
+                transfer_duration_ms: 800,
+                total_bytes_transferred: 524288,
+            },
+            servers: vec![ServerResult {
+                server: "web1".to_string(),
+                remote_path: "/var/www/whynotcats".to_string(),
+                permissions_fixed: true,
+            }],
+        };
+
+        crate::output::assert_golden("deploy_report", &report);
+    }
+
+    #[test]
+    fn shell_quote_keeps_paths_whole() {
+        assert_eq!(shell_quote("/var/www/whynotcats"), "'/var/www/whynotcats'");
+        assert_eq!(shell_quote("/var/www/my site"), "'/var/www/my site'");
+        assert_eq!(shell_quote("/tmp/x; rm -rf ~"), "'/tmp/x; rm -rf ~'");
+        assert_eq!(shell_quote("$(reboot)"), "'$(reboot)'");
+        assert_eq!(shell_quote("cat's"), r"'cat'\''s'");
+    }
+
+    #[test]
+    fn df_available_on_linux() {
+        let output = "\
+Filesystem     1024-blocks     Used Available Capacity Mounted on
+/dev/sda1         41152736 30012908   9026396      77% /
+";
+        assert_eq!(parse_df_available(output), Some(9026396 * 1024));
+    }
+
+    #[test]
+    fn df_available_on_bsd() {
+        // FreeBSD and macOS name the columns differently
+        let output = "\
+Filesystem  1024-blocks     Used    Avail Capacity  Mounted on
+/dev/ada0p2    20307196  6412844 12269780    34%    /
+";
+        assert_eq!(parse_df_available(output), Some(12269780 * 1024));
+    }
+
+    #[test]
+    fn df_available_with_a_wrapped_filesystem_name() {
+        let output = "\
+Filesystem     1024-blocks     Used Available Capacity Mounted on
+/dev/mapper/ubuntu--vg-ubuntu--lv--with--a--long--name
+                  98559220 61873788  31632724      67% /var/www
+";
+        assert_eq!(parse_df_available(output), Some(31632724 * 1024));
+    }
+
+    #[test]
+    fn df_available_skips_login_banners() {
+        let output = "\
+Welcome to web1
+Filesystem     1024-blocks  Used Available Capacity Mounted on
+tmpfs               204800     0    204800       0% /srv
+";
+        assert_eq!(parse_df_available(output), Some(204800 * 1024));
+    }
+
+    #[test]
+    fn df_available_unreadable_output() {
+        assert_eq!(parse_df_available(""), None);
+        assert_eq!(
+            parse_df_available("df: /var/www: No such file or directory"),
+            None
+        );
+        assert_eq!(
+            parse_df_available("Filesystem 1024-blocks Used Available Capacity Mounted on\n"),
+            None
+        );
+        assert_eq!(
+            parse_df_available("Filesystem 1024-blocks Used Available\n/dev/sda1 100 50 lots\n"),
+            None
+        );
+    }
+
+    // An empty scratch directory that no other test uses
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("admin-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(dir: &Path, relative: &str, contents: &str) {
+        let path = dir.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob_match("*.map", "app.js.map"));
+        assert!(glob_match("*.map", ".map"));
+        assert!(!glob_match("*.map", "app.map.js"));
+        assert!(!glob_match("*.map", "map"));
+        assert!(glob_match("debug.*", "debug.log"));
+        assert!(!glob_match("debug.*", "mydebug.log"));
+        assert!(!glob_match("debug.*", "debug"));
+        assert!(glob_match("*~", "index.html~"));
+        assert!(!glob_match("*~", "index.html"));
+        assert!(glob_match("?.swp", "a.swp"));
+        assert!(!glob_match("?.swp", "ab.swp"));
+        assert!(glob_match(".git", ".git"));
+        assert!(!glob_match(".git", ".gitignore"));
+    }
+
+    #[test]
+    fn ignore_patterns_match_paths() {
+        let site = ["*.map", "debug.*", "assets/old/*.js"].map(String::from);
+        let ignore = IgnorePatterns::new(&site);
+
+        assert_eq!(ignore.matching("assets/app.js.map"), Some("*.map"));
+        assert_eq!(ignore.matching("logs/debug.txt"), Some("debug.*"));
+        assert_eq!(ignore.matching("index.html~"), Some("*~"));
+        // Without a `/` a pattern matches any part, directories included
+        assert_eq!(ignore.matching(".git/HEAD"), Some(".git"));
+        assert_eq!(ignore.matching("vendor/.git/refs/main"), Some(".git"));
+        assert_eq!(ignore.matching("assets/.DS_Store"), Some(".DS_Store"));
+        // With one it is matched against the whole path
+        assert_eq!(
+            ignore.matching("assets/old/app.js"),
+            Some("assets/old/*.js")
+        );
+        assert_eq!(ignore.matching("old/app.js"), None);
+        assert_eq!(ignore.matching("static/assets/old/app.js"), None);
+        assert_eq!(ignore.matching("assets/app.js"), None);
+        assert_eq!(ignore.matching(".gitignore"), None);
+    }
+
+    #[test]
+    fn apply_ignore_removes_unreferenced_matches() {
+        let dist = scratch_dir("apply-ignore");
+        write(&dist, "index.html", r#"<script src="/app.js"></script>"#);
+        write(&dist, "app.js", "");
+        write(&dist, "app.js.map", "");
+        write(&dist, "assets/.DS_Store", "");
+        let ignore = IgnorePatterns::new(&["*.map".to_string()]);
+
+        let mut ignored = IgnoredFiles::default();
+        apply_ignore(&dist, &ignore, true, &mut ignored).unwrap();
+        assert_eq!(ignored.files.len(), 2);
+        assert!(dist.join("app.js.map").exists());
+
+        let mut ignored = IgnoredFiles::default();
+        apply_ignore(&dist, &ignore, false, &mut ignored).unwrap();
+        assert_eq!(
+            ignored.by_pattern(),
+            BTreeMap::from([(".DS_Store", 1), ("*.map", 1)])
+        );
+        assert!(!dist.join("app.js.map").exists());
+        assert!(!dist.join("assets/.DS_Store").exists());
+        assert!(dist.join("app.js").exists());
+        fs::remove_dir_all(&dist).unwrap();
+    }
+
+    #[test]
+    fn apply_ignore_refuses_when_index_links_an_ignored_file() {
+        let dist = scratch_dir("apply-ignore-linked");
+        write(
+            &dist,
+            "index.html",
+            r#"<link href="./debug.css?v=2" rel="stylesheet"><a href="https://example.com/debug.css">"#,
+        );
+        write(&dist, "debug.css", "");
+        let ignore = IgnorePatterns::new(&["debug.*".to_string()]);
+
+        let err = apply_ignore(&dist, &ignore, false, &mut IgnoredFiles::default()).unwrap_err();
+        match &err {
+            DeployError::IgnoredReference { file, pattern } => {
+                assert_eq!((file.as_str(), pattern.as_str()), ("debug.css", "debug.*"));
+            }
+            other => panic!("expected an ignored reference, got {}", other),
+        }
+        // Nothing is removed when the deploy is refused
+        assert!(dist.join("debug.css").exists());
+        fs::remove_dir_all(&dist).unwrap();
+    }
+
+    #[test]
+    fn extra_static_later_directories_win() {
+        let app = scratch_dir("extra-static");
+        let dist = app.join("dist");
+        write(&dist, "index.html", "<html></html>");
+        write(&dist, "robots.txt", "User-agent: *\n");
+        write(&app, "static/robots.txt", "User-agent: *\n");
+        write(&app, "static/favicon.ico", "old");
+        write(&app, "static/.well-known/security.txt", "Contact: a");
+        write(&app, "branding/favicon.ico", "new");
+        write(&app, "branding/logo.svg~", "");
+        let extra_static = [PathBuf::from("static"), PathBuf::from("branding")];
+        let ignore = IgnorePatterns::new(&[]);
+
+        let mut ignored = IgnoredFiles::default();
+        let copied =
+            copy_extra_static(&app, &dist, &extra_static, &ignore, false, &mut ignored).unwrap();
+
+        let sources: Vec<(&str, &Path)> = copied
+            .iter()
+            .map(|file| (file.path.as_str(), file.source_dir.as_path()))
+            .collect();
+        // The robots.txt trunk built is the same file, so it is not a conflict
+        assert_eq!(
+            sources,
+            [
+                (".well-known/security.txt", Path::new("static")),
+                ("favicon.ico", Path::new("branding")),
+                ("robots.txt", Path::new("static")),
+            ]
+        );
+        assert_eq!(fs::read_to_string(dist.join("favicon.ico")).unwrap(), "new");
+        assert!(dist.join(".well-known/security.txt").exists());
+        assert_eq!(ignored.files, [("logo.svg~".to_string(), "*~".to_string())]);
+        assert!(!dist.join("logo.svg~").exists());
+        fs::remove_dir_all(&app).unwrap();
+    }
+
+    #[test]
+    fn extra_static_must_not_replace_trunk_output() {
+        let app = scratch_dir("extra-static-conflict");
+        let dist = app.join("dist");
+        write(&dist, "index.html", "<html>built</html>");
+        write(&dist, "app.js", "built");
+        write(&app, "static/index.html", "<html>static</html>");
+        write(&app, "static/app.js", "built");
+        write(&app, "static/robots.txt", "");
+        let extra_static = [PathBuf::from("static")];
+        let ignore = IgnorePatterns::new(&[]);
+
+        let result = copy_extra_static(
+            &app,
+            &dist,
+            &extra_static,
+            &ignore,
+            false,
+            &mut IgnoredFiles::default(),
+        );
+        match result {
+            Err(DeployError::StaticConflict { file, source }) => {
+                assert_eq!(
+                    (file.as_str(), source.as_path()),
+                    ("index.html", Path::new("static"))
+                );
+            }
+            Err(other) => panic!("expected a static conflict, got {}", other),
+            Ok(_) => panic!("expected a static conflict"),
+        }
+        // Checked before anything is copied
+        assert_eq!(
+            fs::read_to_string(dist.join("index.html")).unwrap(),
+            "<html>built</html>"
+        );
+        assert!(!dist.join("robots.txt").exists());
+        fs::remove_dir_all(&app).unwrap();
+    }
+}
@@ -0,0 +1,342 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::env::current_dir;
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::UserConfig;
+use crate::es::{build_client, EsArgs};
+use crate::metadata::{discover_project_toml, load_metadata, select_project};
+use crate::output::CommandReport;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Status {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Status::Pass => 0,
+            Status::Warn => 1,
+            Status::Fail => 2,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: &str, status: Status, detail: impl Into<String>, hint: Option<&str>) -> Check {
+        Check {
+            name: name.to_string(),
+            status,
+            detail: detail.into(),
+            hint: hint.map(String::from),
+        }
+    }
+}
+
+struct Tool {
+    name: &'static str,
+    version_args: &'static [&'static str],
+    required: bool,
+    hint: &'static str,
+}
+
+const TOOLS: [Tool; 6] = [
+    Tool {
+        name: "trunk",
+        version_args: &["--version"],
+        required: true,
+        hint: "cargo install trunk",
+    },
+    Tool {
+        name: "scp",
+        version_args: &[],
+        required: true,
+        hint: "install an OpenSSH client",
+    },
+    Tool {
+        name: "nu",
+        version_args: &["--version"],
+        required: true,
+        hint: "cargo install nu, used to arrange build output",
+    },
+    Tool {
+        name: "git",
+        version_args: &["--version"],
+        required: false,
+        hint: "install git from your package manager",
+    },
+    Tool {
+        name: "rsync",
+        version_args: &["--version"],
+        required: false,
+        hint: "install rsync from your package manager",
+    },
+    Tool {
+        name: "wasm-opt",
+        version_args: &["--version"],
+        required: false,
+        hint: "install binaryen for smaller wasm output",
+    },
+];
+
+// Run a tool and report the first line it prints, which is its version for
+// most tools. scp has no version flag so its usage output proves it exists.
+fn check_tool(tool: &Tool) -> Check {
+    let name = format!("tool: {}", tool.name);
+    match Command::new(tool.name).args(tool.version_args).output() {
+        Ok(output) => {
+            let text = if output.stdout.is_empty() {
+                String::from_utf8_lossy(&output.stderr).to_string()
+            } else {
+                String::from_utf8_lossy(&output.stdout).to_string()
+            };
+            let version = match tool.version_args.is_empty() {
+                true => "installed".to_string(),
+                false => text
+                    .lines()
+                    .next()
+                    .unwrap_or("installed")
+                    .trim()
+                    .to_string(),
+            };
+            Check::new(&name, Status::Pass, version, None)
+        }
+        Err(err) => {
+            let status = if tool.required {
+                Status::Fail
+            } else {
+                Status::Warn
+            };
+            let detail = match err.kind() {
+                ErrorKind::NotFound => "not found on PATH".to_string(),
+                _ => err.to_string(),
+            };
+            Check::new(&name, status, detail, Some(tool.hint))
+        }
+    }
+}
+
+async fn check_elasticsearch(es: &EsArgs, config: &UserConfig) -> Check {
+    let name = "elasticsearch";
+    let hint = Some("check --elasticsearch or run `admin config set elasticsearch <url>`");
+    let endpoint = match es.endpoint(config) {
+        Ok(endpoint) => endpoint,
+        Err(err) => return Check::new(name, Status::Fail, err.to_string(), hint),
+    };
+
+    let client = match build_client(es, config) {
+        Ok(client) => client,
+        Err(err) => return Check::new(name, Status::Fail, err.to_string(), hint),
+    };
+
+    match client.info().send().await {
+        Ok(response) if response.status_code().is_success() => {
+            let body = response.json::<Value>().await.unwrap_or_default();
+            let version = body["version"]["number"].as_str().unwrap_or("unknown");
+            Check::new(
+                name,
+                Status::Pass,
+                format!("{} is up, version {}", endpoint, version),
+                None,
+            )
+        }
+        Ok(response) => Check::new(
+            name,
+            Status::Fail,
+            format!("{} responded with {}", endpoint, response.status_code()),
+            Some("check the credentials for the cluster"),
+        ),
+        Err(err) => Check::new(
+            name,
+            Status::Fail,
+            format!("could not reach {}: {}", endpoint, err),
+            hint,
+        ),
+    }
+}
+
+fn check_ssh(server: &str) -> Check {
+    let name = format!("ssh: {}", server);
+    let result = Command::new("ssh")
+        .args([
+            "-o",
+            "BatchMode=yes",
+            "-o",
+            "ConnectTimeout=5",
+            server,
+            "true",
+        ])
+        .output();
+
+    match result {
+        Ok(output) if output.status.success() => Check::new(&name, Status::Pass, "reachable", None),
+        Ok(output) => Check::new(
+            &name,
+            Status::Fail,
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            Some("check ~/.ssh/config has an entry for the host and the key is loaded"),
+        ),
+        Err(err) => Check::new(
+            &name,
+            Status::Fail,
+            err.to_string(),
+            Some("install an OpenSSH client"),
+        ),
+    }
+}
+
+// Validate the project toml, returning the project directory when it loads
+fn check_project(
+    project_toml: &Option<PathBuf>,
+    project: &Option<String>,
+    checks: &mut Vec<Check>,
+) -> Option<PathBuf> {
+    let name = "project: .cat.toml";
+    let candidates = match project_toml {
+        Some(path) => vec![path.clone()],
+        None => match current_dir().and_then(|dir| discover_project_toml(&dir)) {
+            Ok(candidates) => match project {
+                Some(project) => select_project(&candidates, project),
+                None => candidates,
+            },
+            Err(err) => {
+                checks.push(Check::new(name, Status::Fail, err.to_string(), None));
+                return None;
+            }
+        },
+    };
+
+    match candidates.as_slice() {
+        [] => {
+            checks.push(Check::new(
+                name,
+                Status::Warn,
+                "no project found from the current directory",
+                Some("run from inside a project or pass --project-toml"),
+            ));
+            None
+        }
+        [path] => match load_metadata(path) {
+            Ok(metadata) => {
+                checks.push(Check::new(
+                    name,
+                    Status::Pass,
+                    format!("{} with {} site(s)", path.display(), metadata.sites.len()),
+                    None,
+                ));
+                Some(
+                    metadata
+                        .source_dir
+                        .unwrap_or_else(|| path.parent().unwrap_or(Path::new(".")).to_path_buf()),
+                )
+            }
+            Err(err) => {
+                checks.push(Check::new(
+                    name,
+                    Status::Fail,
+                    format!("{}: {}", path.display(), err),
+                    Some("fix the toml syntax or the listed fields"),
+                ));
+                None
+            }
+        },
+        many => {
+            let paths: Vec<String> = many.iter().map(|p| p.display().to_string()).collect();
+            checks.push(Check::new(
+                name,
+                Status::Fail,
+                format!("more than one project found: {}", paths.join(", ")),
+                Some("pass --project <name> or --project-toml to pick one"),
+            ));
+            None
+        }
+    }
+}
+
+fn check_writable(dir: &Path) -> Check {
+    let name = format!("writable: {}", dir.display());
+    let probe = dir.join(".admin-doctor-probe");
+    match fs::write(&probe, b"") {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            Check::new(&name, Status::Pass, "writable", None)
+        }
+        Err(err) => Check::new(
+            &name,
+            Status::Fail,
+            err.to_string(),
+            Some("fix the directory permissions"),
+        ),
+    }
+}
+
+#[derive(Serialize)]
+pub struct DoctorReport {
+    pub status: Status,
+    pub checks: Vec<Check>,
+}
+
+impl CommandReport for DoctorReport {
+    fn print_human(&self) {
+        for check in &self.checks {
+            let label = match check.status {
+                Status::Pass => "PASS",
+                Status::Warn => "WARN",
+                Status::Fail => "FAIL",
+            };
+            println!("[{}] {}: {}", label, check.name, check.detail);
+            if let Some(hint) = &check.hint {
+                println!("       hint: {}", hint);
+            }
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        self.status.exit_code()
+    }
+}
+
+// Run every preflight check, the report status is the worst result seen
+pub async fn run_doctor(
+    es: &EsArgs,
+    config: &UserConfig,
+    servers: &[String],
+    project_toml: &Option<PathBuf>,
+    project: &Option<String>,
+) -> Result<DoctorReport, Box<dyn Error>> {
+    let mut checks: Vec<Check> = TOOLS.iter().map(check_tool).collect();
+
+    checks.push(check_elasticsearch(es, config).await);
+    checks.extend(servers.iter().map(|server| check_ssh(server)));
+
+    let mut output_dirs = vec![current_dir()?];
+    if let Some(project_dir) = check_project(project_toml, project, &mut checks) {
+        output_dirs.push(project_dir);
+    }
+    output_dirs.dedup();
+    checks.extend(output_dirs.iter().map(|dir| check_writable(dir)));
+
+    let status = checks
+        .iter()
+        .map(|check| check.status)
+        .max()
+        .unwrap_or(Status::Pass);
+
+    Ok(DoctorReport { status, checks })
+}
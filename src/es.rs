@@ -0,0 +1,195 @@
+use clap::Args;
+use elasticsearch::{
+    auth::Credentials,
+    cert::{Certificate, CertificateValidation},
+    http::{
+        headers::{HeaderValue, AUTHORIZATION},
+        transport::{CloudConnectionPool, CloudId, SingleNodeConnectionPool, TransportBuilder},
+        StatusCode, Url,
+    },
+    Elasticsearch,
+};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config::UserConfig;
+use crate::logging::redact_url_password;
+
+// Connection flags shared by every subcommand that talks to Elasticsearch
+#[derive(Args, Debug, Clone)]
+pub struct EsArgs {
+    /// Elasticsearch URL, cannot be combined with --es-cloud-id
+    #[clap(short, long, conflicts_with = "es_cloud_id")]
+    pub elasticsearch: Option<String>,
+
+    /// Elastic Cloud deployment id, used instead of --elasticsearch. Needs
+    /// --api-key or --es-cloud-api-key
+    #[clap(long, visible_alias = "cloud-id")]
+    pub es_cloud_id: Option<String>,
+
+    /// API key for --es-cloud-id, either `id:api_key` or the encoded form from the console
+    #[clap(long, requires = "es_cloud_id", conflicts_with = "api_key")]
+    pub es_cloud_api_key: Option<String>,
+
+    /// User for basic authentication against --elasticsearch
+    #[clap(long, conflicts_with = "es_cloud_id")]
+    pub username: Option<String>,
+
+    /// API key for --elasticsearch or --cloud-id, either `id:api_key` or the
+    /// encoded form. Also read from ES_API_KEY
+    #[clap(
+        long,
+        env = "ES_API_KEY",
+        hide_env_values = true,
+        conflicts_with = "username"
+    )]
+    pub api_key: Option<String>,
+
+    /// Password for --username. Prefer ES_PASSWORD, which stays out of the
+    /// shell history
+    #[clap(long, env = "ES_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+
+    /// PEM bundle of the CA that signed the cluster's certificate, for
+    /// clusters behind an internal CA
+    #[clap(long)]
+    pub ca_cert: Option<PathBuf>,
+
+    /// Skip TLS certificate verification, only for local clusters with
+    /// self-signed certificates
+    #[clap(long, conflicts_with = "ca_cert")]
+    pub insecure: bool,
+}
+
+impl EsArgs {
+    // Human readable endpoint, decoding the cloud id so it can be checked and
+    // hiding any password in the URL
+    pub fn endpoint(&self, config: &UserConfig) -> Result<String, Box<dyn Error>> {
+        match &self.es_cloud_id {
+            Some(cloud_id) => {
+                let cloud = CloudId::parse(cloud_id)?;
+                Ok(format!("{} ({})", cloud.url, cloud.name))
+            }
+            None => Ok(redact_url_password(
+                &config.elasticsearch_url(&self.elasticsearch),
+            )),
+        }
+    }
+}
+
+fn api_key_credentials(
+    builder: TransportBuilder,
+    api_key: &str,
+) -> Result<TransportBuilder, Box<dyn Error>> {
+    match api_key.split_once(':') {
+        Some((id, key)) => Ok(builder.auth(Credentials::ApiKey(id.into(), key.into()))),
+        // Already base64 encoded `id:api_key`, send it as is
+        None => Ok(builder.header(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("ApiKey {}", api_key))?,
+        )),
+    }
+}
+
+fn cert_validation(args: &EsArgs) -> Result<CertificateValidation, Box<dyn Error>> {
+    match (&args.ca_cert, args.insecure) {
+        (Some(path), _) => {
+            let pem = fs::read(path)
+                .map_err(|err| format!("Could not read --ca-cert {}: {}", path.display(), err))?;
+            let cert = Certificate::from_pem(&pem)
+                .map_err(|err| format!("Invalid --ca-cert {}: {}", path.display(), err))?;
+            Ok(CertificateValidation::Full(cert))
+        }
+        (None, true) => Ok(CertificateValidation::None),
+        (None, false) => Ok(CertificateValidation::Default),
+    }
+}
+
+// Whether a request failed on the certificate rather than the connection,
+// reqwest only says so in the error's message
+fn is_tls_error(err: &elasticsearch::Error) -> bool {
+    let mut source: Option<&dyn Error> = Some(err);
+    while let Some(err) = source {
+        let message = err.to_string().to_lowercase();
+        if message.contains("certificate") || message.contains("tls") {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+// Build a client from the connection flags, falling back to the user config
+pub fn build_client(args: &EsArgs, config: &UserConfig) -> Result<Elasticsearch, Box<dyn Error>> {
+    let validation = cert_validation(args)?;
+    let builder = match &args.es_cloud_id {
+        Some(cloud_id) => {
+            let builder = TransportBuilder::new(CloudConnectionPool::new(cloud_id)?)
+                .cert_validation(validation);
+            let api_key = args
+                .es_cloud_api_key
+                .as_deref()
+                .or(args.api_key.as_deref())
+                .ok_or("--cloud-id requires --api-key, ES_API_KEY or --es-cloud-api-key")?;
+            api_key_credentials(builder, api_key)?
+        }
+        None => {
+            let url = Url::parse(&config.elasticsearch_url(&args.elasticsearch))?;
+            let builder = TransportBuilder::new(SingleNodeConnectionPool::new(url))
+                .cert_validation(validation);
+            if let Some(api_key) = &args.api_key {
+                return Ok(Elasticsearch::new(
+                    api_key_credentials(builder, api_key)?.build()?,
+                ));
+            }
+            match (&args.username, &args.password) {
+                (Some(username), Some(password)) => {
+                    builder.auth(Credentials::Basic(username.clone(), password.clone()))
+                }
+                (Some(_), None) => {
+                    return Err("--username requires --password or ES_PASSWORD".into())
+                }
+                // ES_PASSWORD may be set for other tools, it only counts with a user
+                (None, _) => builder,
+            }
+        }
+    };
+
+    Ok(Elasticsearch::new(builder.build()?))
+}
+
+// `build_client`, then ask the cluster for its info to make sure it accepts
+// the credentials and the certificate, so a refusal is reported before a
+// long run starts instead of by whichever request comes first. Anything but
+// a 401 or a TLS failure is left for the command itself to run into.
+pub async fn connect(args: &EsArgs, config: &UserConfig) -> Result<Elasticsearch, Box<dyn Error>> {
+    let client = build_client(args, config)?;
+    match client.info().send().await {
+        Ok(response) if response.status_code() == StatusCode::UNAUTHORIZED => {
+            return Err(format!("authentication failed against {}", args.endpoint(config)?).into());
+        }
+        Err(err) if is_tls_error(&err) => {
+            let hint = match (&args.ca_cert, args.insecure) {
+                (None, false) => {
+                    "Pass --ca-cert <pem> if it is signed by an internal CA, or --insecure for a \
+                     local cluster with a self-signed certificate"
+                }
+                (Some(_), _) => {
+                    "Check that --ca-cert is the CA that signed the cluster's certificate"
+                }
+                (None, true) => "The cluster may not speak TLS on this port",
+            };
+            return Err(format!(
+                "TLS handshake with {} failed: {}. {}",
+                args.endpoint(config)?,
+                err,
+                hint
+            )
+            .into());
+        }
+        _ => {}
+    }
+
+    Ok(client)
+}
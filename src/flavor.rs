@@ -0,0 +1,140 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fmt;
+use std::path::Path;
+
+// The standard geonames extracts. They share a schema but differ a lot in
+// size, so they get different seed defaults.
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DumpFlavor {
+    // allCountries, every feature of every country, around 12 million rows
+    AllCountries,
+    // Cities with a population of at least 500, 1000, 5000 or 15000
+    Cities500,
+    Cities1000,
+    Cities5000,
+    Cities15000,
+    // A single country extract such as US.zip
+    Country,
+    // Anything else, including stdin
+    Other,
+}
+
+impl DumpFlavor {
+    // Recognise the flavor from the archive or file name
+    pub fn detect(path: &str) -> Self {
+        let Some(stem) = Path::new(path).file_stem().and_then(|stem| stem.to_str()) else {
+            return DumpFlavor::Other;
+        };
+
+        match stem.to_lowercase().as_str() {
+            "allcountries" => DumpFlavor::AllCountries,
+            "cities500" => DumpFlavor::Cities500,
+            "cities1000" => DumpFlavor::Cities1000,
+            "cities5000" => DumpFlavor::Cities5000,
+            "cities15000" => DumpFlavor::Cities15000,
+            _ if stem.len() == 2 && stem.chars().all(|c| c.is_ascii_uppercase()) => {
+                DumpFlavor::Country
+            }
+            _ => DumpFlavor::Other,
+        }
+    }
+
+    // --flavor when given, otherwise detected from the file name. Stdin has
+    // no name to go on.
+    pub fn resolve(flavor: Option<DumpFlavor>, path: Option<&str>) -> Self {
+        match (flavor, path) {
+            (Some(flavor), _) => flavor,
+            (None, Some(path)) => DumpFlavor::detect(path),
+            (None, None) => DumpFlavor::Other,
+        }
+    }
+
+    // Records per bulk request when --buffer is not given. The cities files
+    // fit in a few batches, allCountries is dominated by request overhead.
+    pub fn default_buffer(self) -> usize {
+        match self {
+            DumpFlavor::AllCountries => 200_000,
+            DumpFlavor::Cities500
+            | DumpFlavor::Cities1000
+            | DumpFlavor::Cities5000
+            | DumpFlavor::Cities15000 => 20_000,
+            DumpFlavor::Country | DumpFlavor::Other => 100_000,
+        }
+    }
+
+    // Filter worth adding for this flavor, the cities files are already
+    // filtered by population
+    pub fn suggested_filter(self) -> Option<&'static str> {
+        match self {
+            DumpFlavor::AllCountries => Some("--feature-class P,A"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DumpFlavor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DumpFlavor::AllCountries => "allCountries",
+            DumpFlavor::Cities500 => "cities500",
+            DumpFlavor::Cities1000 => "cities1000",
+            DumpFlavor::Cities5000 => "cities5000",
+            DumpFlavor::Cities15000 => "cities15000",
+            DumpFlavor::Country => "single country",
+            DumpFlavor::Other => "unrecognised",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_the_standard_extracts() {
+        let cases = [
+            ("allCountries.zip", DumpFlavor::AllCountries),
+            ("/data/geonames/allCountries.txt", DumpFlavor::AllCountries),
+            ("ALLCOUNTRIES.ZIP", DumpFlavor::AllCountries),
+            ("cities500.zip", DumpFlavor::Cities500),
+            ("cities1000.txt", DumpFlavor::Cities1000),
+            ("dumps/cities5000.zip", DumpFlavor::Cities5000),
+            ("Cities15000.zip", DumpFlavor::Cities15000),
+            ("US.zip", DumpFlavor::Country),
+            ("downloads/DE.txt", DumpFlavor::Country),
+            ("us.zip", DumpFlavor::Other),
+            ("USA.zip", DumpFlavor::Other),
+            ("cities500-2022.zip", DumpFlavor::Other),
+            ("alternateNames.zip", DumpFlavor::Other),
+            ("-", DumpFlavor::Other),
+            ("", DumpFlavor::Other),
+        ];
+
+        for (path, flavor) in cases {
+            assert_eq!(DumpFlavor::detect(path), flavor, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn flavor_option_overrides_the_file_name() {
+        let cities = DumpFlavor::from_str("cities500", false).unwrap();
+        assert_eq!(
+            DumpFlavor::resolve(Some(cities), Some("allCountries.zip")),
+            DumpFlavor::Cities500
+        );
+        assert_eq!(
+            DumpFlavor::resolve(Some(DumpFlavor::AllCountries), None),
+            DumpFlavor::AllCountries
+        );
+        assert_eq!(
+            DumpFlavor::resolve(None, Some("allCountries.zip")),
+            DumpFlavor::AllCountries
+        );
+        assert_eq!(DumpFlavor::resolve(None, None), DumpFlavor::Other);
+        assert!(DumpFlavor::from_str("all-countries", false).is_ok());
+        assert!(DumpFlavor::from_str("allcountries", false).is_err());
+    }
+}
@@ -1,8 +1,19 @@
-use chrono::NaiveDate;
+use chrono::{NaiveDate, Utc};
+use clap::ValueEnum;
 use csv;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::{collections::HashMap, error::Error};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    error::Error,
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::iso3166::ALPHA2_TO_ALPHA3;
+use crate::output::CommandReport;
 
 //  code, name, name ascii, geonameid
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -22,7 +33,235 @@ pub struct Admin2Data {
     pub geonameid: i64,
 }
 
-trait AdminData {
+// Lookup of admin code (e.g. US.CA) to display name
+pub type AdminLookup = HashMap<String, String>;
+
+// log10 of the most populous places in geonames (~40 million), used to scale
+// population into 0..=1
+const MAX_POPULATION_LOG10: f64 = 7.6;
+
+// Completion suggester weights must be integers
+const SUGGEST_WEIGHT_SCALE: f64 = 1000.0;
+
+// Upper bounds in metres of the elevation categories, anything higher is
+// high_mountain
+const ELEVATION_CATEGORIES: [(i64, &str); 5] = [
+    (0, "below_sea_level"),
+    (10, "sea_level"),
+    (500, "lowland"),
+    (1500, "highland"),
+    (3500, "mountain"),
+];
+
+// Optional parts of the generated documents and mapping
+#[derive(Debug, Clone)]
+pub struct DocumentOptions {
+    pub suggest: bool,
+    // "Name, Admin1, Country" label for autocomplete, off so existing indices
+    // keep their shape
+    pub display_name: bool,
+    pub elevation_category: bool,
+    // Write an empty timezone as null so `exists` queries skip it
+    pub null_empty_timezone: bool,
+    // Field holding the time the document was seeded, which is not when
+    // GeoNames last modified the record, that is `modification_date`
+    pub seeded_at_field: Option<String>,
+    // Alternate names kept per record after removing duplicates, all when unset
+    pub max_alternate_names: Option<usize>,
+    // Fields of BASE_FIELDS left out of documents and mapping
+    pub excluded_fields: BTreeSet<String>,
+}
+
+// Fields every document has unless excluded, options add the rest
+pub const BASE_FIELDS: [&str; 15] = [
+    "name",
+    "ascii_name",
+    "alternate_names",
+    "location",
+    "elevation",
+    "country_code",
+    "country_code_iso3",
+    "cc2",
+    "feature_code",
+    "feature_class",
+    "admin1",
+    "admin2",
+    "population",
+    "timezone",
+    "modification_date",
+];
+
+// Edit distance, for suggesting the field that was probably meant
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous + usize::from(ca != *cb);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+impl Default for DocumentOptions {
+    fn default() -> Self {
+        DocumentOptions {
+            suggest: true,
+            display_name: false,
+            elevation_category: true,
+            null_empty_timezone: false,
+            seeded_at_field: None,
+            max_alternate_names: None,
+            excluded_fields: BTreeSet::new(),
+        }
+    }
+}
+
+impl DocumentOptions {
+    // Fields only written with an option, paired with whether it is on and
+    // how to turn it on
+    fn option_fields(&self) -> Vec<(&str, bool, &'static str)> {
+        vec![
+            (
+                "elevation_category",
+                self.elevation_category,
+                "without --no-elevation-category",
+            ),
+            ("display_name", self.display_name, "with --display-name"),
+            ("suggest", self.suggest, "without --no-suggest"),
+            (
+                self.seeded_at_field.as_deref().unwrap_or("seeded_at"),
+                self.seeded_at_field.is_some(),
+                "with --seeded-at-field",
+            ),
+        ]
+    }
+
+    // Every field the documents and mapping will have
+    pub fn fields(&self) -> Vec<String> {
+        BASE_FIELDS
+            .iter()
+            .copied()
+            .filter(|field| !self.excluded_fields.contains(*field))
+            .chain(
+                self.option_fields()
+                    .into_iter()
+                    .filter(|(_, enabled, _)| *enabled)
+                    .map(|(field, _, _)| field),
+            )
+            .map(String::from)
+            .collect()
+    }
+
+    // Restrict the fields to `include`, or leave out `exclude`. Excluding a
+    // field added by an option turns the option off, so the document and
+    // mapping stay in step.
+    pub fn select_fields(&mut self, include: &[String], exclude: &[String]) -> Result<(), String> {
+        let known: Vec<&str> = BASE_FIELDS
+            .iter()
+            .copied()
+            .chain(self.option_fields().into_iter().map(|(field, _, _)| field))
+            .collect();
+        for field in include.iter().chain(exclude) {
+            if known.contains(&field.as_str()) {
+                continue;
+            }
+            let closest = known
+                .iter()
+                .map(|candidate| (edit_distance(field, candidate), *candidate))
+                .min()
+                .filter(|(distance, _)| *distance <= 3);
+            return Err(match closest {
+                Some((_, candidate)) => {
+                    format!("Unknown field {}, did you mean {}?", field, candidate)
+                }
+                None => format!(
+                    "Unknown field {}, known fields are {}",
+                    field,
+                    known.join(", ")
+                ),
+            });
+        }
+
+        let unwanted: BTreeSet<String> = match include.is_empty() {
+            true => exclude.iter().cloned().collect(),
+            false => {
+                for (field, enabled, flag) in self.option_fields() {
+                    if !enabled && include.iter().any(|included| included == field) {
+                        return Err(format!("Field {} is only generated {}", field, flag));
+                    }
+                }
+                known
+                    .iter()
+                    .filter(|field| !include.iter().any(|included| included == *field))
+                    .map(|field| field.to_string())
+                    .collect()
+            }
+        };
+
+        if self.null_empty_timezone && unwanted.contains("timezone") {
+            return Err("--null-empty-timezone needs the timezone field".to_string());
+        }
+
+        for field in unwanted {
+            match field.as_str() {
+                "elevation_category" => self.elevation_category = false,
+                "display_name" => self.display_name = false,
+                "suggest" => self.suggest = false,
+                name if self.seeded_at_field.as_deref() == Some(name) => {
+                    self.seeded_at_field = None
+                }
+                name if BASE_FIELDS.contains(&name) => {
+                    self.excluded_fields.insert(field);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Alternate names of a record as written to documents, with counts of what
+// was left out
+#[derive(Debug, Clone, Default)]
+pub struct AlternateNames {
+    pub names: Vec<String>,
+    // Repeats differing only in case, or the same as name or ascii_name
+    pub duplicates: usize,
+    // Unique names past --max-alternate-names
+    pub capped: usize,
+}
+
+// Rough writing system of a name from its first letter, so a cap keeps one
+// name per script before keeping more of the same one
+fn script_of(name: &str) -> &'static str {
+    let Some(c) = name.chars().find(|c| c.is_alphabetic()) else {
+        return "other";
+    };
+    match c as u32 {
+        0x0000..=0x024F | 0x1E00..=0x1EFF => "latin",
+        0x0370..=0x03FF | 0x1F00..=0x1FFF => "greek",
+        0x0400..=0x052F => "cyrillic",
+        0x0530..=0x058F => "armenian",
+        0x0590..=0x05FF => "hebrew",
+        0x0600..=0x06FF | 0x0750..=0x077F | 0xFB50..=0xFEFF => "arabic",
+        0x0900..=0x0DFF => "indic",
+        0x0E00..=0x0E7F => "thai",
+        0x10A0..=0x10FF => "georgian",
+        0x1100..=0x11FF | 0xAC00..=0xD7AF => "hangul",
+        0x3040..=0x30FF => "kana",
+        0x3400..=0x9FFF | 0xF900..=0xFAFF => "han",
+        _ => "other",
+    }
+}
+
+// A row of an admin codes file, keyed by its full code
+pub trait AdminData {
     fn key(&self) -> String;
     fn value(&self) -> String;
 }
@@ -47,6 +286,51 @@ impl AdminData for Admin2Data {
     }
 }
 
+// Either level of admin division, for code handling both files alike
+#[derive(Debug, Clone, Serialize)]
+pub enum AdminEntry {
+    Admin1(Admin1Data),
+    Admin2(Admin2Data),
+}
+
+impl AdminData for AdminEntry {
+    fn key(&self) -> String {
+        match self {
+            AdminEntry::Admin1(data) => data.key(),
+            AdminEntry::Admin2(data) => data.key(),
+        }
+    }
+
+    fn value(&self) -> String {
+        match self {
+            AdminEntry::Admin1(data) => data.value(),
+            AdminEntry::Admin2(data) => data.value(),
+        }
+    }
+}
+
+impl TryFrom<&AdminEntry> for Admin1Data {
+    type Error = String;
+
+    fn try_from(entry: &AdminEntry) -> Result<Self, Self::Error> {
+        match entry {
+            AdminEntry::Admin1(data) => Ok(data.clone()),
+            AdminEntry::Admin2(data) => Err(format!("{} is an admin2 entry", data.code)),
+        }
+    }
+}
+
+impl TryFrom<&AdminEntry> for Admin2Data {
+    type Error = String;
+
+    fn try_from(entry: &AdminEntry) -> Result<Self, Self::Error> {
+        match entry {
+            AdminEntry::Admin2(data) => Ok(data.clone()),
+            AdminEntry::Admin1(data) => Err(format!("{} is an admin1 entry", data.code)),
+        }
+    }
+}
+
 // geonameid         : integer id of record in geonames database
 // name              : name of geographical point (utf8) varchar(200)
 // asciiname         : name of geographical point in plain ascii characters, varchar(200)
@@ -94,48 +378,257 @@ impl Location {
         format!("{}, {}", self.name, self.country_code)
     }
 
+    // Unlike `key`, distinguishes same-named places in one country, e.g. the
+    // many Springfields in the US
+    pub fn unique_key(self: &Location) -> String {
+        format!(
+            "{}|{}|{}|{}|{}",
+            self.name, self.country_code, self.admin1_code, self.admin2_code, self.id
+        )
+    }
+
     pub fn value(self: &Location) -> String {
         format!("{},{}", self.latitude, self.longitude)
     }
 
-    pub fn generate_elasticsearch_document(
+    // "Name, Admin2, Admin1, Country" for people, skipping unknown admins
+    pub fn display_name(self: &Location, admin1: Option<&str>, admin2: Option<&str>) -> String {
+        format_display_name(&self.name, admin1, admin2, &self.country_code)
+    }
+
+    // "Name, Admin1, Country" as stored in the display_name field
+    pub fn label(self: &Location, admin1: Option<&str>, country_name: Option<&str>) -> String {
+        format_label(&self.name, admin1, &self.country_code, country_name)
+    }
+
+    // Facet friendly bucket for the elevation, falling back to the digital
+    // elevation model when geonames has no measured elevation
+    pub fn elevation_description(self: &Location) -> &'static str {
+        let Some(elevation) = self.elevation.or(self.dem) else {
+            return "unknown";
+        };
+
+        ELEVATION_CATEGORIES
+            .iter()
+            .find(|(below, _)| elevation < *below)
+            .map(|(_, category)| *category)
+            .unwrap_or("high_mountain")
+    }
+
+    // Population on a log scale between 0 and 1, unknown population is 0
+    pub fn normalized_population(self: &Location) -> f64 {
+        match self.population {
+            Some(population) if population > 0 => {
+                ((population as f64).log10() / MAX_POPULATION_LOG10).min(1.0)
+            }
+            _ => 0.0,
+        }
+    }
+
+    // The comma separated alternate names without case-insensitive repeats,
+    // keeping the casing seen first, and without `name` or `ascii_name`.
+    // Past `max` one name per script is kept first, shortest first, then the
+    // shortest of the rest. Kept names stay in their original order.
+    pub fn deduped_alternate_names(self: &Location, max: Option<usize>) -> AlternateNames {
+        let mut seen: HashSet<String> = [&self.name, &self.ascii_name]
+            .into_iter()
+            .map(|name| name.trim().to_lowercase())
+            .collect();
+        let mut result = AlternateNames::default();
+
+        for name in self.alternate_names.split(',').map(str::trim) {
+            if name.is_empty() {
+                continue;
+            }
+            match seen.insert(name.to_lowercase()) {
+                true => result.names.push(name.to_string()),
+                false => result.duplicates += 1,
+            }
+        }
+
+        let Some(max) = max.filter(|&max| result.names.len() > max) else {
+            return result;
+        };
+        // Positions by preference: the shortest name of each script in the
+        // order scripts first appear, then everything else by length
+        let mut by_length: Vec<usize> = (0..result.names.len()).collect();
+        by_length.sort_by_key(|&i| result.names[i].chars().count());
+        let mut scripts = HashSet::new();
+        let (mut firsts, rest): (Vec<usize>, Vec<usize>) = by_length
+            .into_iter()
+            .partition(|&i| scripts.insert(script_of(&result.names[i])));
+        firsts.sort();
+
+        let mut keep: Vec<usize> = firsts.into_iter().chain(rest).take(max).collect();
+        keep.sort();
+        result.capped = result.names.len() - keep.len();
+        result.names = keep
+            .into_iter()
+            .map(|i| std::mem::take(&mut result.names[i]))
+            .collect();
+        result
+    }
+
+    // Completion suggester payload with every name the place is known by,
+    // plus "name, admin" and "name, country" forms, weighted by population
+    pub fn to_suggest_input(
         self: &Location,
-        admin1: &HashMap<String, String>,
-        admin2: &HashMap<String, String>,
+        alternate_names: &[String],
+        admin1_name: Option<&str>,
+        admin2_name: Option<&str>,
     ) -> Value {
-        let pop = self.population.filter(|&population| population >= 0);
+        let mut inputs: Vec<String> = vec![self.name.clone(), self.ascii_name.clone()];
+        inputs.extend(alternate_names.iter().cloned());
 
-        let admin_1_key = format!("{}.{}", self.country_code.to_uppercase(), self.admin1_code);
-        let admin_2_key = format!(
-            "{}.{}.{}",
-            self.country_code.to_uppercase(),
-            self.admin1_code,
-            self.admin2_code
-        );
+        for admin in [admin2_name, admin1_name].into_iter().flatten() {
+            inputs.push(format!("{}, {}", self.name, admin));
+        }
+        inputs.push(format!("{}, {}", self.name, self.country_code));
+
+        let mut seen = HashSet::new();
+        inputs.retain(|input| !input.is_empty() && seen.insert(input.clone()));
 
         json!({
+            "input": inputs,
+            "weight": (self.normalized_population() * SUGGEST_WEIGHT_SCALE).round() as i64,
+        })
+    }
+
+    // Country code and cc2 list uppercased and checked, with the ISO3 code
+    pub fn normalized_country<'a>(
+        self: &Location,
+        countries: &'a CountryCodes,
+    ) -> NormalizedCountry<'a> {
+        let country_code = countries.normalize(&self.country_code);
+        let mut dropped = match (&country_code, self.country_code.trim()) {
+            (None, code) if !code.is_empty() => 1,
+            _ => 0,
+        };
+
+        let mut cc2 = Vec::new();
+        for code in self
+            .cc2
+            .split(',')
+            .map(str::trim)
+            .filter(|code| !code.is_empty())
+        {
+            match countries.normalize(code) {
+                Some(code) if !cc2.contains(&code) => cc2.push(code),
+                Some(_) => {}
+                None => dropped += 1,
+            }
+        }
+
+        NormalizedCountry {
+            country_code_iso3: country_code
+                .as_deref()
+                .and_then(|code| countries.iso3(code)),
+            country_code,
+            cc2,
+            dropped,
+        }
+    }
+
+    // <CountryCode>.<Admin1Code>, the key of admin1CodesASCII.txt
+    pub fn admin1_key(self: &Location) -> String {
+        format!("{}.{}", self.country_code.to_uppercase(), self.admin1_code)
+    }
+
+    // <CountryCode>.<Admin1Code>.<Admin2Code>, the key of admin2Codes.txt
+    pub fn admin2_key(self: &Location) -> String {
+        format!("{}.{}", self.admin1_key(), self.admin2_code)
+    }
+
+    // Names of the admin1 and admin2 divisions the place is in, when known
+    pub fn admin_names<'a>(
+        self: &Location,
+        admin1: &'a AdminLookup,
+        admin2: &'a AdminLookup,
+    ) -> (Option<&'a String>, Option<&'a String>) {
+        (
+            admin1.get(&self.admin1_key()),
+            admin2.get(&self.admin2_key()),
+        )
+    }
+
+    pub fn generate_elasticsearch_document(
+        self: &Location,
+        admin1: &AdminLookup,
+        admin2: &AdminLookup,
+        countries: &CountryCodes,
+        options: &DocumentOptions,
+    ) -> Value {
+        let pop = self.population.filter(|&population| population >= 0);
+        let (admin1_name, admin2_name) = self.admin_names(admin1, admin2);
+        let country = self.normalized_country(countries);
+        let alternate_names = self
+            .deduped_alternate_names(options.max_alternate_names)
+            .names;
+
+        let mut document = json!({
             "name": self.name,
             "ascii_name": self.ascii_name,
+            "alternate_names": alternate_names,
             "location": [self.longitude, self.latitude],
             "elevation": self.elevation,
-            "country_code": self.country_code,
+            "country_code": country.country_code,
+            "country_code_iso3": country.country_code_iso3,
+            "cc2": country.cc2,
             "feature_code": self.feature_code,
             "feature_class": self.feature_class,
-            "admin1": admin1.get(&admin_1_key),
-            "admin2": admin2.get(&admin_2_key),
+            "admin1": admin1_name,
+            "admin2": admin2_name,
             "population": pop,
             "timezone": self.timezone,
             "modification_date": self.modification_date
-        })
+        });
+
+        if options.null_empty_timezone && self.timezone.trim().is_empty() {
+            document["timezone"] = Value::Null;
+        }
+
+        if options.elevation_category {
+            document["elevation_category"] = json!(self.elevation_description());
+        }
+
+        if options.display_name {
+            document["display_name"] = json!(self.label(
+                admin1_name.map(String::as_str),
+                country
+                    .country_code
+                    .as_deref()
+                    .and_then(|code| countries.name(code)),
+            ));
+        }
+
+        if let Some(field) = &options.seeded_at_field {
+            document[field] = json!(Utc::now().to_rfc3339());
+        }
+
+        if options.suggest {
+            document["suggest"] = self.to_suggest_input(
+                &alternate_names,
+                admin1_name.map(String::as_str),
+                admin2_name.map(String::as_str),
+            );
+        }
+
+        if let Some(fields) = document.as_object_mut() {
+            fields.retain(|field, _| !options.excluded_fields.contains(field));
+        }
+
+        document
     }
 
-    pub fn generate_mapping() -> Value {
-        json!({"properties": {
+    pub fn generate_mapping(options: &DocumentOptions) -> Value {
+        let mut mapping = json!({"properties": {
             "name": {"type": "text"},
             "ascii_name": {"type": "text"},
             "alternate_names": {"type": "text"},
             "location": {"type": "geo_point"},
             "country_code": {"type": "keyword"},
+            "country_code_iso3": {"type": "keyword"},
+            "cc2": {"type": "keyword"},
             "feature_code": {"type": "keyword"},
             "admin1": {"type": "text"},
             "admin2": {"type": "text"},
@@ -144,47 +637,762 @@ impl Location {
             "elevation": {"type": "integer"},
             "timezone": {"type": "keyword"},
             "modification_date": {"type": "date"},
-        }})
+        }});
+
+        if options.elevation_category {
+            mapping["properties"]["elevation_category"] = json!({"type": "keyword"});
+        }
+
+        if options.display_name {
+            mapping["properties"]["display_name"] = json!({
+                "type": "text",
+                "fields": {"keyword": {"type": "keyword"}},
+            });
+        }
+
+        if let Some(field) = &options.seeded_at_field {
+            mapping["properties"][field] = json!({"type": "date"});
+        }
+
+        if options.suggest {
+            mapping["properties"]["suggest"] = json!({"type": "completion"});
+        }
+
+        if let Some(properties) = mapping["properties"].as_object_mut() {
+            properties.retain(|field, _| !options.excluded_fields.contains(field));
+        }
+
+        mapping
+    }
+
+    // WKT puts the x coordinate first, so longitude comes before latitude
+    pub fn to_wkt_point(self: &Location) -> String {
+        format!("POINT({} {})", self.longitude, self.latitude)
+    }
+
+    pub fn to_wkt_multipoint(locations: &[Location]) -> String {
+        if locations.is_empty() {
+            return "MULTIPOINT EMPTY".to_string();
+        }
+
+        let points = locations
+            .iter()
+            .map(|location| format!("({} {})", location.longitude, location.latitude))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("MULTIPOINT({})", points)
+    }
+}
+
+// One `geonameid<TAB>POINT(lon lat)` line per location, which QGIS can load
+// as delimited text with a WKT geometry column
+pub fn write_wkt_file(locations: &[Location], mut writer: impl Write) -> io::Result<()> {
+    for location in locations {
+        writeln!(writer, "{}\t{}", location.id, location.to_wkt_point())?;
     }
+    writer.flush()
+}
+
+// `Location::display_name` for places known only by their fields, such as
+// search hits
+pub fn format_display_name(
+    name: &str,
+    admin1: Option<&str>,
+    admin2: Option<&str>,
+    country_code: &str,
+) -> String {
+    [Some(name), admin2, admin1, Some(country_code)]
+        .into_iter()
+        .flatten()
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Name, admin1 and country joined with commas. The admin1 is left out when
+// unknown or the same as the name, as with cities that are their own region.
+// The country is named when countryInfo.txt is loaded, otherwise the ISO code
+// stands in for it.
+pub fn format_label(
+    name: &str,
+    admin1: Option<&str>,
+    country_code: &str,
+    country_name: Option<&str>,
+) -> String {
+    let admin1 = admin1.filter(|admin1| !admin1.eq_ignore_ascii_case(name));
+    let country = country_name.unwrap_or(country_code);
+
+    [Some(name), admin1, Some(country)]
+        .into_iter()
+        .flatten()
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 pub fn read_file(file_name: &str) -> Result<Vec<Location>, Box<dyn Error>> {
-    let mut rdr = csv::Reader::from_path(file_name)?;
-    let mut locations = Vec::new();
+    let locations = LocationReader::new(File::open(file_name)?).collect::<Result<_, _>>()?;
+    Ok(locations)
+}
+
+#[derive(Debug)]
+pub enum GeonamesError {
+    Io(io::Error),
+    // A row that is not a valid record, numbered from 1
+    Row { row: u64, message: String },
+}
 
-    for result in rdr.deserialize() {
-        let record: Location = result?;
-        locations.push(record);
+impl fmt::Display for GeonamesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeonamesError::Io(err) => write!(f, "{}", err),
+            GeonamesError::Row { row, message } => write!(f, "Row {}: {}", row, message),
+        }
     }
+}
 
-    Ok(locations)
+impl Error for GeonamesError {}
+
+impl From<io::Error> for GeonamesError {
+    fn from(err: io::Error) -> Self {
+        GeonamesError::Io(err)
+    }
+}
+
+// Locations streamed out of a tab separated geonames dump, from a file, a zip
+// entry, a gzip stream or anything else that reads. A malformed row is
+// yielded as an error and reading carries on with the next one.
+pub struct LocationReader<R> {
+    records: csv::DeserializeRecordsIntoIter<R, Location>,
+    row: u64,
+}
+
+impl<R: Read> LocationReader<R> {
+    pub fn new(source: R) -> Self {
+        LocationReader {
+            records: dump_reader(source).into_deserialize(),
+            row: 0,
+        }
+    }
+
+    // Only the locations `filter` matches, errors are passed through
+    pub fn filtered(
+        self,
+        filter: LocationFilter,
+    ) -> impl Iterator<Item = Result<Location, GeonamesError>> {
+        self.filter(move |result| match result {
+            Ok(location) => filter.matches(location),
+            Err(_) => true,
+        })
+    }
+}
+
+impl<R: Read> Iterator for LocationReader<R> {
+    type Item = Result<Location, GeonamesError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.records.next()?;
+        self.row += 1;
+        Some(result.map_err(|err| {
+            if err.is_io_error() {
+                return GeonamesError::Io(err.into());
+            }
+            // Without the position csv repeats, the row number is added
+            let message = match err.kind() {
+                csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+                _ => err.to_string(),
+            };
+            GeonamesError::Row {
+                row: self.row,
+                message,
+            }
+        }))
+    }
+}
+
+// Longitude and latitude bounds, inclusive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub min_longitude: f64,
+    pub min_latitude: f64,
+    pub max_longitude: f64,
+    pub max_latitude: f64,
 }
 
-fn load_admin_file<T>(file_name: &str) -> Result<HashMap<String, String>, Box<dyn Error>>
+impl BoundingBox {
+    pub fn contains(&self, location: &Location) -> bool {
+        (self.min_longitude..=self.max_longitude).contains(&location.longitude)
+            && (self.min_latitude..=self.max_latitude).contains(&location.latitude)
+    }
+}
+
+// Which records to keep, every part matches everything when unset
+#[derive(Debug, Clone, Default)]
+pub struct LocationFilter {
+    // Uppercase country codes
+    pub countries: HashSet<String>,
+    // Records without a population are left out once this is set
+    pub min_population: Option<i64>,
+    pub feature_classes: Vec<char>,
+    pub bbox: Option<BoundingBox>,
+    pub modified_since: Option<NaiveDate>,
+}
+
+impl LocationFilter {
+    pub fn matches(&self, location: &Location) -> bool {
+        self.rejected_by(location).is_none()
+    }
+
+    // The first part of the filter leaving the record out, if any
+    pub fn rejected_by(&self, location: &Location) -> Option<&'static str> {
+        if !self.countries.is_empty()
+            && !self
+                .countries
+                .contains(&location.country_code.to_uppercase())
+        {
+            return Some("country");
+        }
+        if self
+            .min_population
+            .is_some_and(|min| location.population.is_none_or(|pop| pop < min))
+        {
+            return Some("min_population");
+        }
+        if !self.feature_classes.is_empty()
+            && location
+                .feature_class
+                .is_none_or(|class| !self.feature_classes.contains(&class))
+        {
+            return Some("feature_class");
+        }
+        if self.bbox.is_some_and(|bbox| !bbox.contains(location)) {
+            return Some("bbox");
+        }
+        if self
+            .modified_since
+            .is_some_and(|since| location.modification_date < since)
+        {
+            return Some("modified_since");
+        }
+        None
+    }
+}
+
+// Header row `write_admin_file` puts above the records, skipped when reading
+// so exported files load like the geonames ones
+const ADMIN_HEADER: [&str; 4] = ["code", "name", "ascii_name", "geonameid"];
+
+// Every record of an admin codes file keyed by its code, later rows replacing
+// earlier ones like they do in the lookup
+fn read_admin_records<T>(file_name: &str) -> Result<HashMap<String, T>, Box<dyn Error>>
 where
     T: DeserializeOwned + AdminData,
 {
-    let mut admin_data: HashMap<String, String> = HashMap::new();
+    let mut records = HashMap::new();
 
     let mut rdr = csv::ReaderBuilder::new()
         .delimiter(b'\t')
         .has_headers(false)
         .from_path(file_name)?;
 
-    for result in rdr.deserialize() {
-        let record: T = result?;
-        admin_data.insert(record.key(), record.value());
+    for (row, result) in rdr.records().enumerate() {
+        let record = result?;
+        if row == 0 && record.iter().eq(ADMIN_HEADER) {
+            continue;
+        }
+        let record: T = record.deserialize(None)?;
+        records.insert(record.key(), record);
     }
 
-    Ok(admin_data)
+    Ok(records)
+}
+
+fn load_admin_file<T>(file_name: &str) -> Result<AdminLookup, Box<dyn Error>>
+where
+    T: DeserializeOwned + AdminData,
+{
+    Ok(read_admin_records::<T>(file_name)?
+        .into_iter()
+        .map(|(code, record)| (code, record.value()))
+        .collect())
+}
+
+// Write admin records as a tab separated file with a header row, sorted by
+// code so the same input always gives the same file
+pub fn write_admin_file<T>(
+    path: &Path,
+    records: &HashMap<String, T>,
+) -> Result<usize, Box<dyn Error>>
+where
+    T: Serialize,
+{
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(true)
+        .from_path(path)?;
+
+    let mut codes: Vec<&String> = records.keys().collect();
+    codes.sort();
+    for code in &codes {
+        wtr.serialize(&records[*code])?;
+    }
+    wtr.flush()?;
+
+    Ok(codes.len())
+}
+
+// Which admin codes file `export-admin` reads
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminKind {
+    Admin1,
+    Admin2,
+}
+
+impl fmt::Display for AdminKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminKind::Admin1 => write!(f, "admin1"),
+            AdminKind::Admin2 => write!(f, "admin2"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ExportAdminReport {
+    pub kind: AdminKind,
+    pub output: PathBuf,
+    pub entries: usize,
+}
+
+impl CommandReport for ExportAdminReport {
+    fn print_human(&self) {
+        println!(
+            "Wrote {} {} entries to {}",
+            self.entries,
+            self.kind,
+            self.output.display()
+        );
+    }
+}
+
+// Load an admin codes file and write it back out with a header row
+pub fn export_admin(
+    input: &str,
+    output: &Path,
+    kind: AdminKind,
+) -> Result<ExportAdminReport, Box<dyn Error>> {
+    let entries = match kind {
+        AdminKind::Admin1 => write_admin_file(output, &read_admin_records::<Admin1Data>(input)?)?,
+        AdminKind::Admin2 => write_admin_file(output, &read_admin_records::<Admin2Data>(input)?)?,
+    };
+
+    Ok(ExportAdminReport {
+        kind,
+        output: output.to_path_buf(),
+        entries,
+    })
+}
+
+// Timezone ids from geonames timeZones.txt, tab separated with a header row
+// and the id in the second column
+pub fn load_timezones(file_name: &str) -> Result<HashSet<String>, Box<dyn Error>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .flexible(true)
+        .from_path(file_name)?;
+
+    let mut timezones = HashSet::new();
+    for result in rdr.records() {
+        if let Some(timezone) = result?.get(1) {
+            timezones.insert(timezone.trim().to_string());
+        }
+    }
+
+    Ok(timezones)
+}
+
+// Reader over a tab separated geonames dump. Fields are never quoted, a name
+// starting with a quote is just a name.
+pub fn dump_reader<R: Read>(source: R) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .quoting(false)
+        .from_reader(source)
 }
 
 pub fn load_admin_files(
     admin_1_file: &str,
     admin_2_file: &str,
-) -> Result<(HashMap<String, String>, HashMap<String, String>), Box<dyn Error>> {
+) -> Result<(AdminLookup, AdminLookup), Box<dyn Error>> {
     let admin_1_data = load_admin_file::<Admin1Data>(admin_1_file)?;
     let admin_2_data = load_admin_file::<Admin2Data>(admin_2_file)?;
 
     Ok((admin_1_data, admin_2_data))
 }
+
+// Alpha-2 to alpha-3 country codes, from countryInfo.txt or the embedded
+// ISO 3166 table
+#[derive(Debug, Clone)]
+pub struct CountryCodes {
+    alpha3: HashMap<String, String>,
+    // Country names by alpha-2 code, only known from countryInfo.txt
+    names: HashMap<String, String>,
+}
+
+impl Default for CountryCodes {
+    fn default() -> Self {
+        CountryCodes {
+            alpha3: ALPHA2_TO_ALPHA3
+                .iter()
+                .map(|(alpha2, alpha3)| (alpha2.to_string(), alpha3.to_string()))
+                .collect(),
+            names: HashMap::new(),
+        }
+    }
+}
+
+impl CountryCodes {
+    // geonames countryInfo.txt, tab separated with `#` comment lines, the
+    // ISO and ISO3 codes in the first two columns and the name in the fifth
+    pub fn from_country_info(file_name: &str) -> Result<Self, Box<dyn Error>> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(false)
+            .comment(Some(b'#'))
+            .flexible(true)
+            .from_path(file_name)?;
+
+        let mut alpha3 = HashMap::new();
+        let mut names = HashMap::new();
+        for result in rdr.records() {
+            let record = result?;
+            if let (Some(alpha2), Some(iso3)) = (record.get(0), record.get(1)) {
+                let alpha2 = alpha2.trim().to_uppercase();
+                if let Some(name) = record.get(4).map(str::trim).filter(|name| !name.is_empty()) {
+                    names.insert(alpha2.clone(), name.to_string());
+                }
+                alpha3.insert(alpha2, iso3.trim().to_uppercase());
+            }
+        }
+
+        Ok(CountryCodes { alpha3, names })
+    }
+
+    // Uppercased alpha-2 code, None when blank or not a known country
+    pub fn normalize(&self, code: &str) -> Option<String> {
+        let code = code.trim().to_uppercase();
+        self.alpha3.contains_key(&code).then_some(code)
+    }
+
+    pub fn iso3(&self, alpha2: &str) -> Option<&str> {
+        self.alpha3.get(alpha2).map(String::as_str)
+    }
+
+    pub fn name(&self, alpha2: &str) -> Option<&str> {
+        self.names.get(alpha2).map(String::as_str)
+    }
+}
+
+// A location's country codes after normalization
+#[derive(Debug, Clone)]
+pub struct NormalizedCountry<'a> {
+    pub country_code: Option<String>,
+    pub country_code_iso3: Option<&'a str>,
+    pub cc2: Vec<String>,
+    // Codes that were present but not valid, the main one included
+    pub dropped: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(name: &str, ascii_name: &str, alternate_names: &str) -> Location {
+        Location {
+            id: 2886242,
+            name: name.to_string(),
+            ascii_name: ascii_name.to_string(),
+            alternate_names: alternate_names.to_string(),
+            latitude: 50.93333,
+            longitude: 6.95,
+            feature_class: Some('P'),
+            feature_code: "PPLA2".to_string(),
+            country_code: "DE".to_string(),
+            cc2: String::new(),
+            admin1_code: "07".to_string(),
+            admin2_code: "053".to_string(),
+            admin3_code: String::new(),
+            admin4_code: None,
+            population: Some(963395),
+            elevation: None,
+            dem: Some(59),
+            timezone: "Europe/Berlin".to_string(),
+            modification_date: NaiveDate::from_ymd_opt(2022, 1, 1).unwrap(),
+        }
+    }
+
+    // Case variants, repeats of the name and stray whitespace among names in
+    // four scripts
+    const COLOGNE_NAMES: &str =
+        "Cologne,cologne,COLOGNE,Koeln,köln,Kolonia,Кёльн,Colonia,, Cologne ,KOLN,Кельн,ケルン";
+
+    #[test]
+    fn alternate_names_keep_the_first_casing_seen() {
+        let names = location("Köln", "Koln", COLOGNE_NAMES).deduped_alternate_names(None);
+        assert_eq!(
+            names.names,
+            [
+                "Cologne",
+                "Koeln",
+                "Kolonia",
+                "Кёльн",
+                "Colonia",
+                "Кельн",
+                "ケルン"
+            ]
+        );
+        // cologne, COLOGNE and " Cologne ", köln as the name, KOLN as the
+        // ascii name
+        assert_eq!(names.duplicates, 5);
+        assert_eq!(names.capped, 0);
+    }
+
+    #[test]
+    fn alternate_names_cap_keeps_a_name_per_script_first() {
+        let names = location("Köln", "Koln", COLOGNE_NAMES).deduped_alternate_names(Some(3));
+        // The shortest latin, cyrillic and kana names, in their original order
+        assert_eq!(names.names, ["Koeln", "Кёльн", "ケルン"]);
+        assert_eq!(names.capped, 4);
+
+        let names = location("Köln", "Koln", COLOGNE_NAMES).deduped_alternate_names(Some(5));
+        // Then the shortest of the rest, ties in their original order
+        assert_eq!(
+            names.names,
+            ["Cologne", "Koeln", "Кёльн", "Кельн", "ケルン"]
+        );
+        assert_eq!(names.capped, 2);
+    }
+
+    #[test]
+    fn alternate_names_under_the_cap_are_all_kept() {
+        let names = location("Köln", "Koln", "Cologne,Kolonia").deduped_alternate_names(Some(3));
+        assert_eq!(names.names, ["Cologne", "Kolonia"]);
+        assert_eq!((names.duplicates, names.capped), (0, 0));
+    }
+
+    // Berlin with every column, a record with only the required ones, Paris
+    // with a latitude that is not a number, New York and a truncated London
+    const FIXTURE: &str = include_str!("../tests/fixtures/locations.tsv");
+
+    #[test]
+    fn reader_reads_every_column() {
+        let berlin = LocationReader::new(FIXTURE.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!((berlin.id, berlin.name.as_str()), (2950159, "Berlin"));
+        assert_eq!(berlin.alternate_names, "Berlino,Berlín");
+        assert_eq!((berlin.latitude, berlin.longitude), (52.52437, 13.41053));
+        assert_eq!(berlin.feature_class, Some('P'));
+        assert_eq!(berlin.admin4_code.as_deref(), Some("11000000"));
+        assert_eq!(
+            (berlin.population, berlin.elevation, berlin.dem),
+            (Some(3426354), Some(74), Some(43))
+        );
+        assert_eq!(berlin.timezone, "Europe/Berlin");
+        assert_eq!(
+            berlin.modification_date,
+            NaiveDate::from_ymd_opt(2022, 1, 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn reader_leaves_empty_optional_columns_unset() {
+        let nowhere = LocationReader::new(FIXTURE.as_bytes())
+            .nth(1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(nowhere.feature_class, None);
+        assert_eq!(nowhere.admin4_code, None);
+        assert_eq!(
+            (nowhere.population, nowhere.elevation, nowhere.dem),
+            (None, None, None)
+        );
+        assert_eq!(
+            (
+                nowhere.alternate_names.as_str(),
+                nowhere.cc2.as_str(),
+                nowhere.timezone.as_str()
+            ),
+            ("", "", "")
+        );
+    }
+
+    #[test]
+    fn reader_numbers_corrupt_rows_and_carries_on() {
+        let results: Vec<_> = LocationReader::new(FIXTURE.as_bytes()).collect();
+        assert_eq!(results.len(), 5);
+
+        let ids: Vec<i64> = results
+            .iter()
+            .filter_map(|result| result.as_ref().ok().map(|location| location.id))
+            .collect();
+        assert_eq!(ids, [2950159, 6299999, 5128581]);
+
+        let rows: Vec<u64> = results
+            .iter()
+            .filter_map(|result| match result {
+                Err(GeonamesError::Row { row, .. }) => Some(*row),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(rows, [3, 5]);
+        let paris = results[2].as_ref().unwrap_err().to_string();
+        assert!(paris.starts_with("Row 3: "), "{}", paris);
+        assert!(!paris.contains("position"), "{}", paris);
+    }
+
+    #[test]
+    fn filtered_reader_passes_errors_through() {
+        let filter = LocationFilter {
+            countries: HashSet::from(["US".to_string()]),
+            ..LocationFilter::default()
+        };
+        let results: Vec<_> = LocationReader::new(FIXTURE.as_bytes())
+            .filtered(filter)
+            .collect();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[1].as_ref().unwrap().name, "New York City");
+        assert!(results[0].is_err() && results[2].is_err());
+    }
+
+    #[test]
+    fn country_codes_are_normalized() {
+        let countries = CountryCodes::default();
+        // country_code, cc2, normalized code, iso3, cc2 kept, dropped
+        type Case<'a> = (
+            &'a str,
+            &'a str,
+            Option<&'a str>,
+            Option<&'a str>,
+            &'a [&'a str],
+            usize,
+        );
+        let cases: [Case; 7] = [
+            ("DE", "", Some("DE"), Some("DEU"), &[], 0),
+            ("de", "at,ch", Some("DE"), Some("DEU"), &["AT", "CH"], 0),
+            (" xk ", "", Some("XK"), Some("XKX"), &[], 0),
+            ("", "", None, None, &[], 0),
+            ("   ", " , ,", None, None, &[], 0),
+            ("ZZ", "US,U1,us", None, None, &["US"], 2),
+            ("Germany", "", None, None, &[], 1),
+        ];
+
+        for (country_code, cc2, code, iso3, kept, dropped) in cases {
+            let mut place = location("Köln", "Koln", "");
+            place.country_code = country_code.to_string();
+            place.cc2 = cc2.to_string();
+
+            let country = place.normalized_country(&countries);
+            assert_eq!(country.country_code.as_deref(), code, "{:?}", country_code);
+            assert_eq!(country.country_code_iso3, iso3, "{:?}", country_code);
+            assert_eq!(country.cc2, kept, "{:?}", cc2);
+            assert_eq!(country.dropped, dropped, "{:?} {:?}", country_code, cc2);
+        }
+    }
+
+    #[test]
+    fn display_name_uses_country_info_names() {
+        let path =
+            std::env::temp_dir().join(format!("admin-{}-countryInfo.txt", std::process::id()));
+        std::fs::write(
+            &path,
+            "#ISO\tISO3\tISO-Numeric\tfips\tCountry\n\
+             de\tdeu\t276\tGM\tGermany\n\
+             XK\tXKX\t0\tKV\t\n",
+        )
+        .unwrap();
+        let countries = CountryCodes::from_country_info(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(countries.iso3("DE"), Some("DEU"));
+        assert_eq!(countries.name("DE"), Some("Germany"));
+        assert_eq!(countries.name("XK"), None);
+        assert_eq!(CountryCodes::default().name("DE"), None);
+
+        let options = DocumentOptions {
+            suggest: false,
+            display_name: true,
+            elevation_category: false,
+            null_empty_timezone: false,
+            seeded_at_field: None,
+            max_alternate_names: None,
+            excluded_fields: BTreeSet::new(),
+        };
+        let admin1 =
+            AdminLookup::from([("DE.07".to_string(), "North Rhine-Westphalia".to_string())]);
+        let place = location("Köln", "Koln", "");
+
+        let named = place.generate_elasticsearch_document(
+            &admin1,
+            &AdminLookup::new(),
+            &countries,
+            &options,
+        );
+        assert_eq!(
+            named["display_name"],
+            "Köln, North Rhine-Westphalia, Germany"
+        );
+        let coded = place.generate_elasticsearch_document(
+            &admin1,
+            &AdminLookup::new(),
+            &CountryCodes::default(),
+            &options,
+        );
+        assert_eq!(coded["display_name"], "Köln, North Rhine-Westphalia, DE");
+    }
+
+    #[test]
+    fn label_joins_name_admin1_and_country() {
+        assert_eq!(
+            format_label("Springfield", Some("Illinois"), "US", None),
+            "Springfield, Illinois, US"
+        );
+        assert_eq!(
+            format_label("Springfield", Some("Illinois"), "US", Some("United States")),
+            "Springfield, Illinois, United States"
+        );
+    }
+
+    #[test]
+    fn label_skips_missing_parts() {
+        assert_eq!(
+            format_label("Vatican City", None, "VA", None),
+            "Vatican City, VA"
+        );
+        assert_eq!(
+            format_label("Null Island", Some("Atlantic"), "", None),
+            "Null Island, Atlantic"
+        );
+        assert_eq!(
+            format_label("Null Island", Some(" "), " ", None),
+            "Null Island"
+        );
+    }
+
+    #[test]
+    fn label_skips_an_admin1_named_like_the_place() {
+        assert_eq!(
+            format_label("Berlin", Some("Berlin"), "DE", None),
+            "Berlin, DE"
+        );
+        assert_eq!(
+            format_label("Hamburg", Some("hamburg"), "DE", Some("Germany")),
+            "Hamburg, Germany"
+        );
+    }
+}
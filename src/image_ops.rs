@@ -0,0 +1,357 @@
+use clap::ValueEnum;
+use image::imageops::FilterType;
+use image::{imageops, DynamicImage, GenericImage, GenericImageView, Pixel, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+// Pixels kept between a watermark and the edges of the image
+const WATERMARK_MARGIN: u32 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+// Scale to `width` keeping the aspect ratio. Images already narrower than
+// `width` are returned as they are rather than upscaled.
+pub fn resize_to_width(img: &DynamicImage, width: u32, filter: FilterType) -> DynamicImage {
+    if width >= img.width() {
+        return img.clone();
+    }
+
+    let height = (img.height() as u64 * width as u64 / img.width() as u64).max(1) as u32;
+    img.resize_exact(width, height, filter)
+}
+
+// Cut a `width` x `height` region out of the middle of the image, clamped to
+// the image bounds
+pub fn crop_center(img: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let width = width.min(img.width());
+    let height = height.min(img.height());
+    let x = (img.width() - width) / 2;
+    let y = (img.height() - height) / 2;
+
+    img.crop_imm(x, y, width, height)
+}
+
+// The largest centered region of the image with a `width`:`height` aspect
+// ratio, such as 16:9 for thumbnails that all have the same shape
+pub fn crop_to_ratio(img: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let (img_width, img_height) = (img.width() as u64, img.height() as u64);
+    let (width, height) = (width.max(1) as u64, height.max(1) as u64);
+    let (crop_width, crop_height) = match img_width * height > img_height * width {
+        // Wider than the ratio, the sides go
+        true => (img_height * width / height, img_height),
+        false => (img_width, img_width * height / width),
+    };
+
+    crop_center(img, crop_width.max(1) as u32, crop_height.max(1) as u32)
+}
+
+// Fit the image inside a `width` x `height` canvas filled with
+// `background` and center it there. Transparent source pixels are blended
+// onto the background rather than cut off at a threshold.
+pub fn pad_to_canvas(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    background: Rgba<u8>,
+    filter: FilterType,
+) -> DynamicImage {
+    let fitted = img.resize(width, height, filter).to_rgba8();
+    let mut canvas = RgbaImage::from_pixel(width, height, background);
+    let x = (width - fitted.width().min(width)) / 2;
+    let y = (height - fitted.height().min(height)) / 2;
+    imageops::overlay(&mut canvas, &fitted, x as i64, y as i64);
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+// Blend `watermark` onto the image, with `opacity` from 0 (invisible) to 1
+// scaling the watermark's own alpha
+pub fn apply_watermark(
+    img: &mut DynamicImage,
+    watermark: &DynamicImage,
+    position: WatermarkPosition,
+    opacity: f32,
+) {
+    let opacity = opacity.clamp(0.0, 1.0);
+    let (x, y) = watermark_origin(img.dimensions(), watermark.dimensions(), position);
+
+    for (wx, wy, mut pixel) in watermark.pixels() {
+        let (px, py) = (x + wx, y + wy);
+        if px >= img.width() || py >= img.height() {
+            continue;
+        }
+
+        let alpha = &mut pixel.channels_mut()[3];
+        *alpha = (*alpha as f32 * opacity).round() as u8;
+
+        let mut target = img.get_pixel(px, py);
+        target.blend(&pixel);
+        img.put_pixel(px, py, target);
+    }
+}
+
+// Top left corner the watermark is drawn from
+fn watermark_origin(
+    (width, height): (u32, u32),
+    (mark_width, mark_height): (u32, u32),
+    position: WatermarkPosition,
+) -> (u32, u32) {
+    let right = width.saturating_sub(mark_width + WATERMARK_MARGIN);
+    let bottom = height.saturating_sub(mark_height + WATERMARK_MARGIN);
+    let left = WATERMARK_MARGIN.min(right);
+    let top = WATERMARK_MARGIN.min(bottom);
+
+    match position {
+        WatermarkPosition::TopLeft => (left, top),
+        WatermarkPosition::TopRight => (right, top),
+        WatermarkPosition::BottomLeft => (left, bottom),
+        WatermarkPosition::BottomRight => (right, bottom),
+        WatermarkPosition::Center => (
+            width.saturating_sub(mark_width) / 2,
+            height.saturating_sub(mark_height) / 2,
+        ),
+    }
+}
+
+// Side of the square windows SSIM is averaged over
+const SSIM_WINDOW: u32 = 8;
+
+// Peak signal to noise ratio over the RGB channels in dB, infinite when the
+// images are identical. Both images must have the same dimensions.
+pub fn psnr(a: &DynamicImage, b: &DynamicImage) -> f64 {
+    let (a, b) = (a.to_rgb8(), b.to_rgb8());
+    let squared_error: f64 = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(x, y)| (*x as f64 - *y as f64).powi(2))
+        .sum();
+    let mse = squared_error / a.as_raw().len().max(1) as f64;
+
+    match mse == 0.0 {
+        true => f64::INFINITY,
+        false => 10.0 * (255.0_f64.powi(2) / mse).log10(),
+    }
+}
+
+// Mean structural similarity of the luma channels, from 1 for identical
+// images down towards 0. Uses non-overlapping windows, so it is coarser than
+// reference implementations but fine for comparing encodes of one image.
+pub fn ssim(a: &DynamicImage, b: &DynamicImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (a, b) = (a.to_luma8(), b.to_luma8());
+    let (width, height) = a.dimensions();
+    let window = SSIM_WINDOW.min(width).min(height).max(1);
+    let mut total = 0.0;
+    let mut windows = 0;
+
+    for y in (0..=height.saturating_sub(window)).step_by(window as usize) {
+        for x in (0..=width.saturating_sub(window)).step_by(window as usize) {
+            let pixels: Vec<(f64, f64)> = (y..y + window)
+                .flat_map(|py| (x..x + window).map(move |px| (px, py)))
+                .map(|(px, py)| (a.get_pixel(px, py)[0] as f64, b.get_pixel(px, py)[0] as f64))
+                .collect();
+            let n = pixels.len() as f64;
+            let mean_a = pixels.iter().map(|(pa, _)| pa).sum::<f64>() / n;
+            let mean_b = pixels.iter().map(|(_, pb)| pb).sum::<f64>() / n;
+            let (mut var_a, mut var_b, mut covariance) = (0.0, 0.0, 0.0);
+            for (pa, pb) in &pixels {
+                var_a += (pa - mean_a).powi(2);
+                var_b += (pb - mean_b).powi(2);
+                covariance += (pa - mean_a) * (pb - mean_b);
+            }
+            let (var_a, var_b, covariance) = (var_a / n, var_b / n, covariance / n);
+
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a.powi(2) + mean_b.powi(2) + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+
+    match windows {
+        0 => 1.0,
+        _ => total / windows as f64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each pixel encodes where it is, red for x and green for y
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |x, y| {
+            Rgba([x as u8, y as u8, 0, 255])
+        }))
+    }
+
+    fn solid(width: u32, height: u32, color: [u8; 4]) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, Rgba(color)))
+    }
+
+    #[test]
+    fn resize_to_width_keeps_the_aspect_ratio() {
+        let resized = resize_to_width(&gradient(200, 100), 50, FilterType::Triangle);
+        assert_eq!(resized.dimensions(), (50, 25));
+    }
+
+    #[test]
+    fn resize_to_width_does_not_upscale() {
+        let img = gradient(40, 30);
+        let resized = resize_to_width(&img, 100, FilterType::Triangle);
+        assert_eq!(resized.dimensions(), (40, 30));
+        assert_eq!(resized.as_bytes(), img.as_bytes());
+    }
+
+    #[test]
+    fn resize_to_width_keeps_at_least_one_row() {
+        let resized = resize_to_width(&gradient(200, 2), 10, FilterType::Nearest);
+        assert_eq!(resized.dimensions(), (10, 1));
+    }
+
+    #[test]
+    fn crop_center_cuts_the_middle() {
+        let cropped = crop_center(&gradient(100, 60), 20, 10);
+        assert_eq!(cropped.dimensions(), (20, 10));
+        assert_eq!(cropped.get_pixel(0, 0), Rgba([40, 25, 0, 255]));
+    }
+
+    #[test]
+    fn crop_center_clamps_to_the_image() {
+        let cropped = crop_center(&gradient(30, 20), 100, 10);
+        assert_eq!(cropped.dimensions(), (30, 10));
+        assert_eq!(cropped.get_pixel(0, 0), Rgba([0, 5, 0, 255]));
+    }
+
+    #[test]
+    fn crop_to_ratio_trims_the_longer_side() {
+        assert_eq!(
+            crop_to_ratio(&gradient(200, 90), 16, 9).dimensions(),
+            (160, 90)
+        );
+        assert_eq!(
+            crop_to_ratio(&gradient(90, 200), 1, 1).dimensions(),
+            (90, 90)
+        );
+        assert_eq!(
+            crop_to_ratio(&gradient(64, 36), 16, 9).dimensions(),
+            (64, 36)
+        );
+    }
+
+    #[test]
+    fn pad_to_canvas_centers_on_the_background() {
+        let padded = pad_to_canvas(
+            &solid(20, 10, [255, 0, 0, 255]),
+            40,
+            40,
+            Rgba([255, 255, 255, 255]),
+            FilterType::Nearest,
+        );
+        assert_eq!(padded.dimensions(), (40, 40));
+        assert_eq!(padded.get_pixel(20, 20), Rgba([255, 0, 0, 255]));
+        assert_eq!(padded.get_pixel(20, 5), Rgba([255, 255, 255, 255]));
+    }
+
+    fn watermarked(opacity: f32) -> Rgba<u8> {
+        let mut img = solid(64, 64, [0, 0, 0, 255]);
+        let mark = solid(8, 8, [255, 255, 255, 255]);
+        apply_watermark(&mut img, &mark, WatermarkPosition::TopLeft, opacity);
+        img.get_pixel(WATERMARK_MARGIN, WATERMARK_MARGIN)
+    }
+
+    #[test]
+    fn watermark_opacity_blends_between_image_and_mark() {
+        assert_eq!(watermarked(1.0), Rgba([255, 255, 255, 255]));
+        assert_eq!(watermarked(0.0), Rgba([0, 0, 0, 255]));
+        let half = watermarked(0.5)[0];
+        assert!((120..=135).contains(&half), "half opacity gave {}", half);
+    }
+
+    #[test]
+    fn watermark_opacity_is_clamped() {
+        assert_eq!(watermarked(2.5), watermarked(1.0));
+        assert_eq!(watermarked(-1.0), watermarked(0.0));
+    }
+
+    #[test]
+    fn watermark_positions_keep_the_margin() {
+        let origin = |position| watermark_origin((100, 80), (10, 10), position);
+        assert_eq!(origin(WatermarkPosition::TopLeft), (16, 16));
+        assert_eq!(origin(WatermarkPosition::TopRight), (74, 16));
+        assert_eq!(origin(WatermarkPosition::BottomLeft), (16, 54));
+        assert_eq!(origin(WatermarkPosition::BottomRight), (74, 54));
+        assert_eq!(origin(WatermarkPosition::Center), (45, 35));
+    }
+
+    #[test]
+    fn watermark_larger_than_the_image_is_clipped() {
+        let mut img = solid(10, 10, [0, 0, 0, 255]);
+        let mark = solid(40, 40, [255, 255, 255, 255]);
+        apply_watermark(&mut img, &mark, WatermarkPosition::BottomRight, 1.0);
+        assert_eq!(img.dimensions(), (10, 10));
+        assert_eq!(img.get_pixel(9, 9), Rgba([255, 255, 255, 255]));
+    }
+
+    // Stripes and a gradient, enough structure for SSIM to notice changes
+    fn pattern() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 64, |x, y| {
+            let stripe = if (x / 4 + y / 8) % 2 == 0 { 200 } else { 40 };
+            Rgba([stripe, (x * 4) as u8, (y * 4) as u8, 255])
+        }))
+    }
+
+    fn jpeg_roundtrip(img: &DynamicImage, quality: u8) -> DynamicImage {
+        let mut encoded = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut encoded, quality)
+            .encode_image(&img.to_rgb8())
+            .unwrap();
+        image::load_from_memory(&encoded).unwrap()
+    }
+
+    #[test]
+    fn identical_images_score_one() {
+        let img = pattern();
+        assert_eq!(ssim(&img, &img.clone()), 1.0);
+        assert_eq!(psnr(&img, &img.clone()), f64::INFINITY);
+    }
+
+    #[test]
+    fn degraded_images_score_lower() {
+        let img = pattern();
+        let light = jpeg_roundtrip(&img, 90);
+        let heavy = jpeg_roundtrip(&img, 5);
+        let blurred = img.blur(3.0);
+        let (light_ssim, heavy_ssim, blurred_ssim) =
+            (ssim(&img, &light), ssim(&img, &heavy), ssim(&img, &blurred));
+
+        assert!(
+            (0.99..1.0).contains(&light_ssim),
+            "quality 90: {}",
+            light_ssim
+        );
+        assert!(
+            (0.6..0.9).contains(&heavy_ssim),
+            "quality 5: {}",
+            heavy_ssim
+        );
+        assert!(blurred_ssim < 0.4, "blurred: {}", blurred_ssim);
+        assert!(psnr(&img, &light) > 38.0);
+        assert!((15.0..25.0).contains(&psnr(&img, &heavy)));
+    }
+
+    #[test]
+    fn ssim_is_symmetric() {
+        let (img, heavy) = (pattern(), jpeg_roundtrip(&pattern(), 5));
+        assert!((ssim(&img, &heavy) - ssim(&heavy, &img)).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,1423 @@
+use clap::ValueEnum;
+use image::{
+    codecs::jpeg::JpegEncoder, imageops::FilterType::Lanczos3, io::Reader as ImageReader,
+    DynamicImage, GenericImageView, Rgba,
+};
+use ravif::{Img, RGBA8};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::cancel::CancelToken;
+use crate::image_ops::{
+    apply_watermark, crop_to_ratio, pad_to_canvas, psnr, ssim, WatermarkPosition,
+};
+use crate::output::CommandReport;
+use crate::progress::{Progress, ProgressTask};
+
+// How large a variant may be
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bounds {
+    // The height follows from the source's aspect, never upscaled
+    Width(u32),
+    // Fit inside the box, keeping the aspect
+    Box {
+        width: u32,
+        height: u32,
+    },
+    // Limits on the longer and shorter edge, whichever needs the smaller
+    // scale wins. Never upscaled.
+    Edges {
+        long: Option<u32>,
+        short: Option<u32>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Size {
+    pub bounds: Bounds,
+    // Added to the file name, the resolved dimensions as WIDTHxHEIGHT when
+    // there is none
+    pub suffix: Option<String>,
+}
+
+// Scale `width` x `height` by `ratio`, as the image crate does
+fn scaled(width: u32, height: u32, ratio: f64) -> (u32, u32) {
+    (
+        ((width as f64 * ratio).round() as u32).max(1),
+        ((height as f64 * ratio).round() as u32).max(1),
+    )
+}
+
+impl Size {
+    pub fn width(width: u32) -> Self {
+        Size {
+            bounds: Bounds::Width(width),
+            suffix: Some(format!("{}px", width)),
+        }
+    }
+
+    // Output dimensions for a source of `width` x `height`
+    pub fn dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        match self.bounds {
+            Bounds::Width(max) if max >= width => (width, height),
+            Bounds::Width(max) => (
+                max,
+                (height as u64 * max as u64 / width as u64).max(1) as u32,
+            ),
+            Bounds::Box {
+                width: max_width,
+                height: max_height,
+            } => {
+                let ratio =
+                    (max_width as f64 / width as f64).min(max_height as f64 / height as f64);
+                scaled(width, height, ratio)
+            }
+            Bounds::Edges { long, short } => {
+                let (long_edge, short_edge) = (width.max(height), width.min(height));
+                let ratio = [(long, long_edge), (short, short_edge)]
+                    .into_iter()
+                    .filter_map(|(limit, edge)| limit.map(|limit| limit as f64 / edge as f64))
+                    .fold(1.0, f64::min);
+                match ratio < 1.0 {
+                    true => scaled(width, height, ratio),
+                    false => (width, height),
+                }
+            }
+        }
+    }
+
+    // Suffix of the variant written at `width` x `height`
+    pub fn suffix_for(&self, width: u32, height: u32) -> String {
+        self.suffix
+            .clone()
+            .unwrap_or_else(|| format!("{}x{}", width, height))
+    }
+
+    fn edges(&self) -> Option<(Option<u32>, Option<u32>)> {
+        match self.bounds {
+            Bounds::Edges { long, short } => Some((long, short)),
+            _ => None,
+        }
+    }
+}
+
+// `1200` for a width, `1200x800` for a bounding box, or edge limits such as
+// `long:2048`, `short:1400` or both as `long:2048,short:1400`
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid size {}, expected WIDTH, WIDTHxHEIGHT, long:PX or short:PX such as 1200, 1200x800 or long:2048,short:1400",
+                s
+            )
+        };
+        let parse = |part: &str| match part.trim().parse::<u32>() {
+            Ok(0) | Err(_) => Err(invalid()),
+            Ok(value) => Ok(value),
+        };
+
+        if s.contains(':') {
+            let (mut long, mut short) = (None, None);
+            for part in s.split(',') {
+                let (edge, value) = part.split_once(':').ok_or_else(invalid)?;
+                let limit = match edge.trim() {
+                    "long" => &mut long,
+                    "short" => &mut short,
+                    _ => return Err(invalid()),
+                };
+                if limit.replace(parse(value)?).is_some() {
+                    return Err(invalid());
+                }
+            }
+            return Ok(Size {
+                bounds: Bounds::Edges { long, short },
+                suffix: None,
+            });
+        }
+
+        match s.split_once('x') {
+            None => Ok(Size::width(parse(s)?)),
+            Some((width, height)) => {
+                let (width, height) = (parse(width)?, parse(height)?);
+                Ok(Size {
+                    bounds: Bounds::Box { width, height },
+                    suffix: Some(format!("{}x{}", width, height)),
+                })
+            }
+        }
+    }
+}
+
+// Suffixes end up in file names, so only letters, digits, `-`, `_` and `.`
+// are allowed, and no leading dot
+fn is_safe_suffix(suffix: &str) -> bool {
+    !suffix.is_empty()
+        && !suffix.starts_with('.')
+        && suffix
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+// A size from --size, naming the file after the suffix as given: either
+// `WIDTH[xHEIGHT]:SUFFIX` such as `800:small` or `1920x1080:hd`, or the older
+// `WIDTHxSUFFIX` and `WIDTHxHEIGHTxSUFFIX`. In the older form a suffix of only
+// digits would read as a height, so it is refused.
+pub fn parse_size_spec(s: &str) -> Result<Size, String> {
+    let invalid = || {
+        format!(
+            "Invalid size {}, expected WIDTH[xHEIGHT]:SUFFIX such as 800:small or 1920x1080:hd, with a suffix of letters, digits, -, _ and .",
+            s
+        )
+    };
+    let parse = |part: &str| match part.parse::<u32>() {
+        Ok(0) | Err(_) => Err(invalid()),
+        Ok(value) => Ok(value),
+    };
+
+    let (bounds, suffix) = match s.split_once(':') {
+        Some((dimensions, suffix)) => {
+            let bounds = match dimensions.split_once('x') {
+                Some((width, height)) => Bounds::Box {
+                    width: parse(width)?,
+                    height: parse(height)?,
+                },
+                None => Bounds::Width(parse(dimensions)?),
+            };
+            (bounds, suffix)
+        }
+        None => {
+            let (width, rest) = s.split_once('x').ok_or_else(invalid)?;
+            let width = parse(width)?;
+            match rest.split_once('x') {
+                Some((height, suffix)) if height.parse::<u32>().is_ok() => (
+                    Bounds::Box {
+                        width,
+                        height: parse(height)?,
+                    },
+                    suffix,
+                ),
+                _ if rest.chars().all(|c| c.is_ascii_digit()) => return Err(invalid()),
+                _ => (Bounds::Width(width), rest),
+            }
+        }
+    };
+    if !is_safe_suffix(suffix) {
+        return Err(invalid());
+    }
+
+    Ok(Size {
+        bounds,
+        suffix: Some(suffix.to_string()),
+    })
+}
+
+// Back to the form it was parsed from, as stored in manifests. Sizes from
+// --size keep their suffix in the `parse_size_spec` form.
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let default_suffix = match self.bounds {
+            Bounds::Width(width) => Some(format!("{}px", width)),
+            Bounds::Box { width, height } => Some(format!("{}x{}", width, height)),
+            Bounds::Edges { .. } => None,
+        };
+        match (&self.bounds, &self.suffix) {
+            (Bounds::Width(width), Some(suffix)) if Some(suffix) != default_suffix.as_ref() => {
+                return write!(f, "{}:{}", width, suffix)
+            }
+            (Bounds::Box { width, height }, Some(suffix))
+                if Some(suffix) != default_suffix.as_ref() =>
+            {
+                return write!(f, "{}x{}:{}", width, height, suffix)
+            }
+            _ => {}
+        }
+        match self.bounds {
+            Bounds::Width(width) => write!(f, "{}", width),
+            Bounds::Box { width, height } => write!(f, "{}x{}", width, height),
+            Bounds::Edges { long, short } => {
+                let parts: Vec<String> = [("long", long), ("short", short)]
+                    .into_iter()
+                    .filter_map(|(edge, limit)| limit.map(|limit| format!("{}:{}", edge, limit)))
+                    .collect();
+                write!(f, "{}", parts.join(","))
+            }
+        }
+    }
+}
+
+// --sizes is split on commas, so `long:2048,short:1400` arrives as two
+// entries. A long limit directly followed by a short one, or the other way
+// round, is put back together into one size.
+pub fn combine_edge_sizes(sizes: Vec<Size>) -> Vec<Size> {
+    let mut combined: Vec<Size> = Vec::with_capacity(sizes.len());
+    for size in sizes {
+        let previous = combined
+            .last_mut()
+            .and_then(|last| last.edges().map(|edges| (last, edges)));
+        match (previous, size.edges()) {
+            (Some((last, (Some(long), None))), Some((None, Some(short))))
+            | (Some((last, (None, Some(short)))), Some((Some(long), None))) => {
+                last.bounds = Bounds::Edges {
+                    long: Some(long),
+                    short: Some(short),
+                };
+            }
+            _ => combined.push(size),
+        }
+    }
+    combined
+}
+
+// Canvas shape for --pad-to-aspect and the shape --crop cuts to, `4:3` or
+// `16x9`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Aspect {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Aspect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid aspect ratio {}, expected W:H or WxH such as 4:3",
+                s
+            )
+        };
+        let (width, height) = s
+            .split_once(':')
+            .or_else(|| s.split_once('x'))
+            .ok_or_else(invalid)?;
+        match (width.trim().parse::<u32>(), height.trim().parse::<u32>()) {
+            (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok(Aspect { width, height }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Aspect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.width, self.height)
+    }
+}
+
+// What padding is filled with. A transparent background is written as PNG,
+// since JPEG has no alpha channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Background {
+    Transparent,
+    Color([u8; 3]),
+}
+
+// `transparent`, `white`, `black` or a hex color such as `#f0f0f0`
+impl FromStr for Background {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "transparent" => return Ok(Background::Transparent),
+            "white" => return Ok(Background::Color([255, 255, 255])),
+            "black" => return Ok(Background::Color([0, 0, 0])),
+            _ => {}
+        }
+
+        let hex = s.trim_start_matches('#');
+        let channel = |i: usize| {
+            hex.get(i..i + 2)
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+        };
+        match (hex.len(), channel(0), channel(2), channel(4)) {
+            (6, Some(r), Some(g), Some(b)) => Ok(Background::Color([r, g, b])),
+            _ => Err(format!(
+                "Invalid background {}, expected transparent, white, black or a hex color",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Background {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Background::Transparent => write!(f, "transparent"),
+            Background::Color([r, g, b]) => write!(f, "#{:02x}{:02x}{:02x}", r, g, b),
+        }
+    }
+}
+
+// File formats variants can be written in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[value(alias = "jpg")]
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+}
+
+impl Format {
+    // The format an output path asks for by its extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "jpg" | "jpeg" => Some(Format::Jpeg),
+            "png" => Some(Format::Png),
+            "webp" => Some(Format::Webp),
+            "avif" => Some(Format::Avif),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpg",
+            Format::Png => "png",
+            Format::Webp => "webp",
+            Format::Avif => "avif",
+        }
+    }
+}
+
+pub const DEFAULT_JPEG_QUALITY: u8 = 85;
+
+fn default_jpeg_quality() -> u8 {
+    DEFAULT_JPEG_QUALITY
+}
+
+// AVIF encoding is slow, a higher speed trades size for time
+pub const DEFAULT_AVIF_QUALITY: u8 = 80;
+pub const DEFAULT_AVIF_SPEED: u8 = 6;
+
+pub const DEFAULT_WATERMARK_SCALE: f64 = 0.15;
+
+// An image composited onto every variant after resizing
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Watermark {
+    pub path: PathBuf,
+    pub position: WatermarkPosition,
+    // Width of the watermark as a fraction of the variant's width
+    pub scale: f64,
+}
+
+pub fn parse_watermark_scale(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(scale) if scale > 0.0 && scale <= 1.0 => Ok(scale),
+        _ => Err(format!("{} is not a fraction above 0 and at most 1", s)),
+    }
+}
+
+// Scale `mark` to the variant and draw it over `img` at the position
+fn draw_watermark(img: &mut DynamicImage, mark: &DynamicImage, watermark: &Watermark) {
+    let width = ((img.width() as f64 * watermark.scale).round() as u32).max(1);
+    let height =
+        ((mark.height() as f64 * width as f64 / mark.width() as f64).round() as u32).max(1);
+    let mark = mark.resize_exact(width, height, Lanczos3);
+    apply_watermark(img, &mark, watermark.position, 1.0);
+}
+
+// How the variants are encoded
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Encoding {
+    // Every size is written once per format. Without any JPEG is written,
+    // or PNG for transparent padding
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub formats: Vec<Format>,
+    // From 1 to 100
+    #[serde(default = "default_jpeg_quality")]
+    pub jpeg_quality: u8,
+    pub avif_quality: u8,
+    pub avif_speed: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watermark: Option<Watermark>,
+    // The source is center cropped to this aspect before any resizing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crop: Option<Aspect>,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding {
+            formats: Vec::new(),
+            jpeg_quality: DEFAULT_JPEG_QUALITY,
+            avif_quality: DEFAULT_AVIF_QUALITY,
+            avif_speed: DEFAULT_AVIF_SPEED,
+            watermark: None,
+            crop: None,
+        }
+    }
+}
+
+impl Encoding {
+    pub fn formats_for(&self, padding: Option<Padding>) -> Vec<Format> {
+        match (
+            self.formats.is_empty(),
+            padding.map(|padding| padding.background),
+        ) {
+            (false, _) => self.formats.clone(),
+            (true, Some(Background::Transparent)) => vec![Format::Png],
+            (true, _) => vec![Format::Jpeg],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Padding {
+    pub aspect: Aspect,
+    pub background: Background,
+}
+
+impl Padding {
+    // Largest canvas of the target aspect for a size, its width unless a
+    // bounding box height makes that too tall
+    fn canvas(&self, size: &Size) -> (u32, u32) {
+        let (aspect_width, aspect_height) = (self.aspect.width as u64, self.aspect.height as u64);
+        let (width, max_height) = match size.bounds {
+            Bounds::Width(width) => (width, None),
+            Bounds::Box { width, height } => (width, Some(height)),
+            // The edges of the canvas are the long and short ones
+            Bounds::Edges { long, short } => {
+                let (long_edge, short_edge) = (
+                    aspect_width.max(aspect_height) as f64,
+                    aspect_width.min(aspect_height) as f64,
+                );
+                let ratio = [(long, long_edge), (short, short_edge)]
+                    .into_iter()
+                    .filter_map(|(limit, edge)| limit.map(|limit| limit as f64 / edge))
+                    .fold(f64::INFINITY, f64::min);
+                return scaled(self.aspect.width, self.aspect.height, ratio);
+            }
+        };
+        let height = (width as u64 * aspect_height / aspect_width).max(1) as u32;
+        match max_height {
+            Some(max_height) if height > max_height => (
+                (max_height as u64 * aspect_width / aspect_height).max(1) as u32,
+                max_height,
+            ),
+            _ => (width, height),
+        }
+    }
+}
+
+pub fn default_sizes() -> Vec<Size> {
+    vec![Size::width(1200), Size::width(600), Size::width(2400)]
+}
+
+// One generated file
+#[derive(Serialize)]
+pub struct ImageVariant {
+    pub path: PathBuf,
+    pub suffix: String,
+    pub format: Format,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: Option<u64>,
+    // Of the file as written, read back from disk
+    pub sha256: Option<String>,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ImagesReport {
+    pub source: PathBuf,
+    pub variants: Vec<ImageVariant>,
+}
+
+impl CommandReport for ImagesReport {
+    fn print_human(&self) {
+        for variant in &self.variants {
+            match &variant.error {
+                None => println!(
+                    "{} {}x{} in {}ms",
+                    variant.path.display(),
+                    variant.width,
+                    variant.height,
+                    variant.duration_ms
+                ),
+                Some(err) => println!("Failed {}: {}", variant.path.display(), err),
+            }
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self.variants.iter().any(|variant| variant.error.is_some()) {
+            true => 1,
+            false => 0,
+        }
+    }
+}
+
+// Write `sha256sum` style lines for every variant written, with paths
+// relative to the checksum file where they are below it so that
+// `sha256sum -c` works from its directory
+pub fn write_checksums<'a>(
+    path: &Path,
+    variants: impl IntoIterator<Item = &'a ImageVariant>,
+) -> io::Result<()> {
+    let base = path.parent().unwrap_or(Path::new(""));
+    let mut file = BufWriter::new(File::create(path)?);
+    for variant in variants {
+        if let Some(sha256) = &variant.sha256 {
+            let name = match base.as_os_str().is_empty() {
+                true => variant.path.as_path(),
+                false => variant.path.strip_prefix(base).unwrap_or(&variant.path),
+            };
+            writeln!(file, "{}  {}", sha256, name.display())?;
+        }
+    }
+    file.flush()
+}
+
+// A size worked out before any resizing: what it is resized to, and where
+// each format of it is written
+struct PlannedSize {
+    width: u32,
+    height: u32,
+    suffix: String,
+    outputs: Vec<(Format, PathBuf)>,
+}
+
+// Next to the source, or in the output directory, named after what is
+// written, not what was asked for. An output that is not a directory is the
+// path to write to.
+fn plan_size(
+    source: &Path,
+    dimensions: (u32, u32),
+    output: &Option<PathBuf>,
+    size: &Size,
+    padding: Option<Padding>,
+    encoding: &Encoding,
+) -> PlannedSize {
+    let (width, height) = match padding {
+        Some(padding) => padding.canvas(size),
+        None => size.dimensions(dimensions.0, dimensions.1),
+    };
+    let suffix = size.suffix_for(width, height);
+    let outputs = encoding
+        .formats_for(padding)
+        .into_iter()
+        .map(|format| {
+            let dir = match output {
+                Some(output) if output.is_dir() => output.as_path(),
+                // An explicit path is kept as is unless formats were chosen
+                Some(output) if encoding.formats.is_empty() => return (format, output.clone()),
+                Some(output) => return (format, output.with_extension(format.extension())),
+                None => source.parent().unwrap_or(Path::new("")),
+            };
+            let file_name = source.file_stem().unwrap();
+            // Not with_extension, a suffix may contain dots
+            let path = dir.join(format!(
+                "{}-{}.{}",
+                file_name
+                    .to_str()
+                    .expect("Could not get file_name of image"),
+                suffix,
+                format.extension()
+            ));
+            (format, path)
+        })
+        .collect();
+
+    PlannedSize {
+        width,
+        height,
+        suffix,
+        outputs,
+    }
+}
+
+fn encode(
+    img: &DynamicImage,
+    path: &Path,
+    format: Format,
+    encoding: &Encoding,
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        // JPEG has no alpha, the padding is opaque by now
+        Format::Jpeg => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            JpegEncoder::new_with_quality(&mut writer, encoding.jpeg_quality)
+                .encode_image(&DynamicImage::ImageRgb8(img.to_rgb8()))?;
+            writer.flush()?;
+        }
+        Format::Png => img.save_with_format(path, image::ImageFormat::Png)?,
+        // The WebP encoder only takes 8 bit RGB(A)
+        Format::Webp => DynamicImage::ImageRgba8(img.to_rgba8())
+            .save_with_format(path, image::ImageFormat::WebP)?,
+        Format::Avif => {
+            let rgba = img.to_rgba8();
+            let pixels: Vec<RGBA8> = rgba
+                .pixels()
+                .map(|&Rgba([r, g, b, a])| RGBA8::new(r, g, b, a))
+                .collect();
+            let encoded = ravif::Encoder::new()
+                .with_quality(encoding.avif_quality as f32)
+                .with_speed(encoding.avif_speed)
+                .encode_rgba(Img::new(
+                    &pixels[..],
+                    rgba.width() as usize,
+                    rgba.height() as usize,
+                ))?;
+            fs::write(path, encoded.avif_file)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_size(
+    img: &DynamicImage,
+    plan: PlannedSize,
+    padding: Option<Padding>,
+    encoding: &Encoding,
+    mark: Option<&DynamicImage>,
+    task: &dyn ProgressTask,
+) -> Vec<ImageVariant> {
+    let mut now = Instant::now();
+    let mut new_img = match padding {
+        Some(padding) => {
+            let background = match padding.background {
+                Background::Transparent => Rgba([0, 0, 0, 0]),
+                Background::Color([r, g, b]) => Rgba([r, g, b, 255]),
+            };
+            pad_to_canvas(img, plan.width, plan.height, background, Lanczos3)
+        }
+        None if (plan.width, plan.height) == img.dimensions() => img.clone(),
+        None => img.resize_exact(plan.width, plan.height, Lanczos3),
+    };
+    if let (Some(watermark), Some(mark)) = (&encoding.watermark, mark) {
+        draw_watermark(&mut new_img, mark, watermark);
+    }
+
+    let mut variants = Vec::with_capacity(plan.outputs.len());
+    for (format, path) in plan.outputs {
+        let result = encode(&new_img, &path, format, encoding);
+        // The resize counts towards the first format written
+        let duration_ms = now.elapsed().as_millis() as u64;
+        now = Instant::now();
+        match &result {
+            Ok(_) => {
+                task.log(&format!("Done processing image in {}ms", duration_ms));
+            }
+            Err(err) => {
+                task.log(&format!(
+                    "Error saving image to {}: {}",
+                    &path.display(),
+                    err
+                ));
+            }
+        }
+        task.update(1);
+
+        let written = result.as_ref().ok().and_then(|_| fs::read(&path).ok());
+        variants.push(ImageVariant {
+            bytes: written.as_ref().map(|contents| contents.len() as u64),
+            sha256: written.map(|contents| format!("{:x}", Sha256::digest(&contents))),
+            path,
+            suffix: plan.suffix.clone(),
+            format,
+            width: new_img.width(),
+            height: new_img.height(),
+            duration_ms,
+            error: result.err().map(|err| err.to_string()),
+        });
+    }
+    variants
+}
+
+// Resize the image at `path` into every size, next to the source unless an
+// output path is given, and write each size in every format of `encoding`.
+// With `padding` each size is fitted onto a canvas of the target aspect
+// instead. The source is decoded once and the sizes are resized in parallel
+// on the rayon pool, except when they would write to the same path. Sizes
+// not started when `cancel` is set are skipped.
+pub fn resize_image(
+    path: &str,
+    output: &Option<PathBuf>,
+    sizes: Vec<Size>,
+    padding: Option<Padding>,
+    encoding: &Encoding,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> Result<ImagesReport, Box<dyn Error>> {
+    progress.log(&format!("Opening image at {}", path));
+
+    let p = Path::new(path);
+    let file_name = p.file_stem().unwrap();
+    let formats = encoding.formats_for(padding).len();
+    let task = progress.start_task(
+        &format!("Resizing {}", file_name.to_string_lossy()),
+        Some((sizes.len() * formats) as u64),
+    );
+    let img = ImageReader::open(path)?.decode()?;
+    let img = match encoding.crop {
+        Some(aspect) => crop_to_ratio(&img, aspect.width, aspect.height),
+        None => img,
+    };
+    let mark = match &encoding.watermark {
+        Some(watermark) => Some(
+            ImageReader::open(&watermark.path)
+                .map_err(|err| {
+                    format!(
+                        "Could not open watermark {}: {}",
+                        watermark.path.display(),
+                        err
+                    )
+                })?
+                .decode()?,
+        ),
+        None => None,
+    };
+
+    let plans: Vec<PlannedSize> = sizes
+        .iter()
+        .map(|size| plan_size(p, img.dimensions(), output, size, padding, encoding))
+        .collect();
+    let mut paths = HashSet::new();
+    let distinct = plans
+        .iter()
+        .flat_map(|plan| &plan.outputs)
+        .all(|(_, path)| paths.insert(path.clone()));
+
+    let write = |plan: PlannedSize| {
+        // Finish the files being written, but start no more
+        if cancel.is_cancelled() {
+            return None;
+        }
+        Some(write_size(
+            &img,
+            plan,
+            padding,
+            encoding,
+            mark.as_ref(),
+            task.as_ref(),
+        ))
+    };
+    let results: Vec<Option<Vec<ImageVariant>>> = match distinct {
+        true => plans.into_par_iter().map(write).collect(),
+        false => plans.into_iter().map(write).collect(),
+    };
+
+    let mut variants = Vec::with_capacity(results.len() * formats);
+    for result in results {
+        match result {
+            Some(written) => variants.extend(written),
+            None => task.log("Interrupted, skipped a size"),
+        }
+    }
+    task.finish("done");
+
+    Ok(ImagesReport {
+        source: p.to_path_buf(),
+        variants,
+    })
+}
+
+#[derive(Serialize)]
+pub struct ImagesDirReport {
+    pub dir: PathBuf,
+    // Paired with the output directory its variants went to
+    pub images: Vec<(ImagesReport, Option<PathBuf>)>,
+    // Files that are not a readable image type
+    pub skipped: Vec<PathBuf>,
+    // Images that could not be opened or decoded
+    pub failed: Vec<(PathBuf, String)>,
+    pub stopped: bool,
+}
+
+impl CommandReport for ImagesDirReport {
+    fn print_human(&self) {
+        for (report, _) in &self.images {
+            report.print_human();
+        }
+        for (path, err) in &self.failed {
+            println!("Failed {}: {}", path.display(), err);
+        }
+        println!(
+            "Processed {} images, skipped {} files",
+            self.images.len(),
+            self.skipped.len()
+        );
+        if !self.failed.is_empty() {
+            println!("{} images failed", self.failed.len());
+        }
+        if self.stopped {
+            println!("Interrupted before every image was resized");
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        let variant_failed = self
+            .images
+            .iter()
+            .any(|(report, _)| report.exit_code() != 0);
+        match self.stopped || variant_failed || !self.failed.is_empty() {
+            true => 1,
+            false => 0,
+        }
+    }
+}
+
+// Files in `dir` split into those the image crate can decode and the rest,
+// by extension. Sorted so runs are repeatable.
+pub fn image_files(dir: &Path, recursive: bool) -> io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let (mut images, mut skipped) = (Vec::new(), Vec::new());
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                if recursive {
+                    dirs.push(path);
+                }
+                continue;
+            }
+            match image::ImageFormat::from_path(&path).map(|format| format.reading_enabled()) {
+                Ok(true) => images.push(path),
+                _ => skipped.push(path),
+            }
+        }
+    }
+    images.sort();
+    skipped.sort();
+    Ok((images, skipped))
+}
+
+// `resize_image` for every image in `dir`, several images at once on the
+// rayon pool. With `output` the variants go there under the same relative
+// directory as their source, otherwise next to it. An image that cannot be
+// decoded is reported and the rest carry on.
+#[allow(clippy::too_many_arguments)]
+pub fn resize_directory(
+    dir: &Path,
+    output: &Option<PathBuf>,
+    recursive: bool,
+    sizes: Vec<Size>,
+    padding: Option<Padding>,
+    encoding: &Encoding,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> Result<ImagesDirReport, Box<dyn Error>> {
+    let (files, skipped) = image_files(dir, recursive)?;
+    progress.log(&format!(
+        "Found {} images in {}, skipping {} other files",
+        files.len(),
+        dir.display(),
+        skipped.len()
+    ));
+
+    let mut out_dirs = Vec::with_capacity(files.len());
+    for file in &files {
+        out_dirs.push(match output {
+            Some(output) => {
+                let relative = file
+                    .parent()
+                    .and_then(|parent| parent.strip_prefix(dir).ok())
+                    .unwrap_or(Path::new(""));
+                let out_dir = output.join(relative);
+                fs::create_dir_all(&out_dir)?;
+                Some(out_dir)
+            }
+            None => None,
+        });
+    }
+
+    let results: Vec<Option<Result<ImagesReport, String>>> = files
+        .par_iter()
+        .zip(&out_dirs)
+        .map(|(file, out_dir)| {
+            if cancel.is_cancelled() {
+                return None;
+            }
+            let resized = resize_image(
+                &file.to_string_lossy(),
+                out_dir,
+                sizes.clone(),
+                padding,
+                encoding,
+                progress,
+                cancel,
+            );
+            Some(resized.map_err(|err| err.to_string()))
+        })
+        .collect();
+
+    let mut report = ImagesDirReport {
+        dir: dir.to_path_buf(),
+        images: Vec::new(),
+        skipped,
+        failed: Vec::new(),
+        stopped: false,
+    };
+    for ((file, out_dir), result) in files.into_iter().zip(out_dirs).zip(results) {
+        match result {
+            Some(Ok(resized)) => report.images.push((resized, out_dir)),
+            Some(Err(err)) => report.failed.push((file, err)),
+            None => report.stopped = true,
+        }
+    }
+    if report.stopped {
+        progress.log("Interrupted, skipped the remaining images");
+    }
+
+    Ok(report)
+}
+
+// What `images --manifest` generated for a source, enough to redo it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    pub source: PathBuf,
+    pub output: Option<PathBuf>,
+    pub sizes: Vec<String>,
+    pub variants: Vec<PathBuf>,
+    // Final width and height of each variant, in the order of `variants`
+    #[serde(default)]
+    pub dimensions: Vec<(u32, u32)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pad_to_aspect: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background: Option<String>,
+    // Missing from manifests written before formats could be chosen
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ImageManifest {
+    pub sources: Vec<ManifestEntry>,
+}
+
+impl ImageManifest {
+    // An empty manifest when the file does not exist yet
+    pub fn load_or_default(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(ImageManifest::default());
+        }
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("Could not read manifest {}: {}", path.display(), err))?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    // Replace the entry for the report's source, or add one
+    pub fn record(
+        &mut self,
+        report: &ImagesReport,
+        output: &Option<PathBuf>,
+        sizes: &[Size],
+        padding: Option<Padding>,
+        encoding: &Encoding,
+    ) {
+        let written = || {
+            report
+                .variants
+                .iter()
+                .filter(|variant| variant.error.is_none())
+        };
+        let entry = ManifestEntry {
+            source: report.source.clone(),
+            output: output.clone(),
+            sizes: sizes.iter().map(Size::to_string).collect(),
+            variants: written().map(|variant| variant.path.clone()).collect(),
+            dimensions: written()
+                .map(|variant| (variant.width, variant.height))
+                .collect(),
+            pad_to_aspect: padding.map(|padding| padding.aspect.to_string()),
+            background: padding.map(|padding| padding.background.to_string()),
+            encoding: encoding.clone(),
+        };
+
+        match self
+            .sources
+            .iter_mut()
+            .find(|existing| existing.source == entry.source)
+        {
+            Some(existing) => *existing = entry,
+            None => self.sources.push(entry),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ImageSyncReport {
+    pub manifest: PathBuf,
+    // Sources newer than a variant, or missing one, that were processed again
+    pub regenerated: Vec<PathBuf>,
+    pub skipped: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl CommandReport for ImageSyncReport {
+    fn print_human(&self) {
+        for source in &self.regenerated {
+            println!("Regenerated {}", source.display());
+        }
+        for source in &self.skipped {
+            println!("Up to date  {}", source.display());
+        }
+        for (source, err) in &self.failed {
+            println!("Failed {}: {}", source.display(), err);
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self.failed.is_empty() {
+            true => 0,
+            false => 1,
+        }
+    }
+}
+
+// A source needs processing when it changed after its oldest variant was
+// written, or when a variant is missing
+fn is_stale(entry: &ManifestEntry) -> Result<bool, Box<dyn Error>> {
+    let source_modified = fs::metadata(&entry.source)?.modified()?;
+    if entry.variants.is_empty() {
+        return Ok(true);
+    }
+
+    for variant in &entry.variants {
+        match fs::metadata(variant).and_then(|meta| meta.modified()) {
+            Ok(modified) if modified >= source_modified => continue,
+            _ => return Ok(true),
+        }
+    }
+
+    Ok(false)
+}
+
+// Regenerate the variants of every manifest source that changed since, then
+// write the manifest back with the new variants
+pub fn sync_images(
+    manifest_path: &Path,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> Result<ImageSyncReport, Box<dyn Error>> {
+    let mut manifest = ImageManifest::load_or_default(manifest_path)?;
+    let mut report = ImageSyncReport {
+        manifest: manifest_path.to_path_buf(),
+        regenerated: Vec::new(),
+        skipped: Vec::new(),
+        failed: Vec::new(),
+    };
+
+    for entry in manifest.sources.clone() {
+        if cancel.is_cancelled() {
+            break;
+        }
+        match is_stale(&entry) {
+            Ok(false) => {
+                report.skipped.push(entry.source);
+                continue;
+            }
+            Ok(true) => {}
+            Err(err) => {
+                report.failed.push((entry.source, err.to_string()));
+                continue;
+            }
+        }
+
+        let sizes = entry
+            .sizes
+            .iter()
+            .map(|size| size.parse().or_else(|_| parse_size_spec(size)))
+            .collect::<Result<Vec<Size>, String>>()?;
+        let sizes = combine_edge_sizes(sizes);
+        let padding = match &entry.pad_to_aspect {
+            Some(aspect) => Some(Padding {
+                aspect: aspect.parse()?,
+                background: match &entry.background {
+                    Some(background) => background.parse()?,
+                    None => Background::Color([255, 255, 255]),
+                },
+            }),
+            None => None,
+        };
+        let source = entry.source.to_string_lossy().to_string();
+        match resize_image(
+            &source,
+            &entry.output,
+            sizes.clone(),
+            padding,
+            &entry.encoding,
+            progress,
+            cancel,
+        ) {
+            Ok(images) => {
+                manifest.record(&images, &entry.output, &sizes, padding, &entry.encoding);
+                match images
+                    .variants
+                    .iter()
+                    .find_map(|variant| variant.error.clone())
+                {
+                    Some(err) => report.failed.push((entry.source, err)),
+                    None => report.regenerated.push(entry.source),
+                }
+            }
+            Err(err) => report.failed.push((entry.source, err.to_string())),
+        }
+    }
+
+    manifest.save(manifest_path)?;
+    Ok(report)
+}
+
+// One side of `image-compare`
+#[derive(Serialize)]
+pub struct ComparedImage {
+    pub path: PathBuf,
+    pub format: Option<String>,
+    pub bytes: u64,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Serialize)]
+pub struct CompareReport {
+    pub a: ComparedImage,
+    pub b: ComparedImage,
+    // Which of the two was scaled down to the other's dimensions
+    pub resized: Option<PathBuf>,
+    // None when the images are identical
+    pub psnr: Option<f64>,
+    pub ssim: f64,
+    pub min_ssim: Option<f64>,
+}
+
+impl CommandReport for CompareReport {
+    fn print_human(&self) {
+        for image in [&self.a, &self.b] {
+            println!(
+                "{} {} {}x{} {} bytes",
+                image.path.display(),
+                image.format.as_deref().unwrap_or("unknown"),
+                image.width,
+                image.height,
+                image.bytes
+            );
+        }
+        if let Some(resized) = &self.resized {
+            println!(
+                "Note: dimensions differ, {} was resized to compare",
+                resized.display()
+            );
+        }
+        match self.psnr {
+            Some(psnr) => println!("PSNR {:.2} dB", psnr),
+            None => println!("PSNR inf (identical)"),
+        }
+        println!("SSIM {:.4}", self.ssim);
+        if let Some(min_ssim) = self.min_ssim {
+            if self.ssim < min_ssim {
+                println!("SSIM is below the minimum of {}", min_ssim);
+            }
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self.min_ssim {
+            Some(min_ssim) if self.ssim < min_ssim => 1,
+            _ => 0,
+        }
+    }
+}
+
+// Measure how far `b` is from `a`. When the sizes differ the larger image is
+// resized to the smaller one's dimensions first.
+pub fn compare_images(
+    a: &Path,
+    b: &Path,
+    min_ssim: Option<f64>,
+) -> Result<CompareReport, Box<dyn Error>> {
+    let open = |path: &Path| -> Result<_, Box<dyn Error>> {
+        let reader = ImageReader::open(path)?.with_guessed_format()?;
+        let format = reader
+            .format()
+            .map(|format| format!("{:?}", format).to_lowercase());
+        let img = reader.decode()?;
+        let info = ComparedImage {
+            path: path.to_path_buf(),
+            format,
+            bytes: fs::metadata(path)?.len(),
+            width: img.width(),
+            height: img.height(),
+        };
+        Ok((img, info))
+    };
+    let (mut img_a, a) = open(a)?;
+    let (mut img_b, b) = open(b)?;
+
+    let mut resized = None;
+    if img_a.dimensions() != img_b.dimensions() {
+        match a.width as u64 * a.height as u64 > b.width as u64 * b.height as u64 {
+            true => {
+                img_a = img_a.resize_exact(b.width, b.height, Lanczos3);
+                resized = Some(a.path.clone());
+            }
+            false => {
+                img_b = img_b.resize_exact(a.width, a.height, Lanczos3);
+                resized = Some(b.path.clone());
+            }
+        }
+    }
+
+    let psnr = psnr(&img_a, &img_b);
+    Ok(CompareReport {
+        ssim: ssim(&img_a, &img_b),
+        psnr: psnr.is_finite().then_some(psnr),
+        a,
+        b,
+        resized,
+        min_ssim,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edges(long: Option<u32>, short: Option<u32>) -> Size {
+        Size {
+            bounds: Bounds::Edges { long, short },
+            suffix: None,
+        }
+    }
+
+    #[test]
+    fn sizes_parse_widths_boxes_and_edges() {
+        assert_eq!("1200".parse(), Ok(Size::width(1200)));
+        assert_eq!(
+            "1200x800".parse(),
+            Ok(Size {
+                bounds: Bounds::Box {
+                    width: 1200,
+                    height: 800
+                },
+                suffix: Some("1200x800".to_string()),
+            })
+        );
+        assert_eq!("long:2048".parse(), Ok(edges(Some(2048), None)));
+        assert_eq!("short:1400".parse(), Ok(edges(None, Some(1400))));
+        assert_eq!(
+            "long:2048,short:1400".parse(),
+            Ok(edges(Some(2048), Some(1400)))
+        );
+        assert_eq!(" 600 ".parse(), Ok(Size::width(600)));
+    }
+
+    #[test]
+    fn sizes_refuse_malformed_values() {
+        for invalid in [
+            "",
+            "0",
+            "-5",
+            "wide",
+            "1200x",
+            "x800",
+            "1200x0",
+            "1200x800x600",
+            "long:",
+            "long:0",
+            "tall:100",
+            "long:100,long:200",
+            "1200:small",
+        ] {
+            let err = invalid.parse::<Size>().unwrap_err();
+            assert!(err.starts_with("Invalid size"), "{}: {}", invalid, err);
+        }
+    }
+
+    #[test]
+    fn split_edge_sizes_are_put_back_together() {
+        let sizes = ["long:2048", "short:1400", "600", "short:300", "long:900"]
+            .iter()
+            .map(|size| size.parse().unwrap())
+            .collect();
+        assert_eq!(
+            combine_edge_sizes(sizes),
+            vec![
+                edges(Some(2048), Some(1400)),
+                Size::width(600),
+                edges(Some(900), Some(300)),
+            ]
+        );
+    }
+
+    #[test]
+    fn edge_sizes_of_one_kind_stay_apart() {
+        let sizes = vec![edges(Some(2048), None), edges(Some(1024), None)];
+        assert_eq!(combine_edge_sizes(sizes.clone()), sizes);
+    }
+
+    #[test]
+    fn edge_limits_bind_whichever_edge_needs_the_smaller_scale() {
+        let size = edges(Some(2048), Some(1400));
+        // Landscape 4:3, the short edge binds
+        assert_eq!(size.dimensions(4000, 3000), (1867, 1400));
+        // Landscape 2:1, the long edge binds
+        assert_eq!(size.dimensions(4000, 2000), (2048, 1024));
+        // Portrait, the same limits apply to the other axes
+        assert_eq!(size.dimensions(3000, 4000), (1400, 1867));
+        assert_eq!(size.dimensions(2000, 4000), (1024, 2048));
+        // Panorama where the short edge would allow far more
+        assert_eq!(size.dimensions(6000, 1000), (2048, 341));
+    }
+
+    #[test]
+    fn edge_limits_on_square_and_near_square_sources() {
+        let size = edges(Some(2048), Some(1400));
+        // Both edges are long and short at once, so the short limit binds
+        assert_eq!(size.dimensions(3000, 3000), (1400, 1400));
+        assert_eq!(size.dimensions(2000, 1600), (1750, 1400));
+    }
+
+    #[test]
+    fn edge_limits_do_not_upscale_small_sources() {
+        let size = edges(Some(2048), Some(1400));
+        assert_eq!(size.dimensions(800, 600), (800, 600));
+        assert_eq!(size.dimensions(600, 800), (600, 800));
+        assert_eq!(edges(None, Some(1400)).dimensions(1400, 900), (1400, 900));
+    }
+
+    #[test]
+    fn single_edge_limits() {
+        assert_eq!(edges(Some(1000), None).dimensions(4000, 3000), (1000, 750));
+        assert_eq!(edges(Some(1000), None).dimensions(3000, 4000), (750, 1000));
+        assert_eq!(edges(None, Some(1000)).dimensions(4000, 2000), (2000, 1000));
+        assert_eq!(edges(None, Some(1000)).dimensions(2000, 4000), (1000, 2000));
+    }
+
+    #[test]
+    fn widths_and_boxes_keep_the_aspect() {
+        assert_eq!(Size::width(1200).dimensions(4000, 3000), (1200, 900));
+        assert_eq!(Size::width(1200).dimensions(3000, 4000), (1200, 1600));
+        assert_eq!(Size::width(1200).dimensions(800, 600), (800, 600));
+        let boxed: Size = "1200x800".parse().unwrap();
+        assert_eq!(boxed.dimensions(4000, 3000), (1067, 800));
+        assert_eq!(boxed.dimensions(3000, 4000), (600, 800));
+        assert_eq!(boxed.dimensions(2000, 2000), (800, 800));
+    }
+
+    #[test]
+    fn edge_sizes_are_named_after_the_resolved_dimensions() {
+        let size = edges(Some(2048), Some(1400));
+        let (width, height) = size.dimensions(3000, 4000);
+        assert_eq!(size.suffix_for(width, height), "1400x1867");
+    }
+}
@@ -0,0 +1,524 @@
+use elasticsearch::{
+    cluster::ClusterHealthParts,
+    http::StatusCode,
+    indices::{IndicesDeleteParts, IndicesGetMappingParts, IndicesStatsParts},
+    Elasticsearch, SearchParts,
+};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::error::Error;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::time::Duration;
+
+use crate::cancel::CancelToken;
+use crate::geonames::{DocumentOptions, Location};
+use crate::mapping::MappingDiff;
+use crate::output::CommandReport;
+
+const NUMERIC_TYPES: [&str; 9] = [
+    "long",
+    "integer",
+    "short",
+    "byte",
+    "double",
+    "float",
+    "half_float",
+    "scaled_float",
+    "unsigned_long",
+];
+
+// Field types with doc values that bucket and metric aggregations can read
+fn is_aggregatable(field_type: &str) -> bool {
+    matches!(field_type, "keyword" | "date" | "boolean" | "geo_point")
+        || NUMERIC_TYPES.contains(&field_type)
+}
+
+fn has_stats(field_type: &str) -> bool {
+    field_type == "date" || NUMERIC_TYPES.contains(&field_type)
+}
+
+fn has_cardinality(field_type: &str) -> bool {
+    is_aggregatable(field_type) && field_type != "geo_point"
+}
+
+// Fetch the live mapping of an index or index pattern, as the properties object
+pub async fn get_properties(client: &Elasticsearch, index: &str) -> Result<Value, Box<dyn Error>> {
+    let response = client
+        .indices()
+        .get_mapping(IndicesGetMappingParts::Index(&[index]))
+        .send()
+        .await?;
+
+    if !response.status_code().is_success() {
+        return Err(format!(
+            "Could not get mapping for index {}: {}",
+            index,
+            response.status_code()
+        )
+        .into());
+    }
+
+    // A pattern such as geolocations-* matches several indices, merge their
+    // properties so fields only some of them have are still reported
+    let body = response.json::<Value>().await?;
+    let mut properties = Map::new();
+    for mapping in body
+        .as_object()
+        .into_iter()
+        .flat_map(|indices| indices.values())
+    {
+        if let Some(fields) = mapping["mappings"]["properties"].as_object() {
+            for (name, definition) in fields {
+                properties
+                    .entry(name.clone())
+                    .or_insert_with(|| definition.clone());
+            }
+        }
+    }
+
+    Ok(Value::Object(properties))
+}
+
+// Flatten object properties into `parent.child` field names with their types
+pub fn flatten_properties(properties: &Value) -> Vec<(String, String)> {
+    fn walk(prefix: &str, properties: &Map<String, Value>, fields: &mut Vec<(String, String)>) {
+        for (name, definition) in properties {
+            let path = match prefix.is_empty() {
+                true => name.clone(),
+                false => format!("{}.{}", prefix, name),
+            };
+            match definition["properties"].as_object() {
+                Some(children) => walk(&path, children, fields),
+                None => fields.push((
+                    path,
+                    definition["type"].as_str().unwrap_or("object").to_string(),
+                )),
+            }
+        }
+    }
+
+    let mut fields = Vec::new();
+    if let Some(properties) = properties.as_object() {
+        walk("", properties, &mut fields);
+    }
+    fields.sort();
+
+    fields
+}
+
+fn stats_query(fields: &[(String, String)]) -> Value {
+    let mut aggs = Map::new();
+    for (field, field_type) in fields {
+        if has_cardinality(field_type) {
+            aggs.insert(
+                format!("{}__cardinality", field),
+                json!({"cardinality": {"field": field}}),
+            );
+        }
+        if has_stats(field_type) {
+            aggs.insert(
+                format!("{}__stats", field),
+                json!({"stats": {"field": field}}),
+            );
+        }
+        // Text has no doc values, so fall back to counting documents
+        // without the field
+        let missing = match is_aggregatable(field_type) {
+            true => json!({"missing": {"field": field}}),
+            false => json!({"filter": {"bool": {"must_not": {"exists": {"field": field}}}}}),
+        };
+        aggs.insert(format!("{}__missing", field), missing);
+    }
+
+    json!({"size": 0, "track_total_hits": true, "aggs": aggs})
+}
+
+// Prefer the formatted form ES returns for dates over epoch millis
+fn format_stat(stats: &Value, name: &str) -> Option<String> {
+    match stats[format!("{}_as_string", name)].as_str() {
+        Some(formatted) => Some(formatted.to_string()),
+        None => stats[name]
+            .as_f64()
+            .map(|number| match number.fract() == 0.0 {
+                true => format!("{}", number as i64),
+                false => format!("{:.2}", number),
+            }),
+    }
+}
+
+#[derive(Serialize)]
+pub struct FieldStats {
+    pub field: String,
+    pub field_type: String,
+    pub cardinality: Option<u64>,
+    pub null_pct: f64,
+    pub min: Option<String>,
+    pub max: Option<String>,
+    pub avg: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct IndexStatsReport {
+    pub index: String,
+    pub documents: u64,
+    pub fields: Vec<FieldStats>,
+}
+
+impl CommandReport for IndexStatsReport {
+    fn print_human(&self) {
+        println!("Index {} has {} documents", self.index, self.documents);
+
+        let width = self
+            .fields
+            .iter()
+            .map(|stats| stats.field.len())
+            .max()
+            .unwrap_or(5)
+            .max(5);
+        println!(
+            "{:<width$} | {:<13} | {:>11} | {:>8} | {:>14} | {:>14} | {:>14}",
+            "field",
+            "type",
+            "cardinality",
+            "null_pct",
+            "min",
+            "max",
+            "avg",
+            width = width
+        );
+
+        let dash = || "-".to_string();
+        for stats in &self.fields {
+            println!(
+                "{:<width$} | {:<13} | {:>11} | {:>7.2}% | {:>14} | {:>14} | {:>14}",
+                stats.field,
+                stats.field_type,
+                stats
+                    .cardinality
+                    .map(|c| c.to_string())
+                    .unwrap_or_else(dash),
+                stats.null_pct,
+                stats.min.clone().unwrap_or_else(dash),
+                stats.max.clone().unwrap_or_else(dash),
+                stats.avg.clone().unwrap_or_else(dash),
+                width = width
+            );
+        }
+    }
+}
+
+// Cardinality, null rate and min/max/avg for the fields of an index
+pub async fn index_stats(
+    client: &Elasticsearch,
+    index: &str,
+    fields: &Option<String>,
+) -> Result<IndexStatsReport, Box<dyn Error>> {
+    let mut mapped = flatten_properties(&get_properties(client, index).await?);
+
+    if let Some(selected) = fields {
+        let selected: Vec<&str> = selected.split(',').map(str::trim).collect();
+        for name in &selected {
+            if !mapped.iter().any(|(field, _)| field == name) {
+                return Err(format!("Field {} is not in the mapping of {}", name, index).into());
+            }
+        }
+        mapped.retain(|(field, _)| selected.contains(&field.as_str()));
+    }
+
+    let response = client
+        .search(SearchParts::Index(&[index]))
+        .body(stats_query(&mapped))
+        .send()
+        .await?;
+
+    if !response.status_code().is_success() {
+        return Err(format!(
+            "Stats query against {} failed: {}",
+            index,
+            response.text().await?
+        )
+        .into());
+    }
+
+    let body = response.json::<Value>().await?;
+    let documents = body["hits"]["total"]["value"].as_u64().unwrap_or_default();
+    let aggs = &body["aggregations"];
+
+    let fields = mapped
+        .into_iter()
+        .map(|(field, field_type)| {
+            let missing = aggs[format!("{}__missing", field)]["doc_count"]
+                .as_u64()
+                .unwrap_or_default();
+            let null_pct = match documents {
+                0 => 0.0,
+                total => missing as f64 * 100.0 / total as f64,
+            };
+            let stats = &aggs[format!("{}__stats", field)];
+
+            FieldStats {
+                cardinality: aggs[format!("{}__cardinality", field)]["value"].as_u64(),
+                null_pct,
+                min: format_stat(stats, "min"),
+                max: format_stat(stats, "max"),
+                avg: format_stat(stats, "avg"),
+                field,
+                field_type,
+            }
+        })
+        .collect();
+
+    Ok(IndexStatsReport {
+        index: index.to_string(),
+        documents,
+        fields,
+    })
+}
+
+// Polls in a row an index may be red before watching gives up
+pub const MAX_RED_POLLS: u32 = 3;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Green,
+    Yellow,
+    Red,
+}
+
+impl std::fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            HealthStatus::Green => "green",
+            HealthStatus::Yellow => "yellow",
+            HealthStatus::Red => "red",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub async fn index_health(
+    client: &Elasticsearch,
+    index: &str,
+) -> Result<HealthStatus, Box<dyn Error>> {
+    let response = client
+        .cluster()
+        .health(ClusterHealthParts::Index(&[index]))
+        .send()
+        .await?
+        .error_for_status_code()?;
+    let body = response.json::<Value>().await?;
+
+    match body["status"].as_str() {
+        Some("green") => Ok(HealthStatus::Green),
+        Some("yellow") => Ok(HealthStatus::Yellow),
+        Some("red") => Ok(HealthStatus::Red),
+        _ => Err(format!("Could not read the health of {}", index).into()),
+    }
+}
+
+#[derive(Serialize)]
+pub struct IndexHealthReport {
+    pub index: String,
+    pub status: HealthStatus,
+    pub polls: u64,
+    // Set when watching stopped because the index stayed red
+    pub red_polls: u32,
+    pub watching: bool,
+}
+
+impl CommandReport for IndexHealthReport {
+    fn print_human(&self) {
+        match self.watching {
+            true if self.red_polls > MAX_RED_POLLS => println!(
+                "{} was red for {} polls in a row, giving up",
+                self.index, self.red_polls
+            ),
+            true => println!(
+                "{} is {} after {} polls",
+                self.index, self.status, self.polls
+            ),
+            false => println!("{} is {}", self.index, self.status),
+        }
+    }
+
+    // A single check exits 0, 1 or 2 for green, yellow or red. Watching only
+    // fails when the index stayed red.
+    fn exit_code(&self) -> i32 {
+        match (self.watching, self.status) {
+            (true, _) if self.red_polls > MAX_RED_POLLS => 1,
+            (true, _) => 0,
+            (false, HealthStatus::Green) => 0,
+            (false, HealthStatus::Yellow) => 1,
+            (false, HealthStatus::Red) => 2,
+        }
+    }
+}
+
+// Check the index health every `interval`, printing a line whenever it
+// changes, until it has been red for more than MAX_RED_POLLS polls in a row
+// or the run is cancelled
+pub async fn watch_index_health(
+    client: &Elasticsearch,
+    index: &str,
+    interval: Duration,
+    cancel: &CancelToken,
+) -> Result<IndexHealthReport, Box<dyn Error>> {
+    let mut report = IndexHealthReport {
+        index: index.to_string(),
+        status: index_health(client, index).await?,
+        polls: 1,
+        red_polls: 0,
+        watching: true,
+    };
+    let mut previous = None;
+
+    loop {
+        if previous != Some(report.status) {
+            println!(
+                "{} {} is {}",
+                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                index,
+                report.status
+            );
+            previous = Some(report.status);
+        }
+        report.red_polls = match report.status {
+            HealthStatus::Red => report.red_polls + 1,
+            _ => 0,
+        };
+        if report.red_polls > MAX_RED_POLLS {
+            return Ok(report);
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = cancel.cancelled() => return Ok(report),
+        }
+        report.status = index_health(client, index).await?;
+        report.polls += 1;
+    }
+}
+
+#[derive(Serialize)]
+pub struct DeleteIndexReport {
+    pub index: String,
+}
+
+impl CommandReport for DeleteIndexReport {
+    fn print_human(&self) {
+        println!("Deleted index {}", self.index);
+    }
+}
+
+// Ask for the index name to be typed back, only an exact match goes ahead
+pub fn confirm_index_name(index: &str) -> Result<(), Box<dyn Error>> {
+    if !io::stdin().is_terminal() {
+        return Err(format!(
+            "Refusing to delete {} without --confirm when stdin is not a terminal",
+            index
+        )
+        .into());
+    }
+    eprint!(
+        "This deletes index {} and every document in it. Type its name to confirm: ",
+        index
+    );
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    match answer.trim() == index {
+        true => Ok(()),
+        false => Err(format!("Name did not match, {} was not deleted", index).into()),
+    }
+}
+
+pub async fn delete_index(
+    client: &Elasticsearch,
+    index: &str,
+) -> Result<DeleteIndexReport, Box<dyn Error>> {
+    let response = client
+        .indices()
+        .delete(IndicesDeleteParts::Index(&[index]))
+        .send()
+        .await?;
+
+    match response.status_code() {
+        StatusCode::NOT_FOUND => Err(format!("Index {} does not exist", index).into()),
+        status if !status.is_success() => {
+            let body = response.json::<Value>().await.unwrap_or_default();
+            Err(format!("Could not delete index {}: {}", index, body["error"]).into())
+        }
+        _ => Ok(DeleteIndexReport {
+            index: index.to_string(),
+        }),
+    }
+}
+
+#[derive(Serialize)]
+pub struct StorageStatsReport {
+    pub index: String,
+    pub documents: u64,
+    pub deleted_documents: u64,
+    pub primary_store_bytes: u64,
+    // The live mapping against the one seed generates by default
+    pub mapping: MappingDiff,
+}
+
+fn megabytes(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+impl CommandReport for StorageStatsReport {
+    fn print_human(&self) {
+        println!("Index {}", self.index);
+        println!("  documents          {}", self.documents);
+        println!("  deleted documents  {}", self.deleted_documents);
+        println!(
+            "  primary store      {} ({} bytes)",
+            megabytes(self.primary_store_bytes),
+            self.primary_store_bytes
+        );
+        self.mapping.print_human();
+    }
+
+    fn exit_code(&self) -> i32 {
+        self.mapping.exit_code()
+    }
+}
+
+// Document counts and storage from `_stats`, with the mapping compared to
+// `Location::generate_mapping`
+pub async fn storage_stats(
+    client: &Elasticsearch,
+    index: &str,
+) -> Result<StorageStatsReport, Box<dyn Error>> {
+    let response = client
+        .indices()
+        .stats(IndicesStatsParts::Index(&[index]))
+        .send()
+        .await?;
+    match response.status_code() {
+        StatusCode::NOT_FOUND => return Err(format!("Index {} does not exist", index).into()),
+        status if !status.is_success() => {
+            return Err(format!("Could not get stats for index {}: {}", index, status).into())
+        }
+        _ => {}
+    }
+    let body = response.json::<Value>().await?;
+    let primaries = &body["_all"]["primaries"];
+
+    let live = get_properties(client, index).await?;
+    let generated = Location::generate_mapping(&DocumentOptions::default());
+
+    Ok(StorageStatsReport {
+        index: index.to_string(),
+        documents: primaries["docs"]["count"].as_u64().unwrap_or_default(),
+        deleted_documents: primaries["docs"]["deleted"].as_u64().unwrap_or_default(),
+        primary_store_bytes: primaries["store"]["size_in_bytes"]
+            .as_u64()
+            .unwrap_or_default(),
+        mapping: MappingDiff::between(index, &live, &generated["properties"]),
+    })
+}
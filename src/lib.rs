@@ -0,0 +1,37 @@
+// The modules behind the admin binary, usable on their own, e.g. to stream
+// geonames dumps with `geonames::LocationReader`
+pub mod alias;
+pub mod backend;
+pub mod bench;
+pub mod cancel;
+pub mod checkpoint;
+pub mod config;
+pub mod deploy;
+pub mod doctor;
+pub mod es;
+pub mod flavor;
+pub mod geonames;
+pub mod image_ops;
+pub mod images;
+pub mod index;
+pub mod iso3166;
+pub mod logging;
+pub mod mapping;
+pub mod meili;
+pub mod metadata;
+pub mod object_storage;
+pub mod output;
+pub mod postgres;
+pub mod progress;
+pub mod search;
+pub mod seed;
+pub mod snapshot;
+pub mod sqlite;
+pub mod tiles;
+pub mod tui;
+pub mod update_admin;
+pub mod validate;
+pub use geonames::{
+    dump_reader, load_admin_files, DocumentOptions, GeonamesError, Location, LocationFilter,
+    LocationReader,
+};
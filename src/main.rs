@@ -1,40 +1,386 @@
 use std::{
     env::current_dir,
     error::Error,
-    fs::File,
-    io::Write,
     path::{Path, PathBuf},
-    time::Instant,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use clap::{Parser, Subcommand};
-use elasticsearch::{
-    http::{transport::Transport, StatusCode},
-    indices::{IndicesCreateParts, IndicesExistsParts, IndicesPutMappingParts},
-    BulkOperation, BulkParts, Elasticsearch,
-};
-use image::GenericImageView;
-use image::{imageops::FilterType::Lanczos3, io::Reader as ImageReader};
-use serde_json::{self, Value};
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
+use serde::Serialize;
 
-pub mod deploy;
-pub mod geonames;
-pub mod metadata;
-pub use geonames::{load_admin_files, Location};
+use admin::{
+    alias, bench, config, deploy, doctor, geonames, images, index, logging, object_storage, output,
+    search, seed, snapshot, status, tiles, update_admin, validate,
+};
+use admin::{dump_reader, load_admin_files, DocumentOptions, Location};
 
-use crate::deploy::{move_files, run_trunk, scp_files};
-use crate::metadata::{discover_single, load_metadata};
+use admin::backend::{DryRunBackend, ElasticsearchBackend, NdjsonBackend};
+use admin::cancel::{install_ctrl_c_handler, CancelToken, INTERRUPTED_EXIT_CODE};
+use admin::config::{config_flag, load_user_config, Config, UserConfig};
+use admin::deploy::{
+    apply_ignore, build_env, cargo_build_release, check_remote_space, clean_old_deployments,
+    copy_extra_static, dir_size, fix_remote_permissions, move_files, remote_api_dir,
+    remote_site_dir, run_trunk, scp_api_binary, scp_files, BuildReport, DeployMetrics,
+    DeployReport, DeployStage, IgnorePatterns, IgnoredFiles, ServerResult, TrunkFeatures,
+    DEFAULT_API_SERVER, DEFAULT_SERVER,
+};
+use admin::es::{connect, EsArgs};
+use admin::geonames::AdminKind;
+use admin::image_ops::WatermarkPosition;
+use admin::images::{
+    Aspect, Background, Encoding, Format, ImageManifest, Padding, Size, Watermark,
+};
+use admin::index::IndexHealthReport;
+use admin::mapping::MappingDiff;
+use admin::meili::{BackendKind, MeilisearchBackend};
+use admin::metadata::{cached_load_metadata, discover_single, Metadata, SiteType};
+use admin::object_storage::ObjectTarget;
+use admin::output::{emit, CommandReport, OutputFormat};
+use admin::postgres::PostgresBackend;
+use admin::progress::{reporter, ProgressMode};
+use admin::search::{Fuzziness, GeoPoint, SearchOptions, SearchSort};
+use admin::seed::{OutputFile, SeedOpts};
+use admin::snapshot::SnapshotAction;
+use admin::sqlite::SqliteBackend;
 
 #[derive(Parser)]
 #[command(author= "Why Not Cats", version, about = "Administrative Utlity for Why Not Cats projects", long_about = None)]
 struct Opt {
     #[command(subcommand)]
     command: Commands,
+
+    /// Ignore ~/.config/admin-cli/config.toml, for reproducible CI runs
+    #[clap(long, global = true)]
+    no_user_config: bool,
+
+    /// TOML file with [seed] and [images] tables of flag defaults, such as
+    /// `index = "places"`. Flags on the command line override them
+    #[clap(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Print the command result as a JSON document on stdout. Status
+    /// messages always go to stderr
+    #[clap(long, global = true, value_enum, default_value_t = OutputFormat::Human)]
+    output_format: OutputFormat,
+
+    /// Show debug messages such as bulk request sizes and response codes,
+    /// and everything with -vv. RUST_LOG overrides it
+    #[clap(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Only show warnings and errors, and no progress. Long only, -q is
+    /// the quality of `images`
+    #[clap(long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Threads running async tasks, defaults to one per CPU core
+    #[clap(long, global = true)]
+    worker_threads: Option<usize>,
+
+    /// Upper limit on threads for blocking work such as image encoding,
+    /// defaults to 512
+    #[clap(long, global = true)]
+    blocking_threads: Option<usize>,
+
+    /// Write debug logs to this file as JSON lines, rotated at 10MB.
+    /// Defaults to admin.log in the user config `log_dir` when that is set
+    #[clap(long, global = true)]
+    log_file: Option<PathBuf>,
+
+    /// Rotated log files to keep
+    #[clap(long, global = true, default_value_t = logging::DEFAULT_LOG_KEEP)]
+    log_keep: usize,
+
+    /// Print progress as periodic plain lines instead of bars, for CI logs
+    #[clap(long, global = true)]
+    no_progress: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Seed {
+    Seed(Box<SeedOpts>),
+    Images {
+        /// Image to resize, or a directory to resize every image in
+        path: String,
+
+        /// File or directory to write to. For a source directory, the
+        /// directory variants are written to, keeping the file names
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// With a source directory, also resize images in its subdirectories
+        #[clap(long)]
+        recursive: bool,
+
+        /// Comma separated sizes to generate, each WIDTH, WIDTHxHEIGHT or edge
+        /// limits such as long:2048 or long:2048,short:1400, whichever binds
+        /// first. Defaults to 1200,600,2400 when neither this nor --size is given
+        #[clap(long, value_delimiter = ',')]
+        sizes: Vec<Size>,
+
+        /// A size with its own file name suffix, WIDTH[xHEIGHT]:SUFFIX such as
+        /// 800:small or 1920x1080:hd. Repeatable, added after --sizes
+        #[clap(long = "size", value_parser = images::parse_size_spec)]
+        size: Vec<Size>,
+
+        /// Record the source, sizes and generated files in this JSON manifest,
+        /// for image-sync
+        #[clap(long)]
+        manifest: Option<PathBuf>,
+
+        /// Write the SHA-256 of every generated file here, in the format
+        /// `sha256sum -c` checks, such as photo-sha256sums.txt
+        #[clap(long)]
+        checksums: Option<PathBuf>,
+
+        /// Fit each size onto a canvas of this W:H aspect, such as 4:3,
+        /// padding instead of cropping
+        #[clap(long)]
+        pad_to_aspect: Option<Aspect>,
+
+        /// Center crop the source to this WxH aspect, such as 16x9 or 1x1,
+        /// before resizing
+        #[clap(long, conflicts_with = "pad_to_aspect")]
+        crop: Option<Aspect>,
+
+        /// Padding color: transparent, white, black or hex such as #f0f0f0.
+        /// Transparent padding is written as PNG
+        #[clap(long, default_value = "white")]
+        background: Background,
+
+        /// Write every size in this format, repeat for several. Defaults to
+        /// the --output extension, else jpeg, or png with transparent padding
+        #[clap(long, value_enum)]
+        format: Vec<Format>,
+
+        /// JPEG quality from 1 to 100, defaults to image_quality from the user
+        /// config, else 85
+        #[clap(short, long, value_parser = clap::value_parser!(u8).range(1..=100))]
+        quality: Option<u8>,
+
+        /// AVIF quality from 1 to 100
+        #[clap(long, default_value_t = images::DEFAULT_AVIF_QUALITY, value_parser = clap::value_parser!(u8).range(1..=100))]
+        avif_quality: u8,
+
+        /// AVIF encoder speed from 1, slowest and smallest, to 10
+        #[clap(long, default_value_t = images::DEFAULT_AVIF_SPEED, value_parser = clap::value_parser!(u8).range(1..=10))]
+        avif_speed: u8,
+
+        /// Image, usually a PNG with transparency, drawn over every size
+        #[clap(long)]
+        watermark: Option<PathBuf>,
+
+        /// Corner of each size the watermark is drawn in, or center
+        #[clap(
+            long,
+            value_enum,
+            default_value = "bottom-right",
+            requires = "watermark"
+        )]
+        watermark_position: WatermarkPosition,
+
+        /// Width of the watermark as a fraction of each size's width
+        #[clap(long, default_value_t = images::DEFAULT_WATERMARK_SCALE, value_parser = images::parse_watermark_scale, requires = "watermark")]
+        watermark_scale: f64,
+    },
+    /// Regenerate the images in a manifest whose source changed since
+    ImageSync {
+        manifest: PathBuf,
+    },
+    /// Report PSNR and SSIM between an image and a re-encode of it
+    ImageCompare {
+        a: PathBuf,
+        b: PathBuf,
+
+        /// Exit 1 when the SSIM is below this, from 0 to 1
+        #[clap(long)]
+        min_ssim: Option<f64>,
+    },
+    /// Cut a large image into a z/x/y tile pyramid for slippy maps
+    ImageTile {
+        source: PathBuf,
+
+        /// Directory for the tiles, defaults to <source name>-tiles next to it
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// Zoom showing the source at full resolution, defaults to the
+        /// number of levels until zoom 0 fits one tile
+        #[clap(long)]
+        max_zoom: Option<u32>,
+    },
+    Deploy {
+        app: String,
+        #[clap(short = 'c', long)]
+        project_toml: Option<PathBuf>,
+
+        /// Site or directory name picking the project when several are found
+        #[clap(long, conflicts_with = "project_toml")]
+        project: Option<String>,
+
+        /// chmod the deployed files to 644 and directories to 755 after transfer
+        #[clap(long)]
+        fix_permissions: bool,
+
+        /// Only create the assets directory, leaving the build output where
+        /// Trunk put it
+        #[clap(long)]
+        skip_file_move: bool,
+
+        /// URL the built assets are served from, over the site's
+        /// `build.public_url`, defaults to /assets/
+        #[clap(long)]
+        public_url: Option<String>,
+
+        /// Write the phase timings and bytes transferred to this file as JSON
+        #[clap(long)]
+        metrics_file: Option<PathBuf>,
+
+        /// Build and print the files that would be moved, without moving or
+        /// transferring anything
+        #[clap(long)]
+        dry_run: bool,
+
+        /// For sites deployed to object storage, remove objects under the
+        /// prefix that are not part of this deploy
+        #[clap(long)]
+        delete: bool,
+
+        /// Upload without checking the server has room for the deploy
+        #[clap(long)]
+        no_space_check: bool,
+    },
+    /// Build a trunk app without deploying it
+    Build {
+        app: String,
+        #[clap(short = 'c', long)]
+        project_toml: Option<PathBuf>,
+
+        /// Site or directory name picking the project when several are found
+        #[clap(long, conflicts_with = "project_toml")]
+        project: Option<String>,
+
+        /// Comma separated Cargo features, defaults to the site's `features`
+        /// in the project toml
+        #[clap(long, value_delimiter = ',')]
+        features: Vec<String>,
+
+        /// Build without the app's default Cargo features
+        #[clap(long)]
+        no_default_features: bool,
+
+        /// KEY=VALUE set for the build, over the site's `build.env`
+        #[clap(long = "env", value_parser = parse_env_var)]
+        env: Vec<(String, String)>,
+
+        /// URL the built assets are served from, over the site's
+        /// `build.public_url`, defaults to /assets/
+        #[clap(long)]
+        public_url: Option<String>,
+    },
+    /// Write a Graphviz DOT graph of the project's site dependencies
+    Graph {
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        #[clap(short = 'c', long)]
+        project_toml: Option<PathBuf>,
+
+        /// Site or directory name picking the project when several are found
+        #[clap(long, conflicts_with = "project_toml")]
+        project: Option<String>,
+    },
+    /// Check the project toml and every site in it before a deploy: sources
+    /// exist, names are unique and static sites have a Trunk.toml
+    Validate {
+        #[clap(short = 'c', long)]
+        project_toml: Option<PathBuf>,
+
+        /// Site or directory name picking the project when several are found
+        #[clap(long, conflicts_with = "project_toml")]
+        project: Option<String>,
+    },
+    /// Search seeded locations by name
+    Search {
+        query: String,
+
+        #[command(flatten)]
+        es: EsArgs,
+
+        #[clap(short, long, default_value = "geolocations")]
+        index: String,
+
+        /// Results to show
+        #[clap(short, long = "limit", default_value_t = 10)]
+        n: usize,
+
+        /// Allowed typos per word: 0, 1, 2 or auto, which is what --fuzzy
+        /// alone means
+        #[clap(long, num_args = 0..=1, default_missing_value = "auto")]
+        fuzzy: Option<Fuzziness>,
+
+        /// Comma separated fields to search, defaults to the name fields
+        #[clap(long, value_delimiter = ',')]
+        fields: Vec<String>,
+
+        /// Comma separated fields to return and show as columns
+        #[clap(long, value_delimiter = ',')]
+        source: Vec<String>,
+
+        #[clap(long, value_enum, default_value_t = SearchSort::Score)]
+        sort: SearchSort,
+
+        /// Point to search around as lat,lon, e.g. 51.5,-0.12
+        #[clap(long, allow_hyphen_values = true)]
+        near: Option<GeoPoint>,
+
+        /// Only show locations within this many kilometers of --near
+        #[clap(long, requires = "near")]
+        radius: Option<f64>,
+
+        /// Show the nearest to --near first, with their distance in kilometers
+        #[clap(long, requires = "near", conflicts_with = "sort")]
+        sort_by_distance: bool,
+
+        /// Print the query DSL sent to Elasticsearch
+        #[clap(long)]
+        explain: bool,
+    },
+    #[command(allow_negative_numbers = true)]
+    ReverseGeocode {
+        lat: f64,
+
+        lon: f64,
+
+        #[command(flatten)]
+        es: EsArgs,
+
+        #[clap(short, long, default_value = "geolocations")]
+        index: String,
+
+        #[clap(short, default_value_t = 5)]
+        n: usize,
+    },
+    /// Check tools, connectivity and project config in one pass
+    Doctor {
+        #[command(flatten)]
+        es: EsArgs,
+
+        /// Deploy servers to check ssh access to
+        #[clap(long = "server", default_value = DEFAULT_SERVER)]
+        servers: Vec<String>,
+
+        #[clap(short = 'c', long)]
+        project_toml: Option<PathBuf>,
+
+        /// Site or directory name picking the project when several are found
+        #[clap(long, conflicts_with = "project_toml")]
+        project: Option<String>,
+    },
+    /// Refresh only the admin1/admin2 names of documents already seeded, after
+    /// the admin code files change
+    UpdateAdmin {
         #[clap(short, long)]
         path: String,
 
@@ -44,240 +390,1076 @@ enum Commands {
         #[clap(short = '2', long)]
         admin2: String,
 
-        #[clap(short, long, default_value = "http://localhost:9200")]
-        elasticsearch: String,
+        #[command(flatten)]
+        es: EsArgs,
 
         #[clap(short, long, default_value = "geolocations")]
         index: String,
 
-        #[clap(short, long, default_value_t = 100000)]
+        /// Documents fetched and compared per request
+        #[clap(short, long, default_value_t = 10000)]
         buffer: usize,
     },
-    Images {
-        path: String,
+    /// Write an admin codes file back out as TSV with a header row, sorted by
+    /// code
+    ExportAdmin {
+        /// Admin codes file to read, e.g. admin1CodesASCII.txt
+        #[clap(short, long)]
+        input: String,
 
         #[clap(short, long)]
-        output: Option<PathBuf>,
+        output: PathBuf,
+
+        #[clap(long, value_enum)]
+        kind: AdminKind,
     },
-    Deploy {
-        app: String,
-        #[clap(short = 'c', long)]
-        project_toml: Option<PathBuf>,
+    /// Measure ingest throughput over a grid of batch sizes and concurrency
+    /// levels with synthetic documents in a temporary index
+    Bench {
+        #[command(flatten)]
+        es: EsArgs,
+
+        /// Comma separated documents per bulk request to try
+        #[clap(long, value_delimiter = ',', default_value = "1000,5000,20000")]
+        batch_sizes: Vec<usize>,
+
+        /// Comma separated numbers of bulk requests in flight to try
+        #[clap(long, value_delimiter = ',', default_value = "1,2,4")]
+        concurrency: Vec<usize>,
+
+        /// Documents to send across all combinations
+        #[clap(long, default_value_t = 200_000)]
+        max_docs: u64,
+
+        /// Seed for the synthetic documents, the same seed sends the same ones
+        #[clap(long, default_value_t = 1)]
+        seed: u64,
+    },
+    /// Per-field cardinality, null rate and numeric stats for an index
+    IndexStats {
+        #[clap(short, long, default_value = "geolocations")]
+        index: String,
+
+        #[command(flatten)]
+        es: EsArgs,
+
+        /// Comma separated fields to report on, defaults to every mapped field
+        #[clap(short, long)]
+        fields: Option<String>,
+    },
+    /// Document counts, storage size and whether the mapping matches the
+    /// generated one, exiting like `mapping diff`
+    Stats {
+        #[clap(short, long, default_value = "geolocations")]
+        index: String,
+
+        #[command(flatten)]
+        es: EsArgs,
+    },
+    /// Print the health of an index, exiting 0, 1 or 2 for green, yellow
+    /// or red, or keep watching it with --interval-secs
+    IndexHealth {
+        #[clap(short, long, default_value = "geolocations")]
+        index: String,
+
+        #[command(flatten)]
+        es: EsArgs,
+
+        /// Poll every this many seconds, printing changes, and exit 1 once the
+        /// index has been red for more than 3 polls in a row
+        #[clap(long)]
+        interval_secs: Option<u64>,
+    },
+    /// Delete an index and every document in it, asking for its name to be
+    /// typed back first
+    Delete {
+        index: String,
+
+        #[command(flatten)]
+        es: EsArgs,
+
+        /// Delete without asking, for scripts
+        #[clap(long)]
+        confirm: bool,
+    },
+    /// Remove versioned deployments of a site beyond the newest few, never
+    /// the one `current` links to
+    CleanOldDeployments {
+        site_name: String,
+
+        #[clap(long, default_value = DEFAULT_SERVER)]
+        server: String,
+
+        /// Deployments to keep
+        #[clap(short, long, default_value_t = 5)]
+        keep: usize,
+
+        /// ssh identity file, defaults to the user config `ssh_identity`
+        #[clap(long)]
+        ssh_key: Option<PathBuf>,
+    },
+    /// Compare index mappings with the one seed generates
+    Mapping {
+        #[command(subcommand)]
+        action: MappingAction,
+    },
+    /// Back up and restore indices with the Elasticsearch snapshot API
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotAction,
+    },
+    /// List the aliases from the user config and project, and where each
+    /// was defined
+    Aliases,
+    /// Read and update the user config file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
     },
 }
 
-struct Size {
-    width: u32,
-    height: Option<u32>,
-    suffix: String,
+#[derive(Subcommand)]
+enum MappingAction {
+    /// Show fields added, removed or changed since the index was created.
+    /// Exits 1 for additions only and 2 when the index needs rebuilding
+    Diff {
+        #[command(flatten)]
+        es: EsArgs,
+
+        #[clap(short, long, default_value = "geolocations")]
+        index: String,
+
+        /// Compare against a mapping without the completion suggester
+        #[clap(long)]
+        no_suggest: bool,
+
+        /// Compare against a mapping with the display_name field
+        #[clap(long)]
+        display_name: bool,
+
+        /// Compare against a mapping without elevation_category
+        #[clap(long)]
+        no_elevation_category: bool,
+
+        /// Compare against a mapping with this seed time field
+        #[clap(long, num_args = 0..=1, default_missing_value = "seeded_at")]
+        seeded_at_field: Option<String>,
+
+        /// Compare against a mapping of only these comma separated fields
+        #[clap(long, value_delimiter = ',', conflicts_with = "exclude_fields")]
+        include_fields: Vec<String>,
+
+        /// Compare against a mapping without these comma separated fields
+        #[clap(long, value_delimiter = ',')]
+        exclude_fields: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    Get { key: String },
+    Set { key: String, value: String },
+    List,
+}
+
+#[derive(Serialize)]
+struct GraphReport {
+    dot: String,
+    output: Option<PathBuf>,
+}
+
+impl CommandReport for GraphReport {
+    fn print_human(&self) {
+        match &self.output {
+            Some(output) => println!("Wrote dependency graph to {}", output.display()),
+            None => print!("{}", self.dot),
+        }
+    }
+}
+
+// `KEY=VALUE` from --env
+fn parse_env_var(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("Invalid --env {}, expected KEY=VALUE", value)),
+    }
 }
 
-async fn run() -> Result<(), Box<dyn Error>> {
-    let opt = Opt::parse();
+// Project metadata and the directory its apps live in
+fn load_project(
+    project_toml: &Option<PathBuf>,
+    project: &Option<String>,
+) -> Result<(Arc<Metadata>, PathBuf), Box<dyn Error>> {
+    status!("Finding project toml");
+    let config_path = match project_toml {
+        Some(path) => path.clone(),
+        None => discover_single(current_dir()?.as_path(), project.as_deref())?,
+    };
+    let config = cached_load_metadata(config_path.as_path())?;
+    let project_dir = config.project_dir(&config_path);
+
+    Ok((config, project_dir))
+}
+
+// Runs the selected command, returning the process exit code
+async fn run(opt: Opt, cancel: &CancelToken) -> Result<i32, Box<dyn Error>> {
+    output::set_format(opt.output_format);
+    let user_config = if opt.no_user_config {
+        UserConfig::default()
+    } else {
+        load_user_config()?
+    };
+    let log_path = logging::log_path(&opt.log_file, &user_config.log_dir);
+    logging::init(
+        logging::console_level(opt.verbose, opt.quiet),
+        log_path.as_deref().map(|path| (path, opt.log_keep)),
+    )?;
+    let progress = reporter(ProgressMode::detect(
+        !opt.no_progress && !opt.quiet && user_config.progress.unwrap_or(true),
+        opt.output_format,
+    ));
 
     match &opt.command {
-        Commands::Seed {
+        Commands::Seed(opts) => {
+            let result = match (&opts.output_file, &opts.sqlite, opts.dry_run) {
+                (Some(path), _, _) => {
+                    status!(
+                        "Writing to {}, nothing is sent to elasticsearch",
+                        path.display()
+                    );
+                    let backend = NdjsonBackend::create(path)
+                        .map_err(|err| format!("Could not create {}: {}", path.display(), err))?;
+                    let mut result = seed::run_seed(opts, &backend, progress, cancel).await?;
+                    let bytes = backend.finish()?;
+                    result.output_file = Some(OutputFile {
+                        path: path.clone(),
+                        bytes,
+                    });
+                    result
+                }
+                (None, Some(path), _) => {
+                    status!(
+                        "Writing to {}, nothing is sent to elasticsearch",
+                        path.display()
+                    );
+                    let backend = SqliteBackend::open(path, opts.replace)?;
+                    let mut result = seed::run_seed(opts, &backend, progress, cancel).await?;
+                    let bytes = backend.finish()?;
+                    result.output_file = Some(OutputFile {
+                        path: path.clone(),
+                        bytes,
+                    });
+                    result
+                }
+                (None, None, true) => {
+                    status!("Dry run, nothing is sent to elasticsearch");
+                    let backend = match opts.check_indices {
+                        true if opts.backend == BackendKind::Meilisearch => {
+                            return Err("--check-indices only works with Elasticsearch".into())
+                        }
+                        true => {
+                            let cluster =
+                                ElasticsearchBackend::new(connect(&opts.es, &user_config).await?);
+                            DryRunBackend::checking(cluster)
+                        }
+                        false => DryRunBackend::new(),
+                    };
+                    seed::run_seed(opts, &backend, progress, cancel).await?
+                }
+                (None, None, false) if opts.postgres.is_some() => {
+                    status!("Creating connection to Postgres");
+                    let url = opts.postgres.as_deref().expect("checked above");
+                    let backend = PostgresBackend::connect(url).await?;
+                    seed::run_seed(opts, &backend, progress, cancel).await?
+                }
+                (None, None, false) if opts.backend == BackendKind::Meilisearch => {
+                    status!("Creating connection to {}", opts.meili.meili_url);
+                    let backend = MeilisearchBackend::connect(&opts.meili).await?;
+                    seed::run_seed(opts, &backend, progress, cancel).await?
+                }
+                (None, None, false) => {
+                    status!("Creating connection to {}", opts.es.endpoint(&user_config)?);
+                    let backend = ElasticsearchBackend::new(connect(&opts.es, &user_config).await?);
+                    seed::run_seed(opts, &backend, progress, cancel).await?
+                }
+            };
+            emit(&result)
+        }
+        Commands::Images {
             path,
-            admin1,
-            admin2,
-            elasticsearch,
-            index,
-            buffer,
+            output,
+            recursive,
+            sizes,
+            size,
+            manifest,
+            checksums,
+            pad_to_aspect,
+            crop,
+            background,
+            format,
+            quality,
+            avif_quality,
+            avif_speed,
+            watermark,
+            watermark_position,
+            watermark_scale,
         } => {
-            println!("Loading admin files");
-            let (admin1, admin2) = load_admin_files(admin1, admin2)?;
+            let sizes = match sizes.is_empty() && size.is_empty() {
+                true => images::default_sizes(),
+                false => {
+                    let mut sizes = images::combine_edge_sizes(sizes.clone());
+                    sizes.extend(size.iter().cloned());
+                    sizes
+                }
+            };
 
-            println!("Creating connection to {}", elasticsearch);
-            let client = Elasticsearch::new(Transport::single_node(elasticsearch)?);
-
-            println!("Checking to see if index {} exists", index);
-            let exists_response = client
-                .indices()
-                .exists(IndicesExistsParts::Index(&[index]))
-                .send()
-                .await?;
-
-            if exists_response.status_code() == StatusCode::NOT_FOUND {
-                println!("Creating index with mapping");
-                let create_index_response = client
-                    .indices()
-                    .create(IndicesCreateParts::Index(index))
-                    .send()
-                    .await?;
-
-                if StatusCode::is_success(&create_index_response.status_code()) {
-                    println!("Applying Mapping");
-                    let apply_mapping_response = client
-                        .indices()
-                        .put_mapping(IndicesPutMappingParts::Index(&[index]))
-                        .body(Location::generate_mapping())
-                        .send()
-                        .await?;
-
-                    if apply_mapping_response.status_code() == StatusCode::OK {
-                        println!("Created mapping for index {}", index);
-                    } else {
-                        panic!("Could not update mapping for index {}", index);
+            let padding = pad_to_aspect.map(|aspect| Padding {
+                aspect,
+                background: *background,
+            });
+            // `-o hero.webp` picks the format when none is given
+            let formats = match (
+                format.is_empty(),
+                output.as_deref().and_then(Format::from_path),
+            ) {
+                (true, Some(format)) => vec![format],
+                _ => format.clone(),
+            };
+            let encoding = Encoding {
+                formats,
+                jpeg_quality: quality
+                    .or(user_config.image_quality)
+                    .unwrap_or(images::DEFAULT_JPEG_QUALITY),
+                avif_quality: *avif_quality,
+                avif_speed: *avif_speed,
+                watermark: watermark.as_ref().map(|path| Watermark {
+                    path: path.clone(),
+                    position: *watermark_position,
+                    scale: *watermark_scale,
+                }),
+                crop: *crop,
+            };
+            // Resizing runs on the rayon pool, kept off the runtime's threads
+            let job = (
+                path.clone(),
+                output.clone(),
+                sizes.clone(),
+                encoding.clone(),
+                cancel.clone(),
+            );
+            if Path::new(path).is_dir() {
+                let recursive = *recursive;
+                let report = tokio::task::spawn_blocking(move || {
+                    let (path, output, sizes, encoding, cancel) = job;
+                    images::resize_directory(
+                        Path::new(&path),
+                        &output,
+                        recursive,
+                        sizes,
+                        padding,
+                        &encoding,
+                        progress.as_ref(),
+                        &cancel,
+                    )
+                    .map_err(|err| err.to_string())
+                })
+                .await??;
+                if let Some(manifest_path) = manifest {
+                    let mut image_manifest = ImageManifest::load_or_default(manifest_path)?;
+                    for (image, out_dir) in &report.images {
+                        image_manifest.record(image, out_dir, &sizes, padding, &encoding);
                     }
-                } else {
-                    panic!("Could not create index {}", index);
+                    image_manifest.save(manifest_path)?;
+                }
+                if let Some(checksums) = checksums {
+                    let variants = report.images.iter().flat_map(|(image, _)| &image.variants);
+                    images::write_checksums(checksums, variants)?;
                 }
-            } else {
-                println!("Index {} exists", index);
+                return emit(&report);
             }
+            let report = tokio::task::spawn_blocking(move || {
+                let (path, output, sizes, encoding, cancel) = job;
+                images::resize_image(
+                    &path,
+                    &output,
+                    sizes,
+                    padding,
+                    &encoding,
+                    progress.as_ref(),
+                    &cancel,
+                )
+                .map_err(|err| err.to_string())
+            })
+            .await??;
+            if let Some(manifest_path) = manifest {
+                let mut image_manifest = ImageManifest::load_or_default(manifest_path)?;
+                image_manifest.record(&report, output, &sizes, padding, &encoding);
+                image_manifest.save(manifest_path)?;
+            }
+            if let Some(checksums) = checksums {
+                images::write_checksums(checksums, &report.variants)?;
+            }
+            emit(&report)
+        }
+        Commands::ImageSync { manifest } => {
+            let (manifest, cancel) = (manifest.clone(), cancel.clone());
+            let report = tokio::task::spawn_blocking(move || {
+                images::sync_images(&manifest, progress.as_ref(), &cancel)
+                    .map_err(|err| err.to_string())
+            })
+            .await??;
+            emit(&report)
+        }
+        Commands::ImageCompare { a, b, min_ssim } => {
+            let (a, b, min_ssim) = (a.clone(), b.clone(), *min_ssim);
+            let report = tokio::task::spawn_blocking(move || {
+                images::compare_images(&a, &b, min_ssim).map_err(|err| err.to_string())
+            })
+            .await??;
+            emit(&report)
+        }
+        Commands::ImageTile {
+            source,
+            output,
+            max_zoom,
+        } => {
+            let output = match output {
+                Some(output) => output.clone(),
+                None => source.with_file_name(format!(
+                    "{}-tiles",
+                    source.file_stem().unwrap_or_default().to_string_lossy()
+                )),
+            };
+            let (source, max_zoom, cancel) = (source.clone(), *max_zoom, cancel.clone());
+            let report = tokio::task::spawn_blocking(move || {
+                tiles::generate_tiles(&source, &output, max_zoom, progress.as_ref(), &cancel)
+                    .map_err(|err| err.to_string())
+            })
+            .await??;
+            emit(&report)
+        }
+        Commands::Build {
+            app,
+            project_toml,
+            project,
+            features,
+            no_default_features,
+            env,
+            public_url,
+        } => {
+            let (config, project_dir) = load_project(project_toml, project)?;
+            let site = config.site(app);
+            let env = build_env(site.and_then(|site| site.build.build_env.as_ref()), env);
+            let features = TrunkFeatures {
+                features: match features.is_empty() {
+                    true => site.map(|site| site.features.clone()).unwrap_or_default(),
+                    false => features.clone(),
+                },
+                no_default_features: *no_default_features,
+            };
 
-            println!("Opening file {}", path);
-            let f = std::fs::File::open(path)?;
-            let mut file = zip::read::ZipArchive::new(f)?;
-            let zf = file.by_index(0)?;
-
-            println!("Building file reader");
-            let mut rdr = csv::ReaderBuilder::new()
-                .delimiter(b'\t')
-                .has_headers(false)
-                .from_reader(Box::new(zf));
-
-            let mut records = 0;
-            let mut commands: Vec<BulkOperation<_>> = Vec::with_capacity(*buffer);
-
-            for result in rdr.deserialize() {
-                let record: Location = result?;
-
-                commands.push(
-                    BulkOperation::index(record.generate_elasticsearch_document(&admin1, &admin2))
-                        .id(record.id.to_string())
-                        .into(),
-                );
-                records += 1;
-
-                if records % buffer == 0 {
-                    println!("Loaded {} commands", records);
-
-                    let response = client
-                        .bulk(BulkParts::Index(index))
-                        .body(commands)
-                        .send()
-                        .await?;
-
-                    let response_body = response.json::<Value>().await?;
-                    let success = !response_body["errors"].as_bool().unwrap();
-                    if success {
-                        commands = Vec::with_capacity(*buffer);
-                        println!("Inserted {} records", records);
-                    } else {
-                        let mut file = File::create("error.log")?;
-                        file.write_all(response_body.to_string().as_bytes())?;
-
-                        panic!("Error inserting records into elaticsearch");
-                    }
+            let public_url = deploy::public_url(public_url, site.map(|site| &site.build));
+            let duration_ms = run_trunk(&project_dir.join(app), &features, &env, &public_url)?;
+            let mut env: Vec<String> = env.into_keys().collect();
+            env.sort();
+            emit(&BuildReport {
+                app: app.clone(),
+                features,
+                public_url,
+                env,
+                duration_ms,
+            })
+        }
+        Commands::Deploy {
+            app,
+            project_toml,
+            project,
+            fix_permissions,
+            skip_file_move,
+            public_url,
+            metrics_file,
+            dry_run,
+            delete,
+            no_space_check,
+        } => {
+            let (config, project_dir) = load_project(project_toml, project)?;
+            let app_dir = project_dir.join(app);
+            let site = config.site(app);
+            let deploy_config = site.map(|site| &site.deploy);
+            // Checked before building so a typo does not cost a build
+            let object_target = match deploy_config.and_then(|deploy| deploy.target.as_deref()) {
+                Some(target) => Some(ObjectTarget::parse(target).ok_or_else(|| {
+                    format!(
+                        "deploy.target {} of {} is not an s3://bucket/prefix URL",
+                        target, app
+                    )
+                })?),
+                None => None,
+            };
+            let features = TrunkFeatures {
+                features: site.map(|site| site.features.clone()).unwrap_or_default(),
+                no_default_features: false,
+            };
+            let env = build_env(site.and_then(|site| site.build.build_env.as_ref()), &[]);
+            // Stages are only ever skipped between steps, never interrupted
+            let interrupted = |done: &str| -> Result<(), Box<dyn Error>> {
+                match cancel.is_cancelled() {
+                    true => Err(format!("Deploy of {} interrupted after {}", app, done).into()),
+                    false => Ok(()),
                 }
-            }
+            };
+            let stage = |name: &str, duration_ms: u64| DeployStage {
+                name: name.to_string(),
+                duration_ms,
+            };
+            let mut metrics = DeployMetrics::default();
+            let identity = user_config
+                .ssh_identity
+                .as_ref()
+                .map(|path| path.to_string_lossy().to_string());
+            let ssh_args: Vec<&str> = match &identity {
+                Some(identity) => vec!["-i", identity],
+                None => vec![],
+            };
+
+            // API sites are a cargo binary behind a systemd service, not files
+            if let Some(site) = site.filter(|site| matches!(site.site_type, SiteType::Api)) {
+                if object_target.is_some() {
+                    status!("deploy.target only applies to static sites, ignoring it");
+                }
+                let server = site.deploy.server.as_deref().unwrap_or(DEFAULT_API_SERVER);
+                let binary = site.deploy.binary.as_deref().unwrap_or(app);
+                let service = site.deploy.service.as_deref().unwrap_or(app);
 
-            if !commands.is_empty() {
-                let response = client
-                    .bulk(BulkParts::Index(index))
-                    .body(commands)
-                    .send()
-                    .await?;
-
-                let success = !response.json::<Value>().await?["errors"].as_bool().unwrap();
-                if success {
-                    println!("Inserted {} records", records);
-                } else {
-                    panic!("Error inserting records into elaticsearch")
+                status!("Building project");
+                let (executable, build_duration_ms) =
+                    cargo_build_release(&app_dir, binary, &features, &env)?;
+                metrics.build_duration_ms = build_duration_ms;
+                let mut stages = vec![stage("build", metrics.build_duration_ms)];
+                if *dry_run {
+                    status!(
+                        "Dry run, built {} but transferred nothing",
+                        executable.display()
+                    );
+                    return emit(&DeployReport {
+                        app: app.clone(),
+                        stages,
+                        metrics,
+                        servers: Vec::new(),
+                    });
                 }
+                interrupted("build, nothing was transferred")?;
+
+                // A unit file next to the site is installed with the binary
+                let unit = app_dir.join(format!("{}.service", service));
+                status!("Deploying {} to {}", app, server);
+                let transfer = scp_api_binary(
+                    &executable,
+                    unit.is_file().then_some(unit.as_path()),
+                    server,
+                    app,
+                    service,
+                    user_config.ssh_identity.as_deref(),
+                )?;
+                metrics.transfer_duration_ms = transfer.duration_ms;
+                metrics.total_bytes_transferred = transfer.bytes;
+                stages.push(stage("transfer", metrics.transfer_duration_ms));
+
+                if let Some(metrics_file) = metrics_file {
+                    std::fs::write(metrics_file, serde_json::to_string_pretty(&metrics)?)?;
+                }
+                return emit(&DeployReport {
+                    app: app.clone(),
+                    stages,
+                    metrics,
+                    servers: vec![ServerResult {
+                        server: server.to_string(),
+                        remote_path: remote_api_dir(app),
+                        permissions_fixed: false,
+                    }],
+                });
             }
 
-            println!("Done sending to elasticsearch");
-            Ok(())
-        }
-        Commands::Images { path, output } => {
-            println!("Opening image at {}", path);
-            let sizes = [
-                Size {
-                    width: 1200,
-                    height: None,
-                    suffix: "1200px".to_string(),
-                },
-                Size {
-                    width: 600,
-                    height: None,
-                    suffix: "600px".to_string(),
-                },
-                Size {
-                    width: 2400,
-                    height: None,
-                    suffix: "2400px".to_string(),
-                },
-            ];
+            status!("Building project");
+            let public_url = deploy::public_url(public_url, site.map(|site| &site.build));
+            metrics.build_duration_ms = run_trunk(&app_dir, &features, &env, &public_url)?;
+            interrupted("build")?;
 
-            let p = Path::new(path);
-            let file_name = p.file_stem().unwrap();
-            for size in sizes {
-                let output_path = if output.is_none() {
-                    p.with_file_name(format!(
-                        "{}-{}",
-                        file_name
-                            .to_str()
-                            .expect("Could not get file_name of image"),
-                        size.suffix
-                    ))
-                    .with_extension("jpg")
-                } else {
-                    output.as_deref().unwrap().to_path_buf()
-                };
-
-                let now = Instant::now();
-                let img = ImageReader::open(path)
-                    .expect("Could not open path to image")
-                    .decode()
-                    .expect("Could not decode image");
-
-                let (_x, y) = img.dimensions();
-                let new_img = img.resize(size.width, size.height.unwrap_or(y), Lanczos3);
-
-                match new_img.save_with_format(&output_path, image::ImageFormat::Jpeg) {
-                    Ok(_) => {
-                        println!("Done processing image in {}ms", now.elapsed().as_millis());
-                    }
-                    Err(err) => {
-                        println!("Error saving image to {}: {}", &output_path.display(), err);
+            let (dist_dir, move_duration_ms) =
+                move_files(&app_dir, &public_url, *skip_file_move, *dry_run)?;
+            metrics.move_duration_ms = move_duration_ms;
+            let extra_static = site
+                .map(|site| site.extra_static.as_slice())
+                .unwrap_or_default();
+            let ignore =
+                IgnorePatterns::new(site.map(|site| site.ignore.as_slice()).unwrap_or_default());
+            let mut ignored = IgnoredFiles::default();
+            let copied = copy_extra_static(
+                &app_dir,
+                &dist_dir,
+                extra_static,
+                &ignore,
+                *dry_run,
+                &mut ignored,
+            )?;
+            apply_ignore(&dist_dir, &ignore, *dry_run, &mut ignored)?;
+            if !copied.is_empty() && !*dry_run {
+                status!("Copied {} extra static files", copied.len());
+            }
+            if *dry_run {
+                status!("Dry run, nothing was moved or transferred");
+                return emit(&DeployReport {
+                    app: app.clone(),
+                    stages: vec![
+                        stage("build", metrics.build_duration_ms),
+                        stage("move", metrics.move_duration_ms),
+                    ],
+                    metrics,
+                    servers: Vec::new(),
+                });
+            }
+            interrupted("moving files, nothing was transferred")?;
+            status!("Files moved to {}", &dist_dir.display());
+
+            status!("Deploying {} to production", &app_dir.display());
+            let (transfer, server) = match &object_target {
+                Some(target) => {
+                    let endpoint = deploy_config.and_then(|deploy| deploy.endpoint.as_deref());
+                    let sync = object_storage::sync_to_bucket(
+                        &dist_dir, target, endpoint, *delete, cancel,
+                    )?;
+                    status!(
+                        "{} files uploaded, {} unchanged, {} deleted",
+                        sync.uploaded.len(),
+                        sync.unchanged,
+                        sync.deleted.len()
+                    );
+                    let server = ServerResult {
+                        server: format!("s3://{}", target.bucket),
+                        remote_path: target.prefix.clone(),
+                        permissions_fixed: false,
+                    };
+                    (sync.transfer, server)
+                }
+                None => {
+                    if !*no_space_check {
+                        check_remote_space(
+                            DEFAULT_SERVER,
+                            &remote_site_dir(app),
+                            dir_size(&dist_dir)?,
+                            &ssh_args,
+                        )?;
                     }
+                    let transfer = scp_files(
+                        &dist_dir,
+                        DEFAULT_SERVER,
+                        app,
+                        user_config.ssh_identity.as_deref(),
+                    )?;
+                    let server = ServerResult {
+                        server: DEFAULT_SERVER.to_string(),
+                        remote_path: remote_site_dir(app),
+                        permissions_fixed: *fix_permissions,
+                    };
+                    (transfer, server)
                 }
+            };
+            metrics.transfer_duration_ms = transfer.duration_ms;
+            metrics.total_bytes_transferred = transfer.bytes;
+            let mut stages = vec![
+                stage("build", metrics.build_duration_ms),
+                stage("move", metrics.move_duration_ms),
+                stage("transfer", metrics.transfer_duration_ms),
+            ];
+
+            if *fix_permissions && object_target.is_some() {
+                status!("Object storage has no file permissions, skipping --fix-permissions");
+            } else if *fix_permissions {
+                interrupted("transfer, permissions were not fixed")?;
+                status!("Fixing permissions on {}", remote_site_dir(app));
+                let started = Instant::now();
+                fix_remote_permissions(DEFAULT_SERVER, &remote_site_dir(app), &ssh_args)?;
+                stages.push(stage("permissions", started.elapsed().as_millis() as u64));
+            }
+
+            if let Some(metrics_file) = metrics_file {
+                std::fs::write(metrics_file, serde_json::to_string_pretty(&metrics)?)?;
             }
-            Ok(())
+
+            emit(&DeployReport {
+                app: app.clone(),
+                stages,
+                metrics,
+                servers: vec![server],
+            })
         }
-        Commands::Deploy { app, project_toml } => {
-            println!("Finding project toml");
-            let config_path = project_toml
-                .clone()
-                .unwrap_or(discover_single(current_dir()?.as_path())?);
-            let config = load_metadata(config_path.as_path())?;
-
-            let project_dir = config.source_dir.unwrap_or(
-                config_path
-                    .parent()
-                    .expect("Config to have a parent path")
-                    .to_path_buf(),
-            );
-            let app_dir = project_dir.join(app);
+        Commands::CleanOldDeployments {
+            site_name,
+            server,
+            keep,
+            ssh_key,
+        } => {
+            let identity = ssh_key
+                .as_ref()
+                .or(user_config.ssh_identity.as_ref())
+                .map(|path| path.to_string_lossy().to_string());
+            let ssh_args: Vec<&str> = match &identity {
+                Some(identity) => vec!["-i", identity],
+                None => vec![],
+            };
 
-            println!("Building project");
-            run_trunk(&app_dir)?;
+            status!("Listing deployments of {} on {}", site_name, server);
+            emit(&clean_old_deployments(server, site_name, *keep, &ssh_args)?)
+        }
+        Commands::Validate {
+            project_toml,
+            project,
+        } => {
+            let config_path = match project_toml {
+                Some(path) => path.clone(),
+                None => discover_single(current_dir()?.as_path(), project.as_deref())?,
+            };
+            emit(&validate::validate_project(&config_path)?)
+        }
+        Commands::Graph {
+            output,
+            project_toml,
+            project,
+        } => {
+            let config_path = match project_toml {
+                Some(path) => path.clone(),
+                None => discover_single(current_dir()?.as_path(), project.as_deref())?,
+            };
+            let dot = cached_load_metadata(config_path.as_path())?.to_dot_graph()?;
+            if let Some(output) = output {
+                std::fs::write(output, &dot)?;
+            }
 
-            let dist_dir = move_files(&app_dir)?;
-            println!("Files moved to {}", &dist_dir.display());
+            emit(&GraphReport {
+                dot,
+                output: output.clone(),
+            })
+        }
+        Commands::Search {
+            query,
+            es,
+            index,
+            n,
+            fuzzy,
+            fields,
+            source,
+            sort,
+            near,
+            radius,
+            sort_by_distance,
+            explain,
+        } => {
+            if near.is_some() && radius.is_none() && !sort_by_distance {
+                return Err("--near needs --radius or --sort-by-distance".into());
+            }
+            if radius.is_some_and(|radius| radius <= 0.0) {
+                return Err("--radius must be positive".into());
+            }
+            let client = connect(es, &user_config).await?;
+            let options = SearchOptions {
+                query,
+                size: *n,
+                fuzzy: *fuzzy,
+                fields,
+                source,
+                sort: *sort,
+                near: *near,
+                radius_km: *radius,
+                sort_by_distance: *sort_by_distance,
+            };
+            emit(&search::search(&client, index, &options, *explain).await?)
+        }
+        Commands::ReverseGeocode {
+            lat,
+            lon,
+            es,
+            index,
+            n,
+        } => {
+            let client = connect(es, &user_config).await?;
+            emit(&search::reverse_geocode(&client, index, *lat, *lon, *n).await?)
+        }
+        Commands::Doctor {
+            es,
+            servers,
+            project_toml,
+            project,
+        } => emit(&doctor::run_doctor(es, &user_config, servers, project_toml, project).await?),
+        Commands::ExportAdmin {
+            input,
+            output,
+            kind,
+        } => {
+            status!("Loading {} file {}", kind, input);
+            emit(&geonames::export_admin(input, output, *kind)?)
+        }
+        Commands::UpdateAdmin {
+            path,
+            admin1,
+            admin2,
+            es,
+            index,
+            buffer,
+        } => {
+            status!("Loading admin files");
+            let (admin1, admin2) = load_admin_files(admin1, admin2)?;
+
+            status!("Creating connection to {}", es.endpoint(&user_config)?);
+            let backend = ElasticsearchBackend::new(connect(es, &user_config).await?);
 
-            println!("Deploying {} to production", &app_dir.display());
-            scp_files(&dist_dir, "static", app)?;
+            status!("Opening file {}", path);
+            let mut file = zip::read::ZipArchive::new(std::fs::File::open(path)?)?;
+            let mut rdr = dump_reader(file.by_index(0)?);
 
-            Ok(())
+            let report = update_admin::update_admin_fields(
+                &backend,
+                index,
+                (&admin1, &admin2),
+                &mut rdr,
+                *buffer,
+                Path::new("error.log"),
+                progress.as_ref(),
+            )
+            .await?;
+            emit(&report)
+        }
+        Commands::Bench {
+            es,
+            batch_sizes,
+            concurrency,
+            max_docs,
+            seed,
+        } => {
+            status!("Creating connection to {}", es.endpoint(&user_config)?);
+            let backend = ElasticsearchBackend::new(connect(es, &user_config).await?);
+            let report = bench::bench(
+                &backend,
+                batch_sizes,
+                concurrency,
+                *max_docs,
+                *seed,
+                progress.as_ref(),
+                cancel,
+            )
+            .await?;
+            emit(&report)
+        }
+        Commands::IndexStats { index, es, fields } => {
+            let client = connect(es, &user_config).await?;
+            emit(&index::index_stats(&client, index, fields).await?)
+        }
+        Commands::Stats { index, es } => {
+            let client = connect(es, &user_config).await?;
+            emit(&index::storage_stats(&client, index).await?)
+        }
+        Commands::Delete { index, es, confirm } => {
+            let client = connect(es, &user_config).await?;
+            if !confirm {
+                index::confirm_index_name(index)?;
+            }
+            emit(&index::delete_index(&client, index).await?)
+        }
+        Commands::IndexHealth {
+            index,
+            es,
+            interval_secs,
+        } => {
+            let client = connect(es, &user_config).await?;
+            let report = match interval_secs {
+                Some(secs) => {
+                    let interval = Duration::from_secs((*secs).max(1));
+                    index::watch_index_health(&client, index, interval, cancel).await?
+                }
+                None => IndexHealthReport {
+                    index: index.clone(),
+                    status: index::index_health(&client, index).await?,
+                    polls: 1,
+                    red_polls: 0,
+                    watching: false,
+                },
+            };
+            emit(&report)
+        }
+        Commands::Mapping {
+            action:
+                MappingAction::Diff {
+                    es,
+                    index,
+                    no_suggest,
+                    display_name,
+                    no_elevation_category,
+                    seeded_at_field,
+                    include_fields,
+                    exclude_fields,
+                },
+        } => {
+            let client = connect(es, &user_config).await?;
+            let live = index::get_properties(&client, index).await?;
+            let mut options = DocumentOptions {
+                suggest: !no_suggest,
+                display_name: *display_name,
+                elevation_category: !no_elevation_category,
+                seeded_at_field: seeded_at_field.clone(),
+                ..DocumentOptions::default()
+            };
+            options.select_fields(include_fields, exclude_fields)?;
+            let generated = Location::generate_mapping(&options);
+            emit(&MappingDiff::between(
+                index,
+                &live,
+                &generated["properties"],
+            ))
+        }
+        Commands::Snapshot { action } => match action {
+            SnapshotAction::Create {
+                es,
+                repo,
+                index,
+                register,
+            } => {
+                let client = connect(es, &user_config).await?;
+                emit(
+                    &snapshot::create_snapshot(
+                        &client,
+                        repo,
+                        index,
+                        register.as_ref(),
+                        progress.as_ref(),
+                    )
+                    .await?,
+                )
+            }
+            SnapshotAction::List { es, repo } => {
+                let client = connect(es, &user_config).await?;
+                emit(&snapshot::list_snapshots(&client, repo).await?)
+            }
+            SnapshotAction::Restore {
+                snapshot,
+                es,
+                repo,
+                index,
+                rename_to,
+                yes,
+            } => {
+                let client = connect(es, &user_config).await?;
+                emit(
+                    &snapshot::restore_snapshot(
+                        &client,
+                        repo,
+                        snapshot,
+                        index,
+                        rename_to.as_deref(),
+                        *yes,
+                    )
+                    .await?,
+                )
+            }
+        },
+        Commands::Aliases => emit(&alias::load_aliases(opt.no_user_config, &Opt::command())?),
+        Commands::Config { action } => {
+            match action {
+                ConfigAction::Get { key } => config::get(key)?,
+                ConfigAction::Set { key, value } => config::set(key, value)?,
+                ConfigAction::List => config::list()?,
+            }
+            Ok(0)
         }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    run().await?;
+fn build_runtime(opt: &Opt) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(threads) = opt.worker_threads {
+        builder.worker_threads(threads);
+    }
+    if let Some(threads) = opt.blocking_threads {
+        builder.max_blocking_threads(threads);
+    }
+
+    builder.build()
+}
+
+// The command line after expanding aliases and adding --config flags
+fn parse_opt() -> Result<Opt, Box<dyn Error>> {
+    // Flags are not parsed yet, but --no-user-config also hides user aliases
+    let args: Vec<String> = std::env::args().collect();
+    let no_user_config = args.iter().any(|arg| arg == "--no-user-config");
+    let cli = Opt::command();
+    let aliases = alias::load_aliases(no_user_config, &cli)?;
+    let args = alias::expand_args(args, &aliases, &cli)?;
+    let args = match config_flag(&args) {
+        Some(path) => Config::load(&path)?.apply(args, &cli, &path)?,
+        None => args,
+    };
+    let opt = Opt::parse_from(args);
+    if opt.worker_threads == Some(0) || opt.blocking_threads == Some(0) {
+        return Err("--worker-threads and --blocking-threads must be at least 1".into());
+    }
+    Ok(opt)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = parse_opt().unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        std::process::exit(1)
+    });
+    let runtime = build_runtime(&opt)?;
+    let cancel = CancelToken::default();
+    let result = runtime.block_on(async {
+        install_ctrl_c_handler(&cancel);
+        run(opt, &cancel).await
+    });
+
+    if let Err(err) = &result {
+        tracing::error!(target: logging::FILE_ONLY, "{}", err);
+    }
+    if cancel.is_cancelled() {
+        if let Err(err) = result {
+            eprintln!("Error: {}", err);
+        }
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    }
+    // Printed with Display, returning it would show the Debug form
+    let code = result.unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        1
+    });
+    if code != 0 {
+        std::process::exit(code);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn runtime_honors_the_worker_thread_count() {
+        let opt =
+            Opt::try_parse_from(["admin", "config", "list", "--worker-threads", "1"]).unwrap();
+        let runtime = build_runtime(&opt).unwrap();
+
+        assert_eq!(runtime.metrics().num_workers(), 1);
+        // Tasks spawned from the runtime all land on that one worker
+        let workers = runtime.block_on(async {
+            let mut handles = Vec::new();
+            for _ in 0..8 {
+                handles.push(tokio::spawn(async { std::thread::current().id() }));
+            }
+            let mut workers = HashSet::new();
+            for handle in handles {
+                workers.insert(handle.await.unwrap());
+            }
+            workers
+        });
+        assert_eq!(workers.len(), 1);
+    }
+}
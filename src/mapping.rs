@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+use crate::output::CommandReport;
+
+// Options Elasticsearch fills in on its own, by field type, left out when
+// comparing so an untouched field does not show up as changed
+const ES_DEFAULTS: [(&str, &str, Value); 4] = [
+    ("completion", "analyzer", Value::Null),
+    ("completion", "preserve_separators", Value::Bool(true)),
+    (
+        "completion",
+        "preserve_position_increments",
+        Value::Bool(true),
+    ),
+    ("completion", "max_input_length", Value::Null),
+];
+
+// Every field of a mapping by its dotted path, including multi-fields such
+// as `display_name.keyword`, with sub-properties and defaults removed
+pub fn normalize(properties: &Value) -> BTreeMap<String, Value> {
+    fn walk(prefix: &str, properties: &Map<String, Value>, fields: &mut BTreeMap<String, Value>) {
+        for (name, definition) in properties {
+            let path = match prefix.is_empty() {
+                true => name.clone(),
+                false => format!("{}.{}", prefix, name),
+            };
+            let Some(definition) = definition.as_object() else {
+                continue;
+            };
+
+            let mut options = definition.clone();
+            let children = options.remove("properties");
+            let multi_fields = options.remove("fields");
+            // Objects read back from the cluster carry the implied type
+            if children.is_some() && options.get("type") == Some(&Value::from("object")) {
+                options.remove("type");
+            }
+            let field_type = options
+                .get("type")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string();
+            for (default_type, option, value) in &ES_DEFAULTS {
+                let is_default = value.is_null() || options.get(*option) == Some(value);
+                if field_type == *default_type && is_default {
+                    options.remove(*option);
+                }
+            }
+
+            if !options.is_empty() {
+                fields.insert(path.clone(), Value::Object(options));
+            }
+            for nested in [children, multi_fields].into_iter().flatten() {
+                if let Some(nested) = nested.as_object() {
+                    walk(&path, nested, fields);
+                }
+            }
+        }
+    }
+
+    let mut fields = BTreeMap::new();
+    if let Some(properties) = properties.as_object() {
+        walk("", properties, &mut fields);
+    }
+    fields
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FieldDefinition {
+    pub field: String,
+    pub definition: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FieldChange {
+    pub field: String,
+    pub live: Value,
+    pub generated: Value,
+}
+
+// How the generated mapping differs from the one on the cluster
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct MappingDiff {
+    pub index: String,
+    // Only in the generated mapping, can be added with a mapping update
+    pub added: Vec<FieldDefinition>,
+    // Only on the cluster
+    pub removed: Vec<FieldDefinition>,
+    // In both with a different type or options, needs a new index
+    pub changed: Vec<FieldChange>,
+}
+
+impl MappingDiff {
+    pub fn between(index: &str, live: &Value, generated: &Value) -> Self {
+        let live = normalize(live);
+        let generated = normalize(generated);
+        let mut diff = MappingDiff {
+            index: index.to_string(),
+            ..MappingDiff::default()
+        };
+
+        for (field, definition) in &generated {
+            match live.get(field) {
+                None => diff.added.push(FieldDefinition {
+                    field: field.clone(),
+                    definition: definition.clone(),
+                }),
+                Some(existing) if existing != definition => diff.changed.push(FieldChange {
+                    field: field.clone(),
+                    live: existing.clone(),
+                    generated: definition.clone(),
+                }),
+                Some(_) => {}
+            }
+        }
+        for (field, definition) in &live {
+            if !generated.contains_key(field) {
+                diff.removed.push(FieldDefinition {
+                    field: field.clone(),
+                    definition: definition.clone(),
+                });
+            }
+        }
+
+        diff
+    }
+
+    pub fn is_breaking(&self) -> bool {
+        !self.removed.is_empty() || !self.changed.is_empty()
+    }
+}
+
+impl CommandReport for MappingDiff {
+    fn print_human(&self) {
+        if self.added.is_empty() && !self.is_breaking() {
+            println!("Mapping of {} matches the generated mapping", self.index);
+            return;
+        }
+
+        if !self.added.is_empty() {
+            println!("Added fields:");
+            for field in &self.added {
+                println!("  + {:<30} {}", field.field, field.definition);
+            }
+        }
+        if !self.removed.is_empty() {
+            println!("Removed fields:");
+            for field in &self.removed {
+                println!("  - {:<30} {}", field.field, field.definition);
+            }
+        }
+        if !self.changed.is_empty() {
+            println!("Changed fields:");
+            for change in &self.changed {
+                println!(
+                    "  ~ {:<30} {} -> {}",
+                    change.field, change.live, change.generated
+                );
+            }
+        }
+    }
+
+    // 0 when identical, 1 when a mapping update is enough, 2 when the index
+    // has to be rebuilt
+    fn exit_code(&self) -> i32 {
+        if self.is_breaking() {
+            2
+        } else if !self.added.is_empty() {
+            1
+        } else {
+            0
+        }
+    }
+}
@@ -0,0 +1,390 @@
+use clap::{Args, ValueEnum};
+use reqwest::{Client, Method, RequestBuilder, StatusCode};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Duration;
+
+use crate::backend::{BulkDocument, BulkItemResult, SearchBackend};
+
+// Tasks are polled this often at first, backing off to TASK_POLL_MAX
+const TASK_POLL_START: Duration = Duration::from_millis(20);
+const TASK_POLL_MAX: Duration = Duration::from_millis(500);
+
+// Meilisearch ranks matches in earlier attributes higher, so the name
+// comes before the places it is in. Text fields not listed go last.
+const SEARCHABLE_ORDER: [&str; 6] = [
+    "name",
+    "ascii_name",
+    "display_name",
+    "alternate_names",
+    "admin1",
+    "admin2",
+];
+
+// Which search engine `seed` writes to
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BackendKind {
+    #[default]
+    Elasticsearch,
+    Meilisearch,
+}
+
+// Connection flags for `--backend meilisearch`
+#[derive(Args, Debug, Clone)]
+pub struct MeiliArgs {
+    /// Meilisearch URL, for --backend meilisearch
+    #[clap(long, default_value = "http://localhost:7700")]
+    pub meili_url: String,
+
+    /// Meilisearch API key, also read from MEILI_MASTER_KEY
+    #[clap(long, env = "MEILI_MASTER_KEY", hide_env_values = true)]
+    pub meili_key: Option<String>,
+}
+
+// Searchable, filterable and sortable attributes doing what the
+// Elasticsearch mapping does: text is searched, keywords and numbers
+// filtered, and the geo point becomes `_geo`. Dates and completion fields
+// have no counterpart.
+pub fn settings_from_mapping(mapping: &Value) -> Value {
+    let (mut searchable, mut filterable, mut sortable) = (Vec::new(), vec!["id"], Vec::new());
+    if let Some(properties) = mapping["properties"].as_object() {
+        for (field, spec) in properties {
+            match spec["type"].as_str().unwrap_or_default() {
+                "text" => searchable.push(field.as_str()),
+                "keyword" => filterable.push(field.as_str()),
+                "geo_point" => {
+                    filterable.push("_geo");
+                    sortable.push("_geo");
+                }
+                "integer" | "long" | "unsigned_long" | "float" | "double" => {
+                    filterable.push(field.as_str());
+                    sortable.push(field.as_str());
+                }
+                _ => {}
+            }
+        }
+    }
+    searchable.sort_by_key(|field| {
+        SEARCHABLE_ORDER
+            .iter()
+            .position(|known| known == field)
+            .unwrap_or(SEARCHABLE_ORDER.len())
+    });
+
+    json!({
+        "searchableAttributes": searchable,
+        "filterableAttributes": filterable,
+        "sortableAttributes": sortable,
+    })
+}
+
+// The Elasticsearch document with its id as the primary key, the
+// `[lon, lat]` location as `_geo` and the completion input left out
+pub fn meili_document(document: BulkDocument) -> Value {
+    let mut body = match document.body {
+        Value::Object(body) => body,
+        _ => Map::new(),
+    };
+    if let Some(location) = body.remove("location") {
+        if let (Some(lng), Some(lat)) = (location[0].as_f64(), location[1].as_f64()) {
+            body.insert("_geo".to_string(), json!({"lat": lat, "lng": lng}));
+        }
+    }
+    body.remove("suggest");
+    body.insert("id".to_string(), json!(document.id));
+    Value::Object(body)
+}
+
+// Status a failed task's documents are reported with, so that Meilisearch
+// having trouble is retried and a bad document is not
+fn task_status(task: &Value) -> u16 {
+    match task["status"].as_str() {
+        Some("succeeded") => 200,
+        _ => match task["error"]["type"].as_str() {
+            Some("internal") | Some("system") => 500,
+            _ => 400,
+        },
+    }
+}
+
+pub struct MeilisearchBackend {
+    client: Client,
+    url: String,
+    key: Option<String>,
+}
+
+impl MeilisearchBackend {
+    // Check the instance answers and accepts the key before anything is sent
+    pub async fn connect(args: &MeiliArgs) -> Result<Self, Box<dyn Error>> {
+        let backend = MeilisearchBackend {
+            client: Client::new(),
+            url: args.meili_url.trim_end_matches('/').to_string(),
+            key: args.meili_key.clone(),
+        };
+        let response = backend.request(Method::GET, "/version").send().await?;
+        match response.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Err(format!("authentication failed against {}", backend.url).into())
+            }
+            status if !status.is_success() => {
+                Err(format!("{} answered {}", backend.url, status).into())
+            }
+            _ => Ok(backend),
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self.client.request(method, format!("{}{}", self.url, path));
+        match &self.key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        }
+    }
+
+    // Poll a task until it is done, failed or not
+    async fn wait_for(&self, uid: u64) -> Result<Value, Box<dyn Error>> {
+        let mut delay = TASK_POLL_START;
+        loop {
+            let task = self
+                .request(Method::GET, &format!("/tasks/{}", uid))
+                .send()
+                .await?
+                .json::<Value>()
+                .await?;
+            if matches!(
+                task["status"].as_str(),
+                Some("succeeded" | "failed" | "canceled")
+            ) {
+                tracing::debug!("Task {} {}", uid, task["status"]);
+                return Ok(task);
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(TASK_POLL_MAX);
+        }
+    }
+
+    // Changes are queued as tasks, send `request` and wait for its task to
+    // succeed
+    async fn run_task(&self, request: RequestBuilder, what: &str) -> Result<(), Box<dyn Error>> {
+        let response = request.send().await?;
+        let status = response.status();
+        let body = response.json::<Value>().await?;
+        if !status.is_success() {
+            return Err(format!("Could not {}: {}", what, body["message"]).into());
+        }
+        let uid = body["taskUid"]
+            .as_u64()
+            .ok_or("Meilisearch did not return a task")?;
+
+        let task = self.wait_for(uid).await?;
+        match task["status"].as_str() {
+            Some("succeeded") => Ok(()),
+            _ => Err(format!("Could not {}: {}", what, task["error"]["message"]).into()),
+        }
+    }
+
+    // A result per document from the task that wrote them, Meilisearch
+    // accepts or fails a batch as a whole
+    async fn write_documents(
+        &self,
+        method: Method,
+        index: &str,
+        ids: Vec<String>,
+        documents: Vec<Value>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        let response = self
+            .request(method.clone(), &format!("/indexes/{}/documents", index))
+            .query(&[("primaryKey", "id")])
+            .json(&documents)
+            .send()
+            .await?;
+        let status = response.status();
+        let body = response.json::<Value>().await.unwrap_or_default();
+        tracing::debug!(
+            "{} of {} documents to {} answered {}",
+            method,
+            ids.len(),
+            index,
+            status
+        );
+
+        let (status, error) = match (status.is_success(), body["taskUid"].as_u64()) {
+            (true, Some(uid)) => {
+                let task = self.wait_for(uid).await?;
+                let status = task_status(&task);
+                (status, (status != 200).then(|| task["error"].clone()))
+            }
+            (true, None) => return Err("Meilisearch did not return a task".into()),
+            // A rejected request, such as a payload too large, fails every
+            // document with its status
+            (false, _) => (status.as_u16(), Some(body)),
+        };
+        Ok(ids
+            .into_iter()
+            .map(|id| BulkItemResult {
+                id,
+                status,
+                error: error.clone(),
+            })
+            .collect())
+    }
+}
+
+impl SearchBackend for MeilisearchBackend {
+    async fn ensure_index(&self, index: &str) -> Result<bool, Box<dyn Error>> {
+        let response = self
+            .request(Method::GET, &format!("/indexes/{}", index))
+            .send()
+            .await?;
+        if response.status() != StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+
+        self.run_task(
+            self.request(Method::POST, "/indexes")
+                .json(&json!({"uid": index, "primaryKey": "id"})),
+            &format!("create index {}", index),
+        )
+        .await?;
+        Ok(true)
+    }
+
+    async fn put_mapping(&self, index: &str, mapping: Value) -> Result<(), Box<dyn Error>> {
+        self.run_task(
+            self.request(Method::PATCH, &format!("/indexes/{}/settings", index))
+                .json(&settings_from_mapping(&mapping)),
+            &format!("update settings for index {}", index),
+        )
+        .await
+    }
+
+    async fn delete_index(&self, index: &str) -> Result<(), Box<dyn Error>> {
+        self.run_task(
+            self.request(Method::DELETE, &format!("/indexes/{}", index)),
+            &format!("delete index {}", index),
+        )
+        .await
+    }
+
+    async fn bulk(
+        &self,
+        index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        let ids: Vec<String> = documents.iter().map(|doc| doc.id.clone()).collect();
+        let documents = documents.into_iter().map(meili_document).collect();
+        self.write_documents(Method::POST, index, ids, documents)
+            .await
+    }
+
+    // PUT merges into documents, but would also create the missing ones, so
+    // those are looked up first and reported as not found like Elasticsearch
+    // does
+    async fn bulk_update(
+        &self,
+        index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        let ids: Vec<String> = documents.iter().map(|doc| doc.id.clone()).collect();
+        let existing = self.get_fields(index, &ids, &["id"]).await?;
+        let (present, missing): (Vec<_>, Vec<_>) = documents
+            .into_iter()
+            .partition(|doc| existing.contains_key(&doc.id));
+
+        let present_ids = present.iter().map(|doc| doc.id.clone()).collect();
+        let updates = present.into_iter().map(meili_document).collect();
+        let mut results: HashMap<String, BulkItemResult> = self
+            .write_documents(Method::PUT, index, present_ids, updates)
+            .await?
+            .into_iter()
+            .map(|result| (result.id.clone(), result))
+            .collect();
+        for doc in missing {
+            results.insert(
+                doc.id.clone(),
+                BulkItemResult {
+                    id: doc.id,
+                    status: 404,
+                    error: Some(json!({"type": "document_missing_exception"})),
+                },
+            );
+        }
+
+        Ok(ids
+            .into_iter()
+            .filter_map(|id| results.remove(&id))
+            .collect())
+    }
+
+    async fn get_fields(
+        &self,
+        index: &str,
+        ids: &[String],
+        fields: &[&str],
+    ) -> Result<HashMap<String, Value>, Box<dyn Error>> {
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let mut requested: Vec<&str> = fields.to_vec();
+        requested.push("id");
+        let filter = format!(
+            "id IN [{}]",
+            ids.iter()
+                .map(|id| format!("{:?}", id))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        let response = self
+            .request(Method::POST, &format!("/indexes/{}/documents/fetch", index))
+            .json(&json!({"filter": filter, "fields": requested, "limit": ids.len()}))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "Could not fetch documents from {}: {}",
+                index,
+                response.status()
+            )
+            .into());
+        }
+
+        let body = response.json::<Value>().await?;
+        Ok(body["results"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|mut doc| {
+                let id = doc["id"].as_str()?.to_string();
+                if !fields.contains(&"id") {
+                    doc.as_object_mut()?.remove("id");
+                }
+                Some((id, doc))
+            })
+            .collect())
+    }
+
+    async fn count(&self, index: &str) -> Result<u64, Box<dyn Error>> {
+        let body = self
+            .request(Method::GET, &format!("/indexes/{}/stats", index))
+            .send()
+            .await?
+            .json::<Value>()
+            .await?;
+        body["numberOfDocuments"]
+            .as_u64()
+            .ok_or_else(|| format!("Could not count documents in {}", index).into())
+    }
+
+    // Writes are waited for as tasks, there is nothing left to flush
+    async fn refresh(&self, _index: &str) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    // A Meilisearch instance is a single node
+    async fn data_nodes(&self) -> Result<u64, Box<dyn Error>> {
+        Ok(1)
+    }
+}
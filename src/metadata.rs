@@ -1,8 +1,12 @@
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
 use std::fs::{self, canonicalize, read_dir};
 use std::io;
 use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::{fs::ReadDir, path::PathBuf};
 // use toml;
 
@@ -25,37 +29,364 @@ pub struct ProjectSite {
     pub name: String,
     pub source: PathBuf,
     pub site_type: SiteType,
+    // Names of sites that have to be built before this one
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    // Cargo features trunk builds the site with when --features is not given
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub build: BuildConfig,
+    #[serde(default)]
+    pub deploy: DeployConfig,
+    // Directories, relative to the site, copied as they are into the root of
+    // the deployed dist, such as static/ with robots.txt
+    #[serde(default)]
+    pub extra_static: Vec<PathBuf>,
+    // Globs of files never deployed, on top of DEFAULT_IGNORE, such as *.map
+    #[serde(default)]
+    pub ignore: Vec<String>,
 }
 
-pub fn load_metadata(root: &Path) -> Result<Metadata, Box<dyn Error>> {
-    let root = discover_single(root)?;
+// The `build` table of a site
+#[derive(Deserialize, Default)]
+pub struct BuildConfig {
+    // Environment variables set for the build, values may use $VAR or ${VAR}
+    #[serde(rename = "env", alias = "build_env")]
+    pub build_env: Option<HashMap<String, String>>,
+    // Where the site's assets are served from, passed to trunk as
+    // --public-url, DEFAULT_PUBLIC_URL when not set
+    pub public_url: Option<String>,
+}
+
+// The `deploy` table of a site. Static sites may be served from object
+// storage instead of the ssh server, API sites name where their binary runs.
+#[derive(Deserialize, Default)]
+pub struct DeployConfig {
+    // s3://bucket/prefix
+    pub target: Option<String>,
+    // S3 compatible endpoint such as R2 or MinIO, may use $VAR or ${VAR}
+    pub endpoint: Option<String>,
+    // ssh host an API site is deployed to, DEFAULT_API_SERVER when not set
+    pub server: Option<String>,
+    // Cargo binary of an API site, the site name when not set
+    pub binary: Option<String>,
+    // systemd unit restarted after the binary is replaced, the site name
+    // when not set
+    pub service: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum MetadataError {
+    Io(io::Error),
+    Parse {
+        path: PathBuf,
+        // Line and column, counting from 1, when the error points somewhere
+        position: Option<(usize, usize)>,
+        message: String,
+    },
+    CyclicDependency(Vec<String>),
+    UnknownDependency {
+        site: String,
+        dependency: String,
+    },
+    // Sites with the directory their source was looked for in
+    MissingSources(Vec<(String, PathBuf)>),
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataError::Io(err) => write!(f, "{}", err),
+            MetadataError::Parse {
+                path,
+                position: Some((line, column)),
+                message,
+            } => write!(
+                f,
+                "invalid project toml {}:{}:{}: {}",
+                path.display(),
+                line,
+                column,
+                message
+            ),
+            MetadataError::Parse { path, message, .. } => {
+                write!(f, "invalid project toml {}: {}", path.display(), message)
+            }
+            MetadataError::CyclicDependency(cycle) => {
+                write!(f, "sites depend on each other: {}", cycle.join(" -> "))
+            }
+            MetadataError::UnknownDependency { site, dependency } => {
+                write!(f, "site {} depends on unknown site {}", site, dependency)
+            }
+            MetadataError::MissingSources(sites) => {
+                let sites: Vec<String> = sites
+                    .iter()
+                    .map(|(site, source)| format!("{} ({})", site, source.display()))
+                    .collect();
+                write!(f, "site sources do not exist: {}", sites.join(", "))
+            }
+        }
+    }
+}
+
+impl Error for MetadataError {}
+
+impl From<io::Error> for MetadataError {
+    fn from(err: io::Error) -> Self {
+        MetadataError::Io(err)
+    }
+}
+
+impl MetadataError {
+    pub(crate) fn parse(path: &Path, file: &str, err: toml::de::Error) -> Self {
+        let position = err.span().map(|span| {
+            let before = &file[..span.start.min(file.len())];
+            let line = before.matches('\n').count() + 1;
+            let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+            (line, column)
+        });
+        MetadataError::Parse {
+            path: path.to_path_buf(),
+            position,
+            message: err.message().trim_end().to_string(),
+        }
+    }
+}
+
+// The last project loaded through `cached_load_metadata`, with the root it
+// was loaded from. The OnceLock sits behind a Mutex so it can be invalidated.
+static METADATA_CACHE: Mutex<OnceLock<(PathBuf, Arc<Metadata>)>> = Mutex::new(OnceLock::new());
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    InProgress,
+    Done,
+}
+
+impl Metadata {
+    // Where the sites live, `source_dir` or else the directory of the project
+    // toml
+    pub fn project_dir(&self, project_toml: &Path) -> PathBuf {
+        self.source_dir.clone().unwrap_or_else(|| {
+            project_toml
+                .parent()
+                .unwrap_or(Path::new("."))
+                .to_path_buf()
+        })
+    }
+
+    // Every site's `source` has to exist under the project directory
+    pub fn check_sources(&self, project_toml: &Path) -> Result<(), MetadataError> {
+        let project_dir = self.project_dir(project_toml);
+        let missing: Vec<(String, PathBuf)> = self
+            .sites
+            .iter()
+            .map(|site| (site.name.clone(), project_dir.join(&site.source)))
+            .filter(|(_, source)| !source.exists())
+            .collect();
+        match missing.is_empty() {
+            true => Ok(()),
+            false => Err(MetadataError::MissingSources(missing)),
+        }
+    }
+
+    pub fn site(&self, name: &str) -> Option<&ProjectSite> {
+        self.sites.iter().find(|site| site.name == name)
+    }
+
+    // Depth first walk of depends_on, failing on the first cycle found
+    pub fn check_dependencies(&self) -> Result<(), MetadataError> {
+        let sites: HashMap<&str, &ProjectSite> = self
+            .sites
+            .iter()
+            .map(|site| (site.name.as_str(), site))
+            .collect();
+
+        fn visit<'a>(
+            name: &'a str,
+            sites: &HashMap<&'a str, &'a ProjectSite>,
+            state: &mut HashMap<&'a str, Visit>,
+            path: &mut Vec<&'a str>,
+        ) -> Result<(), MetadataError> {
+            match state.get(name) {
+                Some(Visit::Done) => return Ok(()),
+                Some(Visit::InProgress) => {
+                    let start = path.iter().position(|n| *n == name).unwrap_or(0);
+                    let mut cycle: Vec<String> =
+                        path[start..].iter().map(|n| n.to_string()).collect();
+                    cycle.push(name.to_string());
+                    return Err(MetadataError::CyclicDependency(cycle));
+                }
+                None => {}
+            }
+
+            state.insert(name, Visit::InProgress);
+            path.push(name);
+            for dependency in &sites[name].depends_on {
+                if !sites.contains_key(dependency.as_str()) {
+                    return Err(MetadataError::UnknownDependency {
+                        site: name.to_string(),
+                        dependency: dependency.clone(),
+                    });
+                }
+                visit(dependency, sites, state, path)?;
+            }
+            path.pop();
+            state.insert(name, Visit::Done);
+
+            Ok(())
+        }
+
+        let mut state = HashMap::new();
+        for site in &self.sites {
+            visit(&site.name, &sites, &mut state, &mut Vec::new())?;
+        }
+
+        Ok(())
+    }
+
+    // Graphviz DOT of the sites, with an edge from each site to the sites it
+    // depends on
+    pub fn to_dot_graph(&self) -> Result<String, MetadataError> {
+        self.check_dependencies()?;
+
+        let mut dot = String::from("digraph sites {\n    rankdir=LR;\n");
+        for site in &self.sites {
+            let shape = match site.site_type {
+                SiteType::Static => "box",
+                SiteType::Api => "ellipse",
+            };
+            dot.push_str(&format!("    \"{}\" [shape={}];\n", site.name, shape));
+        }
+        for site in &self.sites {
+            for dependency in &site.depends_on {
+                dot.push_str(&format!("    \"{}\" -> \"{}\";\n", site.name, dependency));
+            }
+        }
+        dot.push_str("}\n");
+
+        Ok(dot)
+    }
+}
+
+pub fn load_metadata(root: &Path) -> Result<Metadata, MetadataError> {
+    let root = discover_single(root, None)?;
     let file = fs::read_to_string(&root)?;
 
-    let parsed_toml = toml::from_str::<Metadata>(&file)?;
+    let parsed_toml =
+        toml::from_str::<Metadata>(&file).map_err(|err| MetadataError::parse(&root, &file, err))?;
+    parsed_toml.check_sources(&root)?;
 
     Ok(parsed_toml)
 }
 
-// Find and load .cat.toml project metadata
-pub fn discover_single(path: &Path) -> Result<PathBuf, io::Error> {
-    let mut candidates = discover_project_toml(path)?;
-    let res = match candidates.pop() {
-        None => panic!("No project toml found"),
-        Some(it) => it,
+// `load_metadata`, parsed once per root and shared until invalidated
+pub fn cached_load_metadata(root: &Path) -> Result<Arc<Metadata>, MetadataError> {
+    let mut cache = METADATA_CACHE.lock().unwrap();
+    if let Some((cached_root, metadata)) = cache.get() {
+        if cached_root == root {
+            return Ok(Arc::clone(metadata));
+        }
+    }
+
+    let metadata = Arc::new(load_metadata(root)?);
+    *cache = OnceLock::from((root.to_path_buf(), Arc::clone(&metadata)));
+
+    Ok(metadata)
+}
+
+// Drop the cached project so the next load reads .cat.toml again, e.g. after
+// it changed on disk
+pub fn invalidate_metadata_cache() {
+    METADATA_CACHE.lock().unwrap().take();
+}
+
+// Find and load .cat.toml project metadata, failing when there is none or
+// more than one to choose from. `project` picks among several by a site or
+// directory name.
+pub fn discover_single(path: &Path, project: Option<&str>) -> Result<PathBuf, io::Error> {
+    let found = discover_project_toml(path)?;
+    let mut candidates = match project {
+        Some(project) => select_project(&found, project),
+        None => found.clone(),
     };
+    if let (Some(project), true, false) = (project, candidates.is_empty(), found.is_empty()) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "No project named {} under {}, found:\n{}",
+                project,
+                path.display(),
+                describe_projects(&found)
+            ),
+        ));
+    }
+    if candidates.len() > 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "More than one project found under {}, pick one with --project <name> or --project-toml:\n{}",
+                path.display(),
+                describe_projects(&candidates)
+            ),
+        ));
+    }
+
+    candidates.pop().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "No .cat.toml found in {}, its parents or the directories below it",
+                path.display()
+            ),
+        )
+    })
+}
 
-    if !candidates.is_empty() {
-        panic!("more than one project found");
+// Names a project answers to for --project: its directory and its sites
+fn project_names(path: &Path) -> Vec<String> {
+    let mut names: Vec<String> = path
+        .parent()
+        .and_then(Path::file_name)
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .into_iter()
+        .collect();
+    // One that does not parse still matches by directory
+    if let Some(metadata) = fs::read_to_string(path)
+        .ok()
+        .and_then(|file| toml::from_str::<Metadata>(&file).ok())
+    {
+        names.extend(metadata.sites.into_iter().map(|site| site.name));
     }
+    names
+}
 
-    Ok(res)
+// The candidates `project` is the directory or a site name of
+pub fn select_project(candidates: &[PathBuf], project: &str) -> Vec<PathBuf> {
+    candidates
+        .iter()
+        .filter(|path| project_names(path).iter().any(|name| name == project))
+        .cloned()
+        .collect()
 }
 
-fn discover_project_toml(path: &Path) -> std::io::Result<Vec<PathBuf>> {
-    return find_project_toml(path)?
+// One line per project, sorted, with the names it can be picked by
+fn describe_projects(candidates: &[PathBuf]) -> String {
+    let mut lines: Vec<String> = candidates
+        .iter()
+        .map(|path| format!("  {} ({})", path.display(), project_names(path).join(", ")))
+        .collect();
+    lines.sort();
+    lines.join("\n")
+}
+
+// All candidate project tomls for a path, without requiring exactly one
+pub fn discover_project_toml(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    find_project_toml(path)?
         .into_iter()
         .map(|path| path.canonicalize())
-        .collect();
+        .collect()
 }
 
 fn find_project_toml(path: &Path) -> std::io::Result<Vec<PathBuf>> {
@@ -66,7 +397,7 @@ fn find_project_toml(path: &Path) -> std::io::Result<Vec<PathBuf>> {
 }
 
 fn find_in_parent_dirs(path: &Path, file_name: &str) -> Option<PathBuf> {
-    if path.file_name().unwrap_or_default() == file_name && path.is_file() {
+    if path.file_name() == Some(OsStr::new(file_name)) && path.is_file() {
         return Some(path.to_path_buf());
     }
 
@@ -93,3 +424,37 @@ fn find_toml_in_child_dir(entities: ReadDir) -> Vec<PathBuf> {
         .filter_map(|it| it.ok())
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE_SITE: &str = "[[sites]]\nname = \"web\"\nsource = \"web\"\nsite_type = \"static\"\n";
+
+    #[test]
+    fn cached_metadata_is_reread_after_invalidating() {
+        let dir = std::env::temp_dir().join(format!("admin-{}-metadata-cache", std::process::id()));
+        fs::create_dir_all(dir.join("web")).unwrap();
+        fs::create_dir_all(dir.join("api")).unwrap();
+        let project_toml = dir.join(".cat.toml");
+        fs::write(&project_toml, ONE_SITE).unwrap();
+
+        let first = cached_load_metadata(&project_toml).unwrap();
+        assert_eq!(first.sites.len(), 1);
+
+        let two_sites = format!(
+            "{}[[sites]]\nname = \"api\"\nsource = \"api\"\nsite_type = \"api\"\n",
+            ONE_SITE
+        );
+        fs::write(&project_toml, two_sites).unwrap();
+        let cached = cached_load_metadata(&project_toml).unwrap();
+        assert!(Arc::ptr_eq(&first, &cached));
+        assert_eq!(cached.sites.len(), 1);
+
+        invalidate_metadata_cache();
+        let reloaded = cached_load_metadata(&project_toml).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(!Arc::ptr_eq(&first, &reloaded));
+        assert_eq!(reloaded.sites.len(), 2);
+    }
+}
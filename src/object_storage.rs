@@ -0,0 +1,270 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+use crate::cancel::CancelToken;
+use crate::deploy::{expand_env_vars, walk_files, DeployError, Transfer};
+use crate::status;
+
+// Object under the prefix recording the checksum of every uploaded file, so
+// unchanged files can be skipped on the next deploy
+pub const MANIFEST_KEY: &str = ".deploy-manifest.json";
+
+// Trunk puts content hashed files in assets/, they never change under the
+// same name
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+const MUTABLE_CACHE_CONTROL: &str = "no-cache";
+
+// `s3://bucket/prefix` from a site's `deploy.target`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectTarget {
+    pub bucket: String,
+    pub prefix: String,
+}
+
+impl ObjectTarget {
+    pub fn parse(target: &str) -> Option<Self> {
+        let rest = target.strip_prefix("s3://")?;
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return None;
+        }
+
+        Some(ObjectTarget {
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+        })
+    }
+
+    pub fn key(&self, path: &str) -> String {
+        match self.prefix.is_empty() {
+            true => path.to_string(),
+            false => format!("{}/{}", self.prefix, path),
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("s3://{}/{}", self.bucket, self.key(path))
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct RemoteManifest {
+    // Path relative to the prefix to the sha256 of its contents
+    files: BTreeMap<String, String>,
+}
+
+pub struct ObjectSync {
+    pub uploaded: Vec<String>,
+    pub unchanged: usize,
+    pub deleted: Vec<String>,
+    pub transfer: Transfer,
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    match path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase()
+        .as_str()
+    {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "wasm" => "application/wasm",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+pub fn cache_control(relative: &str) -> &'static str {
+    match relative.starts_with("assets/") {
+        true => IMMUTABLE_CACHE_CONTROL,
+        false => MUTABLE_CACHE_CONTROL,
+    }
+}
+
+fn staged_files(dir: &Path) -> Result<Vec<(String, PathBuf)>, DeployError> {
+    let mut files = Vec::new();
+    walk_files(dir, dir, &mut HashSet::new(), &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+// aws CLI invocation for the target, credentials come from the usual AWS_*
+// environment variables or profile
+fn aws(endpoint: Option<&str>) -> Command {
+    let mut cmd = Command::new("aws");
+    if let Some(endpoint) = endpoint {
+        cmd.arg("--endpoint-url").arg(expand_env_vars(endpoint));
+    }
+    cmd
+}
+
+fn run(mut cmd: Command) -> Result<Vec<u8>, DeployError> {
+    let output = cmd.stderr(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(DeployError::CommandFailed {
+            command: format!("{:?}", cmd),
+            status: output.status,
+        });
+    }
+    Ok(output.stdout)
+}
+
+// The manifest of the previous deploy, empty when there was none
+fn load_manifest(target: &ObjectTarget, endpoint: Option<&str>) -> RemoteManifest {
+    let mut cmd = aws(endpoint);
+    cmd.args(["s3", "cp", "--quiet", &target.url(MANIFEST_KEY), "-"])
+        .stderr(Stdio::null());
+    match cmd.output() {
+        Ok(output) if output.status.success() => {
+            serde_json::from_slice(&output.stdout).unwrap_or_default()
+        }
+        _ => RemoteManifest::default(),
+    }
+}
+
+fn list_keys(target: &ObjectTarget, endpoint: Option<&str>) -> Result<Vec<String>, DeployError> {
+    let mut cmd = aws(endpoint);
+    cmd.args(["s3api", "list-objects-v2", "--bucket", &target.bucket])
+        .args(["--output", "json"]);
+    if !target.prefix.is_empty() {
+        cmd.arg("--prefix").arg(format!("{}/", target.prefix));
+    }
+    let stdout = run(cmd)?;
+    // Nothing is printed for an empty listing
+    let listing: Value = serde_json::from_slice(&stdout).unwrap_or_default();
+
+    Ok(listing["Contents"]
+        .as_array()
+        .map(|objects| {
+            objects
+                .iter()
+                .filter_map(|object| object["Key"].as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+// Upload the staged dist directory to the bucket, skipping files whose
+// checksum matches the previous deploy's manifest. With `delete`, objects
+// under the prefix that are not part of this deploy are removed.
+pub fn sync_to_bucket(
+    dist_dir: &Path,
+    target: &ObjectTarget,
+    endpoint: Option<&str>,
+    delete: bool,
+    cancel: &CancelToken,
+) -> Result<ObjectSync, Box<dyn Error>> {
+    let started = Instant::now();
+    let previous = load_manifest(target, endpoint);
+    let mut manifest = RemoteManifest::default();
+    let mut sync = ObjectSync {
+        uploaded: Vec::new(),
+        unchanged: 0,
+        deleted: Vec::new(),
+        transfer: Transfer {
+            duration_ms: 0,
+            bytes: 0,
+        },
+    };
+
+    for (relative, path) in staged_files(dist_dir)? {
+        if cancel.is_cancelled() {
+            return Err(format!(
+                "Upload to {} interrupted, {} files were uploaded",
+                target.url(""),
+                sync.uploaded.len()
+            )
+            .into());
+        }
+
+        let contents = fs::read(&path)?;
+        let checksum = format!("{:x}", Sha256::digest(&contents));
+        if previous.files.get(&relative) == Some(&checksum) {
+            sync.unchanged += 1;
+            manifest.files.insert(relative, checksum);
+            continue;
+        }
+
+        status!("Uploading {}", relative);
+        let mut cmd = aws(endpoint);
+        cmd.args(["s3", "cp", "--quiet"])
+            .arg(&path)
+            .arg(target.url(&relative))
+            .args(["--content-type", content_type(&path)])
+            .args(["--cache-control", cache_control(&relative)]);
+        run(cmd)?;
+
+        sync.transfer.bytes += contents.len() as u64;
+        sync.uploaded.push(relative.clone());
+        manifest.files.insert(relative, checksum);
+    }
+
+    if delete {
+        let manifest_key = target.key(MANIFEST_KEY);
+        for key in list_keys(target, endpoint)? {
+            let relative = match target.prefix.is_empty() {
+                true => key.as_str(),
+                false => key
+                    .strip_prefix(&format!("{}/", target.prefix))
+                    .unwrap_or(&key),
+            };
+            if key == manifest_key || manifest.files.contains_key(relative) {
+                continue;
+            }
+
+            status!("Deleting {}", key);
+            let mut cmd = aws(endpoint);
+            cmd.args(["s3", "rm", "--quiet"])
+                .arg(format!("s3://{}/{}", target.bucket, key));
+            run(cmd)?;
+            sync.deleted.push(key);
+        }
+    }
+
+    // Written last, so an interrupted deploy re-uploads what it missed
+    let mut cmd = aws(endpoint);
+    cmd.args(["s3", "cp", "--quiet", "-", &target.url(MANIFEST_KEY)])
+        .args(["--content-type", "application/json"])
+        .args(["--cache-control", MUTABLE_CACHE_CONTROL])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null());
+    let mut child = cmd.spawn()?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(serde_json::to_string_pretty(&manifest)?.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(DeployError::CommandFailed {
+            command: format!("aws s3 cp - {}", target.url(MANIFEST_KEY)),
+            status,
+        }
+        .into());
+    }
+
+    sync.transfer.duration_ms = started.elapsed().as_millis() as u64;
+    Ok(sync)
+}
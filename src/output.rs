@@ -0,0 +1,74 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use std::error::Error;
+use std::sync::OnceLock;
+
+// How a command presents its results
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+// Set once at startup from --output-format
+pub fn set_format(format: OutputFormat) {
+    let _ = FORMAT.set(format);
+}
+
+pub fn format() -> OutputFormat {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+// Status messages are info level events, which go to stderr so stdout only
+// ever holds the command result
+pub fn status_line(message: &str) {
+    tracing::info!("{}", message);
+}
+
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        $crate::output::status_line(&format!($($arg)*))
+    };
+}
+
+// The result of a command. Each command builds one and hands it to `emit`,
+// which either prints it for humans or serializes it as JSON on stdout.
+pub trait CommandReport: Serialize {
+    fn print_human(&self);
+
+    // Process exit code for the result, non-zero when the command should
+    // fail a pipeline even though it ran to completion
+    fn exit_code(&self) -> i32 {
+        0
+    }
+}
+
+pub fn emit<R: CommandReport>(report: &R) -> Result<i32, Box<dyn Error>> {
+    match format() {
+        OutputFormat::Human => report.print_human(),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(report)?),
+    }
+
+    Ok(report.exit_code())
+}
+
+// Compare the JSON a report is emitted as with tests/golden/<name>.json.
+// UPDATE_GOLDEN=1 rewrites the file instead, for a deliberate change.
+#[cfg(test)]
+pub(crate) fn assert_golden<R: CommandReport>(name: &str, report: &R) {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.json", name));
+    let json = serde_json::to_string_pretty(report).unwrap() + "\n";
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, &json).unwrap();
+        return;
+    }
+    let golden = std::fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("Could not read {}: {}", path.display(), err));
+    assert_eq!(json, golden, "{} no longer matches", path.display());
+}
@@ -0,0 +1,340 @@
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio_postgres::{Client, NoTls};
+
+use crate::backend::{BulkDocument, BulkItemResult, SearchBackend};
+
+// Every index seeded goes into the one table
+const TABLE: &str = "geonames";
+
+// Document fields stored as they are, besides the id and the location
+const COLUMNS: [&str; 12] = [
+    "name",
+    "ascii_name",
+    "alternate_names",
+    "country_code",
+    "admin1",
+    "admin2",
+    "feature_class",
+    "feature_code",
+    "population",
+    "elevation",
+    "timezone",
+    "modification_date",
+];
+
+fn create_table(postgis: bool) -> String {
+    let location = match postgis {
+        true => "location geography(Point, 4326)",
+        false => "latitude DOUBLE PRECISION,\n    longitude DOUBLE PRECISION",
+    };
+    format!(
+        "CREATE TABLE {} (
+    id BIGINT PRIMARY KEY,
+    name TEXT,
+    ascii_name TEXT,
+    alternate_names TEXT[],
+    country_code TEXT,
+    admin1 TEXT,
+    admin2 TEXT,
+    feature_class TEXT,
+    feature_code TEXT,
+    population BIGINT,
+    elevation INTEGER,
+    timezone TEXT,
+    modification_date DATE,
+    {}
+)",
+        TABLE, location
+    )
+}
+
+// The columns of a document that it has, keyed like the table so
+// jsonb_populate_recordset fills them in. The `[lon, lat]` location becomes
+// an EWKT point, which the geography column parses, or latitude and
+// longitude.
+fn row(doc: &BulkDocument, postgis: bool) -> Value {
+    let mut row: Map<String, Value> = COLUMNS
+        .iter()
+        .filter_map(|&column| {
+            doc.body
+                .get(column)
+                .map(|value| (column.to_string(), value.clone()))
+        })
+        .collect();
+    row.insert("id".to_string(), json!(doc.id.parse::<i64>().ok()));
+    if let Some(location) = doc.body.get("location") {
+        match postgis {
+            true => {
+                row.insert(
+                    "location".to_string(),
+                    json!(format!("SRID=4326;POINT({} {})", location[0], location[1])),
+                );
+            }
+            false => {
+                row.insert("latitude".to_string(), location[1].clone());
+                row.insert("longitude".to_string(), location[0].clone());
+            }
+        }
+    }
+    Value::Object(row)
+}
+
+// Seeds a `geonames` table instead of a cluster, with a geography column when
+// the database has PostGIS. Rows are upserted by id, so seeding again
+// refreshes them.
+pub struct PostgresBackend {
+    client: Client,
+    // Whether the table has a geography column, decided when it is created
+    // or from the one that exists
+    postgis: AtomicBool,
+}
+
+impl PostgresBackend {
+    pub async fn connect(url: &str) -> Result<Self, Box<dyn Error>> {
+        let (client, connection) = tokio_postgres::connect(url, NoTls)
+            .await
+            .map_err(|err| format!("Could not connect to Postgres: {}", err))?;
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                tracing::error!("Postgres connection failed: {}", err);
+            }
+        });
+
+        Ok(PostgresBackend {
+            client,
+            postgis: AtomicBool::new(false),
+        })
+    }
+
+    // PostGIS already in the database, or available to create
+    async fn has_postgis(&self) -> Result<bool, Box<dyn Error>> {
+        let installed = self
+            .client
+            .query_opt("SELECT 1 FROM pg_extension WHERE extname = 'postgis'", &[])
+            .await?
+            .is_some();
+        if installed {
+            return Ok(true);
+        }
+        let available = self
+            .client
+            .query_opt(
+                "SELECT 1 FROM pg_available_extensions WHERE name = 'postgis'",
+                &[],
+            )
+            .await?
+            .is_some();
+        // Creating it needs more than the usual privileges
+        Ok(available
+            && self
+                .client
+                .batch_execute("CREATE EXTENSION IF NOT EXISTS postgis")
+                .await
+                .is_ok())
+    }
+
+    // Every document fails with the statement when Postgres rejects the
+    // batch, everything else such as a lost connection is retried
+    fn rejected(
+        ids: Vec<String>,
+        err: tokio_postgres::Error,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        let reason = match err.as_db_error() {
+            Some(db_error) => db_error.message().to_string(),
+            None => return Err(err.into()),
+        };
+        Ok(ids
+            .into_iter()
+            .map(|id| BulkItemResult {
+                id,
+                status: 400,
+                error: Some(json!({ "reason": reason })),
+            })
+            .collect())
+    }
+}
+
+impl SearchBackend for PostgresBackend {
+    async fn ensure_index(&self, _index: &str) -> Result<bool, Box<dyn Error>> {
+        let columns: Vec<String> = self
+            .client
+            .query(
+                "SELECT column_name::text FROM information_schema.columns WHERE table_name = $1",
+                &[&TABLE],
+            )
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        if !columns.is_empty() {
+            let postgis = columns.iter().any(|column| column == "location");
+            self.postgis.store(postgis, Ordering::Relaxed);
+            return Ok(false);
+        }
+
+        let postgis = self.has_postgis().await?;
+        if !postgis {
+            tracing::info!("PostGIS is not available, storing latitude and longitude columns");
+        }
+        self.client.batch_execute(&create_table(postgis)).await?;
+        self.postgis.store(postgis, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    // The table has its columns already
+    async fn put_mapping(&self, _index: &str, _mapping: Value) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn delete_index(&self, _index: &str) -> Result<(), Box<dyn Error>> {
+        self.client
+            .batch_execute(&format!("DROP TABLE IF EXISTS {}", TABLE))
+            .await?;
+        Ok(())
+    }
+
+    // One multi-row INSERT per batch, upserting on the id
+    async fn bulk(
+        &self,
+        _index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        let postgis = self.postgis.load(Ordering::Relaxed);
+        let rows: Vec<Value> = documents.iter().map(|doc| row(doc, postgis)).collect();
+        let ids: Vec<String> = documents.into_iter().map(|doc| doc.id).collect();
+
+        let mut columns = vec!["id"];
+        columns.extend(COLUMNS);
+        match postgis {
+            true => columns.push("location"),
+            false => columns.extend(["latitude", "longitude"]),
+        }
+        let updates: Vec<String> = columns[1..]
+            .iter()
+            .map(|column| format!("{} = EXCLUDED.{}", column, column))
+            .collect();
+        let sql = format!(
+            "INSERT INTO {table} ({columns}) SELECT {columns} FROM jsonb_populate_recordset(NULL::{table}, $1)
+             ON CONFLICT (id) DO UPDATE SET {updates}",
+            table = TABLE,
+            columns = columns.join(", "),
+            updates = updates.join(", ")
+        );
+
+        match self
+            .client
+            .execute(sql.as_str(), &[&Value::Array(rows)])
+            .await
+        {
+            Ok(_) => Ok(ids
+                .into_iter()
+                .map(|id| BulkItemResult {
+                    id,
+                    status: 201,
+                    error: None,
+                })
+                .collect()),
+            Err(err) => Self::rejected(ids, err),
+        }
+    }
+
+    // Only the columns a document has change, the rest of the row is kept
+    async fn bulk_update(
+        &self,
+        _index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        let postgis = self.postgis.load(Ordering::Relaxed);
+        let rows: Vec<Value> = documents.iter().map(|doc| row(doc, postgis)).collect();
+        let ids: Vec<String> = documents.into_iter().map(|doc| doc.id).collect();
+
+        let sql = format!(
+            "UPDATE {table} SET ({columns}) = (SELECT {columns} FROM jsonb_populate_record({table}, changes.doc))
+             FROM jsonb_array_elements($1) AS changes(doc)
+             WHERE {table}.id = (changes.doc->>'id')::bigint
+             RETURNING {table}.id",
+            table = TABLE,
+            columns = COLUMNS.join(", ")
+        );
+        let updated: Vec<i64> = match self
+            .client
+            .query(sql.as_str(), &[&Value::Array(rows)])
+            .await
+        {
+            Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+            Err(err) => return Self::rejected(ids, err),
+        };
+
+        Ok(ids
+            .into_iter()
+            .map(|id| {
+                let found = id
+                    .parse::<i64>()
+                    .is_ok_and(|parsed| updated.contains(&parsed));
+                BulkItemResult {
+                    status: if found { 200 } else { 404 },
+                    error: (!found).then(|| json!({"type": "document_missing_exception"})),
+                    id,
+                }
+            })
+            .collect())
+    }
+
+    async fn get_fields(
+        &self,
+        _index: &str,
+        ids: &[String],
+        fields: &[&str],
+    ) -> Result<HashMap<String, Value>, Box<dyn Error>> {
+        let ids: Vec<i64> = ids.iter().filter_map(|id| id.parse().ok()).collect();
+        let rows = self
+            .client
+            .query(
+                format!(
+                    "SELECT id, to_jsonb({0}) FROM {0} WHERE id = ANY($1)",
+                    TABLE
+                )
+                .as_str(),
+                &[&ids],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let id: i64 = row.get(0);
+                let document: Value = row.get(1);
+                let selected: Map<String, Value> = fields
+                    .iter()
+                    .filter_map(|&field| {
+                        document
+                            .get(field)
+                            .map(|value| (field.to_string(), value.clone()))
+                    })
+                    .collect();
+                (id.to_string(), Value::Object(selected))
+            })
+            .collect())
+    }
+
+    async fn count(&self, _index: &str) -> Result<u64, Box<dyn Error>> {
+        let row = self
+            .client
+            .query_one(format!("SELECT COUNT(*) FROM {}", TABLE).as_str(), &[])
+            .await?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    // Committed rows are visible straight away
+    async fn refresh(&self, _index: &str) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    // A database is not sized by data nodes
+    async fn data_nodes(&self) -> Result<u64, Box<dyn Error>> {
+        Err("no cluster when writing to Postgres".into())
+    }
+}
@@ -0,0 +1,328 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde_json::json;
+use std::io::{stdout, IsTerminal};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::logging::FILE_ONLY;
+use crate::output::{status_line, OutputFormat};
+
+// How often the plain log fallback reports a running task
+pub const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressMode {
+    // Live bars, stacked when tasks run side by side
+    Bar,
+    // Periodic plain lines for logs and pipes
+    Log,
+    // One JSON event per line on stderr
+    Json,
+}
+
+impl ProgressMode {
+    pub fn detect(enabled: bool, format: OutputFormat) -> Self {
+        if format == OutputFormat::Json {
+            ProgressMode::Json
+        } else if !enabled || !stdout().is_terminal() {
+            ProgressMode::Log
+        } else {
+            ProgressMode::Bar
+        }
+    }
+}
+
+// Entry point commands report through, independent of how it is displayed
+pub trait Progress: Send + Sync {
+    // Begin a task, `total` is the expected number of units when known
+    fn start_task(&self, name: &str, total: Option<u64>) -> Box<dyn ProgressTask>;
+
+    // Print a line without corrupting any bars being drawn
+    fn log(&self, message: &str);
+}
+
+pub trait ProgressTask: Send + Sync {
+    // Record `units` more units of completed work
+    fn update(&self, units: u64);
+
+    fn log(&self, message: &str);
+
+    // Status shown next to the bar, replacing the previous one. Logged like
+    // any other line where there is no bar.
+    fn message(&self, message: &str) {
+        self.log(message);
+    }
+
+    fn finish(&self, message: &str);
+
+    // End a task that failed, leaving its bar where it stopped with the
+    // reason next to it
+    fn abandon(&self, message: &str) {
+        self.log(message);
+    }
+}
+
+pub fn reporter(mode: ProgressMode) -> Arc<dyn Progress> {
+    match mode {
+        ProgressMode::Bar => Arc::new(BarProgress {
+            multi: MultiProgress::new(),
+        }),
+        ProgressMode::Log => Arc::new(LogProgress),
+        ProgressMode::Json => Arc::new(JsonProgress),
+    }
+}
+
+// Line printed by the plain fallback, e.g. `Seeding: 2500/10000 (25%) in 12s`
+pub fn format_log_line(name: &str, done: u64, total: Option<u64>, elapsed: Duration) -> String {
+    match total {
+        Some(total) if total > 0 => format!(
+            "{}: {}/{} ({}%) in {}s",
+            name,
+            done,
+            total,
+            done * 100 / total,
+            elapsed.as_secs()
+        ),
+        _ => format!("{}: {} in {}s", name, done, elapsed.as_secs()),
+    }
+}
+
+struct BarProgress {
+    multi: MultiProgress,
+}
+
+impl Progress for BarProgress {
+    fn start_task(&self, name: &str, total: Option<u64>) -> Box<dyn ProgressTask> {
+        let bar = match total {
+            Some(total) => ProgressBar::new(total).with_style(
+                ProgressStyle::with_template(
+                    "{prefix} [{bar:40}] {pos}/{len} {per_sec} eta {eta} {msg}",
+                )
+                .unwrap()
+                .progress_chars("=> "),
+            ),
+            None => ProgressBar::new_spinner().with_style(
+                ProgressStyle::with_template("{spinner} {prefix} {pos} {per_sec} {msg}").unwrap(),
+            ),
+        };
+        let bar = self.multi.add(bar.with_prefix(name.to_string()));
+        bar.enable_steady_tick(Duration::from_millis(200));
+
+        Box::new(BarTask {
+            bar,
+            multi: self.multi.clone(),
+        })
+    }
+
+    fn log(&self, message: &str) {
+        tracing::info!(target: FILE_ONLY, "{}", message);
+        let _ = self.multi.println(message);
+    }
+}
+
+struct BarTask {
+    bar: ProgressBar,
+    multi: MultiProgress,
+}
+
+impl ProgressTask for BarTask {
+    fn update(&self, units: u64) {
+        self.bar.inc(units);
+        tracing::debug!(target: FILE_ONLY, "{}: {}", self.bar.prefix(), self.bar.position());
+    }
+
+    fn log(&self, message: &str) {
+        tracing::info!(target: FILE_ONLY, "{}", message);
+        let _ = self.multi.println(message);
+    }
+
+    fn message(&self, message: &str) {
+        tracing::info!(target: FILE_ONLY, "{}", message);
+        self.bar.set_message(message.to_string());
+    }
+
+    fn finish(&self, message: &str) {
+        tracing::info!(target: FILE_ONLY, "{}: {} {}", self.bar.prefix(), self.bar.position(), message);
+        self.bar.finish_with_message(message.to_string());
+    }
+
+    fn abandon(&self, message: &str) {
+        tracing::error!(target: FILE_ONLY, "{}: {} {}", self.bar.prefix(), self.bar.position(), message);
+        self.bar.abandon_with_message(message.to_string());
+    }
+}
+
+impl Drop for BarTask {
+    fn drop(&mut self) {
+        if !self.bar.is_finished() {
+            self.bar.abandon();
+        }
+    }
+}
+
+struct LogProgress;
+
+impl Progress for LogProgress {
+    fn start_task(&self, name: &str, total: Option<u64>) -> Box<dyn ProgressTask> {
+        let now = Instant::now();
+        Box::new(LogTask {
+            name: name.to_string(),
+            total,
+            started: now,
+            state: Mutex::new((0, now)),
+        })
+    }
+
+    fn log(&self, message: &str) {
+        status_line(message);
+    }
+}
+
+struct LogTask {
+    name: String,
+    total: Option<u64>,
+    started: Instant,
+    // Units done so far and when progress was last printed
+    state: Mutex<(u64, Instant)>,
+}
+
+impl ProgressTask for LogTask {
+    fn update(&self, units: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.0 += units;
+        if state.1.elapsed() >= LOG_INTERVAL {
+            state.1 = Instant::now();
+            status_line(&format_log_line(
+                &self.name,
+                state.0,
+                self.total,
+                self.started.elapsed(),
+            ));
+        }
+    }
+
+    fn log(&self, message: &str) {
+        status_line(message);
+    }
+
+    fn finish(&self, message: &str) {
+        let done = self.state.lock().unwrap().0;
+        status_line(&format!(
+            "{} {}",
+            format_log_line(&self.name, done, self.total, self.started.elapsed()),
+            message
+        ));
+    }
+}
+
+// Events go to stderr so stdout only carries the command result
+struct JsonProgress;
+
+fn emit(event: serde_json::Value) {
+    tracing::debug!(target: FILE_ONLY, "{}", event);
+    eprintln!("{}", event);
+}
+
+impl Progress for JsonProgress {
+    fn start_task(&self, name: &str, total: Option<u64>) -> Box<dyn ProgressTask> {
+        emit(json!({"event": "start", "task": name, "total": total}));
+        let now = Instant::now();
+        Box::new(JsonTask {
+            name: name.to_string(),
+            total,
+            started: now,
+            state: Mutex::new((0, now)),
+        })
+    }
+
+    fn log(&self, message: &str) {
+        emit(json!({"event": "log", "message": message}));
+    }
+}
+
+struct JsonTask {
+    name: String,
+    total: Option<u64>,
+    started: Instant,
+    state: Mutex<(u64, Instant)>,
+}
+
+impl ProgressTask for JsonTask {
+    fn update(&self, units: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.0 += units;
+        if state.1.elapsed() >= LOG_INTERVAL {
+            state.1 = Instant::now();
+            emit(json!({
+                "event": "progress",
+                "task": self.name,
+                "done": state.0,
+                "total": self.total,
+                "elapsed_ms": self.started.elapsed().as_millis() as u64,
+            }));
+        }
+    }
+
+    fn log(&self, message: &str) {
+        emit(json!({"event": "log", "task": self.name, "message": message}));
+    }
+
+    fn finish(&self, message: &str) {
+        emit(json!({
+            "event": "finish",
+            "task": self.name,
+            "done": self.state.lock().unwrap().0,
+            "total": self.total,
+            "elapsed_ms": self.started.elapsed().as_millis() as u64,
+            "message": message,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_line_shows_the_share_done_of_a_known_total() {
+        let line = format_log_line("Seeding", 2500, Some(10000), Duration::from_millis(12_900));
+        assert_eq!(line, "Seeding: 2500/10000 (25%) in 12s");
+        assert_eq!(
+            format_log_line("Seeding", 10000, Some(10000), Duration::from_secs(40)),
+            "Seeding: 10000/10000 (100%) in 40s"
+        );
+        assert_eq!(
+            format_log_line("Seeding", 1, Some(3), Duration::ZERO),
+            "Seeding: 1/3 (33%) in 0s"
+        );
+    }
+
+    #[test]
+    fn log_line_without_a_total_shows_the_count() {
+        assert_eq!(
+            format_log_line("Resizing", 42, None, Duration::from_secs(3)),
+            "Resizing: 42 in 3s"
+        );
+        // An empty input must not divide by zero
+        assert_eq!(
+            format_log_line("Resizing", 0, Some(0), Duration::from_secs(3)),
+            "Resizing: 0 in 3s"
+        );
+    }
+
+    #[test]
+    fn disabled_bars_and_json_output_fall_back() {
+        assert_eq!(
+            ProgressMode::detect(false, OutputFormat::Human),
+            ProgressMode::Log
+        );
+        assert_eq!(
+            ProgressMode::detect(true, OutputFormat::Json),
+            ProgressMode::Json
+        );
+        assert_eq!(
+            ProgressMode::detect(false, OutputFormat::Json),
+            ProgressMode::Json
+        );
+    }
+}
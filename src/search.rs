@@ -0,0 +1,413 @@
+use clap::ValueEnum;
+use elasticsearch::{Elasticsearch, SearchParts};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::str::FromStr;
+
+use crate::geonames::format_display_name;
+use crate::output::CommandReport;
+
+// Nearest to a point first, with the distance in kilometers as the hit's
+// sort value
+fn geo_distance_sort(lat: f64, lon: f64) -> Value {
+    json!({
+        "_geo_distance": {
+            "location": {"lat": lat, "lon": lon},
+            "order": "asc",
+            "unit": "km",
+            "distance_type": "arc"
+        }
+    })
+}
+
+// Query for the `n` documents closest to a point, sorted nearest first with
+// the distance reported in kilometers
+pub fn reverse_geocode_query(lat: f64, lon: f64, n: usize) -> Value {
+    json!({
+        "size": n,
+        "query": {"match_all": {}},
+        "sort": [geo_distance_sort(lat, lon)]
+    })
+}
+
+#[derive(Serialize)]
+pub struct NearbyLocation {
+    pub id: String,
+    pub name: String,
+    pub admin1: Option<String>,
+    pub admin2: Option<String>,
+    pub country_code: String,
+    pub display_name: String,
+    pub distance_km: f64,
+}
+
+#[derive(Serialize)]
+pub struct ReverseGeocodeReport {
+    pub lat: f64,
+    pub lon: f64,
+    pub locations: Vec<NearbyLocation>,
+}
+
+impl CommandReport for ReverseGeocodeReport {
+    fn print_human(&self) {
+        if self.locations.is_empty() {
+            println!("No locations found near {},{}", self.lat, self.lon);
+        }
+
+        for location in &self.locations {
+            println!("{} | {:.2} km", location.display_name, location.distance_km);
+        }
+    }
+}
+
+// Find the nearest named locations to a coordinate
+pub async fn reverse_geocode(
+    client: &Elasticsearch,
+    index: &str,
+    lat: f64,
+    lon: f64,
+    n: usize,
+) -> Result<ReverseGeocodeReport, Box<dyn Error>> {
+    let response = client
+        .search(SearchParts::Index(&[index]))
+        .body(reverse_geocode_query(lat, lon, n))
+        .send()
+        .await?;
+
+    if !response.status_code().is_success() {
+        return Err(format!(
+            "Reverse geocode against index {} failed with status {}",
+            index,
+            response.status_code()
+        )
+        .into());
+    }
+
+    let body = response.json::<Value>().await?;
+    let hits = body["hits"]["hits"].as_array().cloned().unwrap_or_default();
+
+    let locations = hits
+        .iter()
+        .map(|hit| {
+            let source = &hit["_source"];
+            let name = source["name"].as_str().unwrap_or_default();
+            let admin1 = source["admin1"].as_str();
+            let admin2 = source["admin2"].as_str();
+            let country_code = source["country_code"].as_str().unwrap_or_default();
+
+            NearbyLocation {
+                id: hit["_id"].as_str().unwrap_or_default().to_string(),
+                display_name: format_display_name(name, admin1, admin2, country_code),
+                name: name.to_string(),
+                admin1: admin1.map(String::from),
+                admin2: admin2.map(String::from),
+                country_code: country_code.to_string(),
+                distance_km: hit["sort"][0].as_f64().unwrap_or_default(),
+            }
+        })
+        .collect();
+
+    Ok(ReverseGeocodeReport {
+        lat,
+        lon,
+        locations,
+    })
+}
+
+// Columns of the results table when --source is not given, `coordinates`
+// being the location as latitude and longitude
+pub const DEFAULT_COLUMNS: [&str; 5] = ["name", "country_code", "admin1", "admin2", "coordinates"];
+
+// Fields matched against the query when --fields is not given
+pub const DEFAULT_SEARCH_FIELDS: [&str; 3] = ["name", "ascii_name", "alternate_names"];
+
+// Typo tolerance for the multi_match, as edit distance
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fuzziness {
+    Edits(u8),
+    Auto,
+}
+
+impl FromStr for Fuzziness {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "auto" => Ok(Fuzziness::Auto),
+            "0" => Ok(Fuzziness::Edits(0)),
+            "1" => Ok(Fuzziness::Edits(1)),
+            "2" => Ok(Fuzziness::Edits(2)),
+            _ => Err(format!("fuzziness must be 0, 1, 2 or auto, not {}", value)),
+        }
+    }
+}
+
+impl Fuzziness {
+    fn to_value(self) -> Value {
+        match self {
+            Fuzziness::Edits(edits) => json!(edits.to_string()),
+            Fuzziness::Auto => json!("AUTO"),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SearchSort {
+    #[default]
+    #[value(name = "_score")]
+    Score,
+    Population,
+}
+
+// A `lat,lon` point such as --near 51.5,-0.12
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GeoPoint {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl FromStr for GeoPoint {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected lat,lon such as 51.5,-0.12, not {}", value);
+        let (lat, lon) = value.split_once(',').ok_or_else(invalid)?;
+        let lat: f64 = lat.trim().parse().map_err(|_| invalid())?;
+        let lon: f64 = lon.trim().parse().map_err(|_| invalid())?;
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+            return Err(format!(
+                "latitude must be within -90..90 and longitude within -180..180, not {}",
+                value
+            ));
+        }
+        Ok(GeoPoint { lat, lon })
+    }
+}
+
+pub struct SearchOptions<'a> {
+    pub query: &'a str,
+    pub size: usize,
+    pub fuzzy: Option<Fuzziness>,
+    // Searched fields, DEFAULT_SEARCH_FIELDS when empty
+    pub fields: &'a [String],
+    // Returned fields, the whole document when empty
+    pub source: &'a [String],
+    pub sort: SearchSort,
+    // Point --radius and --sort-by-distance are measured from
+    pub near: Option<GeoPoint>,
+    // Only locations within this many kilometers of `near`
+    pub radius_km: Option<f64>,
+    // Nearest to `near` first, instead of `sort`
+    pub sort_by_distance: bool,
+}
+
+// Full text query over the name fields, most populous first with
+// `SearchSort::Population`. With a radius the match is wrapped in a bool
+// query filtering on the distance from `near`.
+pub fn search_query(options: &SearchOptions) -> Value {
+    let fields: Vec<&str> = match options.fields.is_empty() {
+        true => DEFAULT_SEARCH_FIELDS.to_vec(),
+        false => options.fields.iter().map(String::as_str).collect(),
+    };
+
+    let mut multi_match = json!({"query": options.query, "fields": fields});
+    if let Some(fuzzy) = options.fuzzy {
+        multi_match["fuzziness"] = fuzzy.to_value();
+    }
+
+    let mut matching = json!({"multi_match": multi_match});
+    if let (Some(near), Some(radius_km)) = (options.near, options.radius_km) {
+        matching = json!({
+            "bool": {
+                "must": matching,
+                "filter": {
+                    "geo_distance": {
+                        "distance": format!("{}km", radius_km),
+                        "location": {"lat": near.lat, "lon": near.lon}
+                    }
+                }
+            }
+        });
+    }
+
+    let mut query = json!({
+        "size": options.size,
+        "query": matching,
+    });
+    if !options.source.is_empty() {
+        query["_source"] = json!(options.source);
+    }
+    match (options.near, options.sort_by_distance) {
+        (Some(near), true) => {
+            query["sort"] = json!([geo_distance_sort(near.lat, near.lon), "_score"]);
+            // Hits sorted on anything but the score come back without one
+            query["track_scores"] = json!(true);
+        }
+        _ if options.sort == SearchSort::Population => {
+            query["sort"] = json!([
+                {"population": {"order": "desc", "missing": "_last"}},
+                "_score"
+            ]);
+        }
+        _ => {}
+    }
+
+    query
+}
+
+#[derive(Serialize)]
+pub struct SearchHit {
+    pub id: String,
+    pub score: Option<f64>,
+    // From `near`, when sorting by distance
+    pub distance_km: Option<f64>,
+    pub source: Value,
+}
+
+#[derive(Serialize)]
+pub struct SearchReport {
+    pub query: String,
+    // Columns shown, the --source fields or DEFAULT_COLUMNS
+    pub columns: Vec<String>,
+    pub hits: Vec<SearchHit>,
+    // The query sent, with --explain
+    pub dsl: Option<Value>,
+}
+
+// A source value as shown in a table cell
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+impl SearchReport {
+    fn row(&self, hit: &SearchHit) -> Vec<String> {
+        let source = &hit.source;
+        self.columns
+            .iter()
+            .map(|column| match column.as_str() {
+                "distance_km" => hit
+                    .distance_km
+                    .map(|distance| format!("{:.2}", distance))
+                    .unwrap_or_default(),
+                "display_name" if source.get("display_name").is_none() => format_display_name(
+                    source["name"].as_str().unwrap_or_default(),
+                    source["admin1"].as_str(),
+                    source["admin2"].as_str(),
+                    source["country_code"].as_str().unwrap_or_default(),
+                ),
+                "coordinates" if source.get("coordinates").is_none() => {
+                    match (
+                        source["location"][1].as_f64(),
+                        source["location"][0].as_f64(),
+                    ) {
+                        (Some(lat), Some(lon)) => format!("{:.4}, {:.4}", lat, lon),
+                        _ => String::new(),
+                    }
+                }
+                column => cell(&source[column]),
+            })
+            .collect()
+    }
+}
+
+impl CommandReport for SearchReport {
+    fn print_human(&self) {
+        if let Some(dsl) = &self.dsl {
+            println!("{}", serde_json::to_string_pretty(dsl).unwrap_or_default());
+        }
+        if self.hits.is_empty() {
+            println!("No locations match {}", self.query);
+            return;
+        }
+
+        let rows: Vec<Vec<String>> = self.hits.iter().map(|hit| self.row(hit)).collect();
+        let widths: Vec<usize> = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                rows.iter()
+                    .map(|row| row[i].chars().count())
+                    .chain([column.len()])
+                    .max()
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        let line = |cells: &[String]| {
+            cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join(" | ")
+        };
+        println!("{} | score", line(&self.columns));
+        for (row, hit) in rows.iter().zip(&self.hits) {
+            let score = hit.score.map(|score| format!("{:.2}", score));
+            println!("{} | {}", line(row), score.unwrap_or_default());
+        }
+    }
+}
+
+// Run a name search and return the hits in ranking order
+pub async fn search(
+    client: &Elasticsearch,
+    index: &str,
+    options: &SearchOptions<'_>,
+    explain: bool,
+) -> Result<SearchReport, Box<dyn Error>> {
+    let query = search_query(options);
+    let response = client
+        .search(SearchParts::Index(&[index]))
+        .body(query.clone())
+        .send()
+        .await?;
+
+    if !response.status_code().is_success() {
+        return Err(format!(
+            "Search against index {} failed with status {}",
+            index,
+            response.status_code()
+        )
+        .into());
+    }
+
+    let body = response.json::<Value>().await?;
+    let by_distance = options.near.is_some() && options.sort_by_distance;
+    let hits = body["hits"]["hits"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|hit| SearchHit {
+            id: hit["_id"].as_str().unwrap_or_default().to_string(),
+            score: hit["_score"].as_f64(),
+            distance_km: by_distance.then(|| hit["sort"][0].as_f64()).flatten(),
+            source: hit["_source"].clone(),
+        })
+        .collect();
+
+    let mut columns: Vec<String> = match options.source.is_empty() {
+        true => DEFAULT_COLUMNS
+            .iter()
+            .map(|column| column.to_string())
+            .collect(),
+        false => options.source.to_vec(),
+    };
+    if by_distance {
+        columns.push("distance_km".to_string());
+    }
+
+    Ok(SearchReport {
+        query: options.query.to_string(),
+        columns,
+        hits,
+        dsl: explain.then_some(query),
+    })
+}
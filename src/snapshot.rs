@@ -0,0 +1,515 @@
+use clap::Subcommand;
+use elasticsearch::{
+    http::StatusCode,
+    indices::{IndicesDeleteParts, IndicesExistsParts},
+    snapshot::{
+        SnapshotCreateParts, SnapshotCreateRepositoryParts, SnapshotGetParts,
+        SnapshotGetRepositoryParts, SnapshotRestoreParts, SnapshotStatusParts,
+    },
+    Elasticsearch,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::es::EsArgs;
+use crate::output::CommandReport;
+use crate::progress::Progress;
+use crate::status;
+
+// How often a running snapshot is polled
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Subcommand)]
+pub enum SnapshotAction {
+    /// Snapshot an index under a timestamped name
+    Create {
+        #[command(flatten)]
+        es: EsArgs,
+
+        /// Snapshot repository
+        #[clap(long)]
+        repo: String,
+
+        #[clap(short, long, default_value = "geolocations")]
+        index: String,
+
+        /// Register the repository when it is missing, as fs:<path> or
+        /// s3:<bucket>
+        #[clap(long)]
+        register: Option<RepositorySpec>,
+    },
+    /// Snapshots in a repository, oldest first
+    List {
+        #[command(flatten)]
+        es: EsArgs,
+
+        #[clap(long)]
+        repo: String,
+    },
+    /// Restore an index from a snapshot
+    Restore {
+        snapshot: String,
+
+        #[command(flatten)]
+        es: EsArgs,
+
+        #[clap(long)]
+        repo: String,
+
+        /// Index to restore from the snapshot
+        #[clap(short, long, default_value = "geolocations")]
+        index: String,
+
+        /// Restore under this name instead of the original one
+        #[clap(long)]
+        rename_to: Option<String>,
+
+        /// Delete the target index first when it already exists
+        #[clap(long)]
+        yes: bool,
+    },
+}
+
+// Where a repository keeps its snapshots, for registering it
+#[derive(Clone, Debug)]
+pub enum RepositorySpec {
+    // A path listed in the cluster's path.repo
+    Fs(String),
+    S3(String),
+}
+
+impl FromStr for RepositorySpec {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            Some(("fs", path)) if !path.is_empty() => Ok(RepositorySpec::Fs(path.to_string())),
+            Some(("s3", bucket)) if !bucket.is_empty() => {
+                Ok(RepositorySpec::S3(bucket.to_string()))
+            }
+            _ => Err(format!(
+                "Invalid repository {}, expected fs:<path> or s3:<bucket>",
+                value
+            )),
+        }
+    }
+}
+
+impl RepositorySpec {
+    fn body(&self) -> Value {
+        match self {
+            RepositorySpec::Fs(path) => json!({"type": "fs", "settings": {"location": path}}),
+            RepositorySpec::S3(bucket) => json!({"type": "s3", "settings": {"bucket": bucket}}),
+        }
+    }
+}
+
+// A shard that did not make it into a snapshot
+#[derive(Serialize, Debug)]
+pub struct ShardFailure {
+    pub index: String,
+    pub shard: String,
+    pub reason: String,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    RepositoryMissing(String),
+    // The snapshot finished without every shard, `shards` says why
+    Failed {
+        snapshot: String,
+        state: String,
+        shards: Vec<ShardFailure>,
+    },
+    // Restoring would overwrite an index and --yes was not given
+    IndexExists(String),
+    Request(Box<dyn Error>),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::RepositoryMissing(repo) => write!(
+                f,
+                "Snapshot repository {} does not exist, register it with --register",
+                repo
+            ),
+            SnapshotError::Failed {
+                snapshot,
+                state,
+                shards,
+            } => {
+                write!(f, "Snapshot {} finished as {}", snapshot, state)?;
+                for shard in shards {
+                    write!(f, "\n  {}[{}]: {}", shard.index, shard.shard, shard.reason)?;
+                }
+                Ok(())
+            }
+            SnapshotError::IndexExists(index) => write!(
+                f,
+                "Index {} already exists, pass --yes to replace it",
+                index
+            ),
+            SnapshotError::Request(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl From<elasticsearch::Error> for SnapshotError {
+    fn from(err: elasticsearch::Error) -> Self {
+        SnapshotError::Request(err.into())
+    }
+}
+
+// Fail with the response body when Elasticsearch refused a request
+async fn check(
+    response: elasticsearch::http::response::Response,
+    action: &str,
+) -> Result<Value, SnapshotError> {
+    let status = response.status_code();
+    let body = response.json::<Value>().await?;
+    if !status.is_success() {
+        return Err(SnapshotError::Request(
+            format!("Could not {}: {} {}", action, status, body["error"]).into(),
+        ));
+    }
+
+    Ok(body)
+}
+
+// Make sure the repository exists, registering it from `register` if given
+pub async fn ensure_repository(
+    client: &Elasticsearch,
+    repo: &str,
+    register: Option<&RepositorySpec>,
+) -> Result<(), SnapshotError> {
+    let response = client
+        .snapshot()
+        .get_repository(SnapshotGetRepositoryParts::Repository(&[repo]))
+        .send()
+        .await?;
+    if response.status_code() != StatusCode::NOT_FOUND {
+        check(response, &format!("read repository {}", repo)).await?;
+        return Ok(());
+    }
+
+    let Some(spec) = register else {
+        return Err(SnapshotError::RepositoryMissing(repo.to_string()));
+    };
+    status!("Registering snapshot repository {}", repo);
+    let response = client
+        .snapshot()
+        .create_repository(SnapshotCreateRepositoryParts::Repository(repo))
+        .body(spec.body())
+        .send()
+        .await?;
+    check(response, &format!("register repository {}", repo)).await?;
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct SnapshotReport {
+    pub repo: String,
+    pub snapshot: String,
+    pub indices: Vec<String>,
+    pub shards: u64,
+    pub duration_ms: u64,
+}
+
+impl CommandReport for SnapshotReport {
+    fn print_human(&self) {
+        println!(
+            "Snapshot {}/{} of {} ({} shards) in {}ms",
+            self.repo,
+            self.snapshot,
+            self.indices.join(", "),
+            self.shards,
+            self.duration_ms
+        );
+    }
+}
+
+// Shards whose stage in the status API is FAILURE
+fn failed_shards(status: &Value) -> Vec<ShardFailure> {
+    let mut failures = Vec::new();
+    let Some(indices) = status["indices"].as_object() else {
+        return failures;
+    };
+
+    for (index, detail) in indices {
+        for (shard, shard_status) in detail["shards"].as_object().into_iter().flatten() {
+            if shard_status["stage"] == "FAILURE" {
+                failures.push(ShardFailure {
+                    index: index.clone(),
+                    shard: shard.clone(),
+                    reason: shard_status["reason"]
+                        .as_str()
+                        .unwrap_or("no reason given")
+                        .to_string(),
+                });
+            }
+        }
+    }
+
+    failures
+}
+
+// Snapshot `index` as `<index>-<timestamp>` and wait for it to finish,
+// reporting shards as they complete
+pub async fn create_snapshot(
+    client: &Elasticsearch,
+    repo: &str,
+    index: &str,
+    register: Option<&RepositorySpec>,
+    progress: &dyn Progress,
+) -> Result<SnapshotReport, SnapshotError> {
+    let started = Instant::now();
+    ensure_repository(client, repo, register).await?;
+
+    let snapshot = format!("{}-{}", index, chrono::Utc::now().format("%Y%m%d%H%M%S"));
+    status!("Creating snapshot {}/{}", repo, snapshot);
+    let response = client
+        .snapshot()
+        .create(SnapshotCreateParts::RepositorySnapshot(repo, &snapshot))
+        .wait_for_completion(false)
+        .body(json!({"indices": index, "include_global_state": false}))
+        .send()
+        .await?;
+    check(response, &format!("create snapshot {}", snapshot)).await?;
+
+    let task = progress.start_task(&format!("Snapshot {}", snapshot), None);
+    let mut done = 0;
+    let status = loop {
+        let response = client
+            .snapshot()
+            .status(SnapshotStatusParts::RepositorySnapshot(repo, &[&snapshot]))
+            .send()
+            .await?;
+        let body = check(response, &format!("read status of {}", snapshot)).await?;
+        let status = body["snapshots"][0].clone();
+
+        let finished = status["shards_stats"]["done"].as_u64().unwrap_or_default();
+        task.update(finished.saturating_sub(done));
+        done = finished.max(done);
+        match status["state"].as_str() {
+            Some("STARTED") | Some("INIT") | None => tokio::time::sleep(POLL_INTERVAL).await,
+            Some(_) => break status,
+        }
+    };
+
+    // The status API only says done, the snapshot itself says if it is whole
+    let response = client
+        .snapshot()
+        .get(SnapshotGetParts::RepositorySnapshot(repo, &[&snapshot]))
+        .send()
+        .await?;
+    let info = check(response, &format!("read snapshot {}", snapshot)).await?;
+    let info = &info["snapshots"][0];
+    let state = info["state"].as_str().unwrap_or("UNKNOWN").to_string();
+    task.finish(&state.to_lowercase());
+
+    if state != "SUCCESS" {
+        let mut shards = failed_shards(&status);
+        if shards.is_empty() {
+            shards = info["failures"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|failure| ShardFailure {
+                    index: failure["index"].as_str().unwrap_or_default().to_string(),
+                    shard: failure["shard_id"].to_string(),
+                    reason: failure["reason"].as_str().unwrap_or_default().to_string(),
+                })
+                .collect();
+        }
+        return Err(SnapshotError::Failed {
+            snapshot,
+            state,
+            shards,
+        });
+    }
+
+    Ok(SnapshotReport {
+        repo: repo.to_string(),
+        snapshot,
+        indices: serde_json::from_value(info["indices"].clone()).unwrap_or_default(),
+        shards: info["shards"]["total"].as_u64().unwrap_or_default(),
+        duration_ms: started.elapsed().as_millis() as u64,
+    })
+}
+
+#[derive(Serialize)]
+pub struct SnapshotSummary {
+    pub snapshot: String,
+    pub state: String,
+    pub indices: Vec<String>,
+    pub start_time: Option<String>,
+    pub duration_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct SnapshotListReport {
+    pub repo: String,
+    pub snapshots: Vec<SnapshotSummary>,
+}
+
+impl CommandReport for SnapshotListReport {
+    fn print_human(&self) {
+        if self.snapshots.is_empty() {
+            println!("No snapshots in {}", self.repo);
+        }
+        for snapshot in &self.snapshots {
+            println!(
+                "{:<40} {:<8} {:<25} {}",
+                snapshot.snapshot,
+                snapshot.state,
+                snapshot.start_time.as_deref().unwrap_or_default(),
+                snapshot.indices.join(", ")
+            );
+        }
+    }
+}
+
+pub async fn list_snapshots(
+    client: &Elasticsearch,
+    repo: &str,
+) -> Result<SnapshotListReport, SnapshotError> {
+    ensure_repository(client, repo, None).await?;
+    let response = client
+        .snapshot()
+        .get(SnapshotGetParts::RepositorySnapshot(repo, &["_all"]))
+        .send()
+        .await?;
+    let body = check(response, &format!("list snapshots in {}", repo)).await?;
+
+    let snapshots = body["snapshots"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|snapshot| SnapshotSummary {
+            snapshot: snapshot["snapshot"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            state: snapshot["state"].as_str().unwrap_or_default().to_string(),
+            indices: serde_json::from_value(snapshot["indices"].clone()).unwrap_or_default(),
+            start_time: snapshot["start_time"].as_str().map(String::from),
+            duration_ms: snapshot["duration_in_millis"].as_u64(),
+        })
+        .collect();
+
+    Ok(SnapshotListReport {
+        repo: repo.to_string(),
+        snapshots,
+    })
+}
+
+#[derive(Serialize)]
+pub struct RestoreReport {
+    pub snapshot: String,
+    pub index: String,
+    pub restored_as: String,
+    // An existing index was deleted to make room
+    pub replaced: bool,
+    pub duration_ms: u64,
+}
+
+impl CommandReport for RestoreReport {
+    fn print_human(&self) {
+        println!(
+            "Restored {} from {} as {} in {}ms",
+            self.index, self.snapshot, self.restored_as, self.duration_ms
+        );
+    }
+}
+
+// Restore one index from a snapshot, deleting an index in the way only when
+// `replace` is set
+pub async fn restore_snapshot(
+    client: &Elasticsearch,
+    repo: &str,
+    snapshot: &str,
+    index: &str,
+    rename_to: Option<&str>,
+    replace: bool,
+) -> Result<RestoreReport, SnapshotError> {
+    let started = Instant::now();
+    ensure_repository(client, repo, None).await?;
+    let target = rename_to.unwrap_or(index);
+
+    let exists = client
+        .indices()
+        .exists(IndicesExistsParts::Index(&[target]))
+        .send()
+        .await?
+        .status_code()
+        .is_success();
+    if exists {
+        if !replace {
+            return Err(SnapshotError::IndexExists(target.to_string()));
+        }
+        status!("Deleting index {} before restoring", target);
+        let response = client
+            .indices()
+            .delete(IndicesDeleteParts::Index(&[target]))
+            .send()
+            .await?;
+        check(response, &format!("delete index {}", target)).await?;
+    }
+
+    let mut body = json!({"indices": index, "include_global_state": false});
+    if let Some(rename_to) = rename_to {
+        body["rename_pattern"] = json!(format!("^{}$", regex_escape(index)));
+        body["rename_replacement"] = json!(rename_to);
+    }
+
+    status!("Restoring {} from {}/{}", index, repo, snapshot);
+    let response = client
+        .snapshot()
+        .restore(SnapshotRestoreParts::RepositorySnapshot(repo, snapshot))
+        .wait_for_completion(true)
+        .body(body)
+        .send()
+        .await?;
+    let result = check(response, &format!("restore {}", snapshot)).await?;
+
+    let shards = &result["snapshot"]["shards"];
+    if shards["failed"].as_u64().unwrap_or_default() > 0 {
+        return Err(SnapshotError::Failed {
+            snapshot: snapshot.to_string(),
+            state: format!(
+                "a restore with {} of {} shards failed",
+                shards["failed"], shards["total"]
+            ),
+            shards: Vec::new(),
+        });
+    }
+
+    Ok(RestoreReport {
+        snapshot: snapshot.to_string(),
+        index: index.to_string(),
+        restored_as: target.to_string(),
+        replaced: exists,
+        duration_ms: started.elapsed().as_millis() as u64,
+    })
+}
+
+// rename_pattern is a Java regex, index names can contain `.` and `+`
+fn regex_escape(value: &str) -> String {
+    value
+        .chars()
+        .flat_map(|c| match c {
+            '.' | '+' | '*' | '?' | '(' | ')' | '[' | ']' | '{' | '}' | '|' | '^' | '$' | '\\' => {
+                vec!['\\', c]
+            }
+            c => vec![c],
+        })
+        .collect()
+}
@@ -0,0 +1,319 @@
+use rusqlite::types::{Value as SqlValue, ValueRef};
+use rusqlite::{params_from_iter, Connection, OptionalExtension};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::backend::{BulkDocument, BulkItemResult, SearchBackend};
+
+// Every index seeded goes into the one table
+const TABLE: &str = "locations";
+
+// Document fields stored, besides the id and the location which becomes
+// latitude and longitude
+const COLUMNS: [&str; 9] = [
+    "name",
+    "ascii_name",
+    "country_code",
+    "admin1",
+    "admin2",
+    "population",
+    "timezone",
+    "feature_code",
+    "modification_date",
+];
+
+const CREATE_TABLE: &str = "CREATE TABLE locations (
+    id INTEGER PRIMARY KEY,
+    name TEXT,
+    ascii_name TEXT,
+    latitude REAL,
+    longitude REAL,
+    country_code TEXT,
+    admin1 TEXT,
+    admin2 TEXT,
+    population INTEGER,
+    timezone TEXT,
+    feature_code TEXT,
+    modification_date TEXT
+)";
+
+// Strings stored as they are rather than as JSON, anything else that is not a
+// number too
+fn sql_value(value: &Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(flag) => SqlValue::Integer(*flag as i64),
+        Value::Number(n) => match n.as_i64() {
+            Some(n) => SqlValue::Integer(n),
+            None => SqlValue::Real(n.as_f64().unwrap_or_default()),
+        },
+        Value::String(s) => SqlValue::Text(s.clone()),
+        other => SqlValue::Text(other.to_string()),
+    }
+}
+
+fn json_value(value: ValueRef<'_>) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(n) => json!(n),
+        ValueRef::Real(n) => json!(n),
+        ValueRef::Text(s) | ValueRef::Blob(s) => json!(String::from_utf8_lossy(s)),
+    }
+}
+
+fn has_table(connection: &Connection) -> rusqlite::Result<bool> {
+    Ok(connection
+        .query_row(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [TABLE],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?
+        .is_some())
+}
+
+// The columns of a document that it has, with `[lon, lat]` split up
+fn row(body: &Value) -> Vec<(&'static str, Value)> {
+    let mut row: Vec<(&str, Value)> = COLUMNS
+        .iter()
+        .filter_map(|&column| body.get(column).map(|value| (column, value.clone())))
+        .collect();
+    if let Some(location) = body.get("location") {
+        row.push(("latitude", location[1].clone()));
+        row.push(("longitude", location[0].clone()));
+    }
+    row
+}
+
+// Writes the documents to a SQLite file instead of a cluster, creating the
+// locations table. Without `replace` a file that already has one is refused,
+// with it documents are upserted by id.
+pub struct SqliteBackend {
+    path: PathBuf,
+    replace: bool,
+    connection: Mutex<Connection>,
+}
+
+impl SqliteBackend {
+    pub fn open(path: &Path, replace: bool) -> Result<Self, Box<dyn Error>> {
+        let connection = Connection::open(path)
+            .map_err(|err| format!("Could not open {}: {}", path.display(), err))?;
+        if has_table(&connection)? && !replace {
+            return Err(format!(
+                "{} already has a {} table, pass --replace to upsert into it",
+                path.display(),
+                TABLE
+            )
+            .into());
+        }
+
+        Ok(SqliteBackend {
+            path: path.to_path_buf(),
+            replace,
+            connection: Mutex::new(connection),
+        })
+    }
+
+    // Index the columns looked up most once everything is in, which is
+    // cheaper than keeping the indexes up to date on every insert, and return
+    // the size of the file
+    pub fn finish(self) -> Result<u64, Box<dyn Error>> {
+        let connection = self.connection.into_inner().unwrap();
+        connection.execute_batch(
+            "CREATE INDEX IF NOT EXISTS locations_country_code ON locations (country_code);
+             CREATE INDEX IF NOT EXISTS locations_name ON locations (name);",
+        )?;
+        connection.close().map_err(|(_, err)| err)?;
+        Ok(fs::metadata(&self.path)?.len())
+    }
+
+    fn insert_sql(&self) -> String {
+        let mut columns = vec!["id", "name", "ascii_name", "latitude", "longitude"];
+        columns.extend(&COLUMNS[2..]);
+        let placeholders = vec!["?"; columns.len()].join(", ");
+        let mut sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            TABLE,
+            columns.join(", "),
+            placeholders
+        );
+        if self.replace {
+            let updates: Vec<String> = columns[1..]
+                .iter()
+                .map(|column| format!("{} = excluded.{}", column, column))
+                .collect();
+            sql.push_str(&format!(
+                " ON CONFLICT (id) DO UPDATE SET {}",
+                updates.join(", ")
+            ));
+        }
+        sql
+    }
+}
+
+impl SearchBackend for SqliteBackend {
+    async fn ensure_index(&self, _index: &str) -> Result<bool, Box<dyn Error>> {
+        let connection = self.connection.lock().unwrap();
+        let created = !has_table(&connection)?;
+        if created {
+            connection.execute(CREATE_TABLE, [])?;
+        }
+        Ok(created)
+    }
+
+    // The table has its columns already
+    async fn put_mapping(&self, _index: &str, _mapping: Value) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn delete_index(&self, _index: &str) -> Result<(), Box<dyn Error>> {
+        Err("cannot delete an index from a SQLite file".into())
+    }
+
+    // A batch is a transaction, rows that do not insert are rejected on their
+    // own like Elasticsearch does
+    async fn bulk(
+        &self,
+        _index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        let sql = self.insert_sql();
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let mut results = Vec::with_capacity(documents.len());
+        {
+            let mut insert = transaction.prepare(&sql)?;
+            for doc in documents {
+                let body = &doc.body;
+                let values = [
+                    json!(doc.id.parse::<i64>().ok()),
+                    body["name"].clone(),
+                    body["ascii_name"].clone(),
+                    body["location"][1].clone(),
+                    body["location"][0].clone(),
+                ]
+                .into_iter()
+                .chain(COLUMNS[2..].iter().map(|column| body[*column].clone()))
+                .map(|value| sql_value(&value));
+                let (status, error) = match insert.execute(params_from_iter(values)) {
+                    Ok(_) => (201, None),
+                    Err(err) => (409, Some(json!({"reason": err.to_string()}))),
+                };
+                results.push(BulkItemResult {
+                    id: doc.id,
+                    status,
+                    error,
+                });
+            }
+        }
+        transaction.commit()?;
+
+        Ok(results)
+    }
+
+    async fn bulk_update(
+        &self,
+        _index: &str,
+        documents: Vec<BulkDocument>,
+    ) -> Result<Vec<BulkItemResult>, Box<dyn Error>> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let mut results = Vec::with_capacity(documents.len());
+        for doc in documents {
+            let (columns, mut values): (Vec<&str>, Vec<Value>) = row(&doc.body).into_iter().unzip();
+            let status = match columns.is_empty() {
+                true => 200,
+                false => {
+                    let assignments: Vec<String> = columns
+                        .iter()
+                        .map(|column| format!("{} = ?", column))
+                        .collect();
+                    values.push(json!(doc.id.parse::<i64>().ok()));
+                    let changed = transaction.execute(
+                        &format!(
+                            "UPDATE {} SET {} WHERE id = ?",
+                            TABLE,
+                            assignments.join(", ")
+                        ),
+                        params_from_iter(values.iter().map(sql_value)),
+                    )?;
+                    match changed {
+                        0 => 404,
+                        _ => 200,
+                    }
+                }
+            };
+            results.push(BulkItemResult {
+                id: doc.id,
+                status,
+                error: (status == 404).then(|| json!({"type": "document_missing_exception"})),
+            });
+        }
+        transaction.commit()?;
+
+        Ok(results)
+    }
+
+    async fn get_fields(
+        &self,
+        _index: &str,
+        ids: &[String],
+        fields: &[&str],
+    ) -> Result<HashMap<String, Value>, Box<dyn Error>> {
+        let columns: Vec<&str> = fields
+            .iter()
+            .copied()
+            .filter(|field| COLUMNS.contains(field))
+            .collect();
+        let connection = self.connection.lock().unwrap();
+        let mut select = connection.prepare(&format!(
+            "SELECT {} FROM {} WHERE id = ?",
+            std::iter::once("id")
+                .chain(columns.iter().copied())
+                .collect::<Vec<_>>()
+                .join(", "),
+            TABLE
+        ))?;
+
+        let mut found = HashMap::new();
+        for id in ids {
+            let document = select
+                .query_row([id.parse::<i64>().ok()], |row| {
+                    let mut document = Map::new();
+                    for (i, column) in columns.iter().enumerate() {
+                        document.insert(column.to_string(), json_value(row.get_ref(i + 1)?));
+                    }
+                    Ok(Value::Object(document))
+                })
+                .optional()?;
+            if let Some(document) = document {
+                found.insert(id.clone(), document);
+            }
+        }
+
+        Ok(found)
+    }
+
+    async fn count(&self, _index: &str) -> Result<u64, Box<dyn Error>> {
+        let connection = self.connection.lock().unwrap();
+        let count: i64 =
+            connection.query_row(&format!("SELECT COUNT(*) FROM {}", TABLE), [], |row| {
+                row.get(0)
+            })?;
+        Ok(count as u64)
+    }
+
+    // Committed transactions are visible straight away
+    async fn refresh(&self, _index: &str) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    // There is no cluster to size the dump against
+    async fn data_nodes(&self) -> Result<u64, Box<dyn Error>> {
+        Err("no cluster when writing to SQLite".into())
+    }
+}
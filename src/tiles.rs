@@ -0,0 +1,195 @@
+use image::{imageops, imageops::FilterType::Lanczos3, io::Reader as ImageReader, RgbaImage};
+use serde::Serialize;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cancel::CancelToken;
+use crate::output::CommandReport;
+use crate::progress::Progress;
+
+// Side of a tile in pixels, what Leaflet and most slippy map clients expect
+pub const TILE_SIZE: u32 = 256;
+
+// Name of the descriptor written next to the zoom directories
+pub const DESCRIPTOR_FILE: &str = "tiles.json";
+
+#[derive(Serialize)]
+pub struct ZoomLevel {
+    pub zoom: u32,
+    pub width: u32,
+    pub height: u32,
+    pub columns: u32,
+    pub rows: u32,
+}
+
+// tiles.json, enough to set up a map over the pyramid. The source is shown
+// at its full resolution at `max_zoom` and halved for each level below.
+#[derive(Serialize)]
+pub struct TileDescriptor {
+    pub source: PathBuf,
+    pub tile_size: u32,
+    pub format: &'static str,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+    pub width: u32,
+    pub height: u32,
+    pub levels: Vec<ZoomLevel>,
+}
+
+#[derive(Serialize)]
+pub struct TilesReport {
+    pub output: PathBuf,
+    pub descriptor: TileDescriptor,
+    pub tiles_written: u64,
+    pub stopped: bool,
+}
+
+impl CommandReport for TilesReport {
+    fn print_human(&self) {
+        for level in &self.descriptor.levels {
+            println!(
+                "zoom {:<3} {}x{} px, {}x{} tiles",
+                level.zoom, level.width, level.height, level.columns, level.rows
+            );
+        }
+        println!(
+            "Wrote {} tiles to {}",
+            self.tiles_written,
+            self.output.display()
+        );
+        if self.stopped {
+            println!("Interrupted before every zoom level was written");
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self.stopped {
+            true => 1,
+            false => 0,
+        }
+    }
+}
+
+// Zoom at which a single tile covers the whole image
+pub fn natural_max_zoom(width: u32, height: u32) -> u32 {
+    let mut zoom = 0;
+    while (TILE_SIZE << zoom) < width.max(height) {
+        zoom += 1;
+    }
+    zoom
+}
+
+fn level_size(size: u32, levels_down: u32) -> u32 {
+    size.div_ceil(1 << levels_down).max(1)
+}
+
+// Cut a z/x/y.png pyramid out of `source` into `output`, from `max_zoom`
+// down to 0. Only the current level and the one being scaled from are kept
+// in memory, tiles are written as soon as they are cut. Edge tiles are
+// padded to the full tile size with transparent pixels.
+pub fn generate_tiles(
+    source: &Path,
+    output: &Path,
+    max_zoom: Option<u32>,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> Result<TilesReport, Box<dyn Error>> {
+    progress.log(&format!("Opening image at {}", source.display()));
+    let img = ImageReader::open(source)?.decode()?;
+    let (width, height) = (img.width(), img.height());
+    let max_zoom = max_zoom.unwrap_or_else(|| natural_max_zoom(width, height));
+    // Past 30 the shifts overflow, and so would any real map
+    if max_zoom > 30 {
+        return Err(format!("--max-zoom {} is too deep, at most 30", max_zoom).into());
+    }
+
+    let levels: Vec<ZoomLevel> = (0..=max_zoom)
+        .rev()
+        .map(|zoom| {
+            let (level_width, level_height) = (
+                level_size(width, max_zoom - zoom),
+                level_size(height, max_zoom - zoom),
+            );
+            ZoomLevel {
+                zoom,
+                width: level_width,
+                height: level_height,
+                columns: level_width.div_ceil(TILE_SIZE),
+                rows: level_height.div_ceil(TILE_SIZE),
+            }
+        })
+        .collect();
+    let total: u64 = levels
+        .iter()
+        .map(|level| level.columns as u64 * level.rows as u64)
+        .sum();
+
+    fs::create_dir_all(output)?;
+    let task = progress.start_task(&format!("Tiling {}", source.display()), Some(total));
+    let mut level_img = img.to_rgba8();
+    drop(img);
+    let mut tiles_written = 0;
+    let mut stopped = false;
+
+    'levels: for level in &levels {
+        if level_img.dimensions() != (level.width, level.height) {
+            level_img = imageops::resize(&level_img, level.width, level.height, Lanczos3);
+        }
+
+        for x in 0..level.columns {
+            let column_dir = output.join(level.zoom.to_string()).join(x.to_string());
+            fs::create_dir_all(&column_dir)?;
+
+            for y in 0..level.rows {
+                if cancel.is_cancelled() {
+                    task.log("Interrupted, skipping the remaining tiles");
+                    stopped = true;
+                    break 'levels;
+                }
+
+                let (left, top) = (x * TILE_SIZE, y * TILE_SIZE);
+                let piece = imageops::crop_imm(
+                    &level_img,
+                    left,
+                    top,
+                    TILE_SIZE.min(level.width - left),
+                    TILE_SIZE.min(level.height - top),
+                )
+                .to_image();
+                let mut tile = RgbaImage::new(TILE_SIZE, TILE_SIZE);
+                imageops::replace(&mut tile, &piece, 0, 0);
+                tile.save_with_format(
+                    column_dir.join(format!("{}.png", y)),
+                    image::ImageFormat::Png,
+                )?;
+
+                tiles_written += 1;
+                task.update(1);
+            }
+        }
+    }
+    task.finish("done");
+
+    let descriptor = TileDescriptor {
+        source: source.to_path_buf(),
+        tile_size: TILE_SIZE,
+        format: "png",
+        min_zoom: 0,
+        max_zoom,
+        width,
+        height,
+        levels,
+    };
+    fs::write(
+        output.join(DESCRIPTOR_FILE),
+        serde_json::to_string_pretty(&descriptor)?,
+    )?;
+
+    Ok(TilesReport {
+        output: output.to_path_buf(),
+        descriptor,
+        tiles_written,
+        stopped,
+    })
+}
@@ -0,0 +1,329 @@
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Gauge, Paragraph, Sparkline};
+use ratatui::{Frame, Terminal};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, stderr, IsTerminal, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::logging::FILE_ONLY;
+use crate::progress::{format_log_line, Progress, ProgressTask, LOG_INTERVAL};
+use crate::seed::SeedMonitor;
+
+// How often the dashboard redraws and checks for key presses
+const TICK: Duration = Duration::from_millis(250);
+
+// Seconds of throughput history kept for the sparkline
+const HISTORY: usize = 120;
+
+// The task currently running, as reported through `Progress`
+#[derive(Default)]
+pub struct DashboardState {
+    title: String,
+    last_message: String,
+}
+
+// Progress reporter for TUI mode. Nothing is printed, the dashboard shows the
+// state and every line is appended to a log file that outlives the terminal.
+pub struct TuiProgress {
+    state: Arc<Mutex<DashboardState>>,
+    log: Arc<Mutex<File>>,
+}
+
+impl TuiProgress {
+    pub fn create(log_path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)?;
+
+        Ok(TuiProgress {
+            state: Arc::new(Mutex::new(DashboardState::default())),
+            log: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn state(&self) -> Arc<Mutex<DashboardState>> {
+        Arc::clone(&self.state)
+    }
+}
+
+fn write_log(log: &Mutex<File>, state: &Mutex<DashboardState>, message: &str) {
+    tracing::info!(target: FILE_ONLY, "{}", message);
+    let _ = writeln!(
+        log.lock().unwrap(),
+        "{} {}",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+        message
+    );
+    state.lock().unwrap().last_message = message.to_string();
+}
+
+impl Progress for TuiProgress {
+    fn start_task(&self, name: &str, total: Option<u64>) -> Box<dyn ProgressTask> {
+        self.state.lock().unwrap().title = name.to_string();
+        write_log(&self.log, &self.state, &format!("Started {}", name));
+
+        let now = Instant::now();
+        Box::new(TuiTask {
+            name: name.to_string(),
+            total,
+            started: now,
+            progress: Mutex::new((0, now)),
+            state: Arc::clone(&self.state),
+            log: Arc::clone(&self.log),
+        })
+    }
+
+    fn log(&self, message: &str) {
+        write_log(&self.log, &self.state, message);
+    }
+}
+
+struct TuiTask {
+    name: String,
+    total: Option<u64>,
+    started: Instant,
+    // Units done so far and when progress was last logged
+    progress: Mutex<(u64, Instant)>,
+    state: Arc<Mutex<DashboardState>>,
+    log: Arc<Mutex<File>>,
+}
+
+impl ProgressTask for TuiTask {
+    fn update(&self, units: u64) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.0 += units;
+        if progress.1.elapsed() >= LOG_INTERVAL {
+            progress.1 = Instant::now();
+            let line = format_log_line(&self.name, progress.0, self.total, self.started.elapsed());
+            write_log(&self.log, &self.state, &line);
+        }
+    }
+
+    fn log(&self, message: &str) {
+        write_log(&self.log, &self.state, message);
+    }
+
+    fn finish(&self, message: &str) {
+        let done = self.progress.lock().unwrap().0;
+        let line = format_log_line(&self.name, done, self.total, self.started.elapsed());
+        write_log(&self.log, &self.state, &format!("{} {}", line, message));
+    }
+}
+
+// Raw mode and the alternate screen, undone on drop and on panic
+struct TerminalGuard;
+
+fn restore_terminal() {
+    let _ = disable_raw_mode();
+    let _ = execute!(stderr(), LeaveAlternateScreen);
+}
+
+impl TerminalGuard {
+    fn enter() -> io::Result<Self> {
+        static HOOK: Once = Once::new();
+        HOOK.call_once(|| {
+            let previous = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                restore_terminal();
+                previous(info);
+            }));
+        });
+
+        enable_raw_mode()?;
+        execute!(stderr(), EnterAlternateScreen)?;
+        Ok(TerminalGuard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+// A dashboard drawn on its own thread while the seed runs
+pub struct Dashboard {
+    finished: Arc<AtomicBool>,
+    handle: JoinHandle<io::Result<()>>,
+}
+
+impl Dashboard {
+    pub fn start(
+        monitor: Arc<SeedMonitor>,
+        state: Arc<Mutex<DashboardState>>,
+    ) -> Result<Self, String> {
+        if !stderr().is_terminal() {
+            return Err("--tui needs a terminal".to_string());
+        }
+
+        let finished = Arc::new(AtomicBool::new(false));
+        let done = Arc::clone(&finished);
+        let handle = thread::spawn(move || run(&monitor, &state, &done));
+
+        Ok(Dashboard { finished, handle })
+    }
+
+    // Close the dashboard once the seed has returned
+    pub fn finish(self) -> io::Result<()> {
+        self.finished.store(true, Ordering::SeqCst);
+        self.handle.join().expect("Dashboard thread panicked")
+    }
+}
+
+fn run(
+    monitor: &SeedMonitor,
+    state: &Mutex<DashboardState>,
+    finished: &AtomicBool,
+) -> io::Result<()> {
+    let _guard = TerminalGuard::enter()?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stderr()))?;
+
+    let started = Instant::now();
+    let mut history: VecDeque<u64> = VecDeque::with_capacity(HISTORY);
+    let mut last_sample = (Instant::now(), monitor.records());
+
+    while !finished.load(Ordering::SeqCst) {
+        if last_sample.0.elapsed() >= Duration::from_secs(1) {
+            let records = monitor.records();
+            let rate = (records - last_sample.1) as f64 / last_sample.0.elapsed().as_secs_f64();
+            if history.len() == HISTORY {
+                history.pop_front();
+            }
+            history.push_back(rate as u64);
+            last_sample = (Instant::now(), records);
+        }
+
+        terminal.draw(|frame| draw(frame, monitor, state, &history, started))?;
+
+        if event::poll(TICK)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('p') => {
+                        let paused = monitor.toggle_pause();
+                        monitor.warn(match paused {
+                            true => "Paused, waiting for the batch in flight",
+                            false => "Resumed",
+                        });
+                    }
+                    KeyCode::Char('s') | KeyCode::Char('q') => {
+                        monitor.stop();
+                        monitor.warn("Stopping after the batch in flight");
+                    }
+                    // Raw mode swallows SIGINT, so treat Ctrl-C as a stop
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        monitor.stop();
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn draw(
+    frame: &mut Frame,
+    monitor: &SeedMonitor,
+    state: &Mutex<DashboardState>,
+    history: &VecDeque<u64>,
+    started: Instant,
+) {
+    let [progress_area, throughput_area, stats_area, warnings_area, help_area] =
+        Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Length(6),
+            Constraint::Length(5),
+            Constraint::Min(4),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+    let state = state.lock().unwrap();
+    let block = |title: String| Block::default().borders(Borders::ALL).title(title);
+
+    let records = monitor.records();
+    let fraction = monitor.fraction_read().unwrap_or(0.0).clamp(0.0, 1.0);
+    frame.render_widget(
+        Gauge::default()
+            .block(block(state.title.clone()))
+            .gauge_style(Style::default().fg(Color::Green))
+            .ratio(fraction)
+            .label(format!(
+                "{} records ({:.1}%) in {}s",
+                records,
+                fraction * 100.0,
+                started.elapsed().as_secs()
+            )),
+        progress_area,
+    );
+
+    let current = history.back().copied().unwrap_or(0);
+    frame.render_widget(
+        Sparkline::default()
+            .block(block(format!("Throughput: {} records/s", current)))
+            .style(Style::default().fg(Color::Cyan))
+            .data(history.iter().copied()),
+        throughput_area,
+    );
+
+    let latency = |p: f64| match monitor.latency_percentile(p) {
+        Some(ms) => format!("{}ms", ms),
+        None => "-".to_string(),
+    };
+    let status = match (monitor.is_stopped(), monitor.is_paused()) {
+        (true, _) => "stopping",
+        (false, true) => "paused",
+        (false, false) => "running",
+    };
+    frame.render_widget(
+        Paragraph::new(vec![
+            Line::from(format!(
+                "Status: {}    Batches: {}",
+                status,
+                monitor.batches()
+            )),
+            Line::from(format!(
+                "Bulk latency: p50 {}  p90 {}  p99 {}",
+                latency(50.0),
+                latency(90.0),
+                latency(99.0)
+            )),
+            Line::from(format!(
+                "Rejected: {}    Skipped: {}",
+                monitor.rejected(),
+                monitor.skipped()
+            )),
+        ])
+        .block(block("Stats".to_string())),
+        stats_area,
+    );
+
+    let mut lines: Vec<Line> = monitor.warnings().into_iter().map(Line::from).collect();
+    lines.push(Line::from(format!("> {}", state.last_message)));
+    frame.render_widget(
+        Paragraph::new(lines).block(block("Warnings".to_string())),
+        warnings_area,
+    );
+
+    frame.render_widget(
+        Paragraph::new("p pause/resume   s stop and save checkpoint"),
+        help_area,
+    );
+}
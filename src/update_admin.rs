@@ -0,0 +1,129 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::io::Read;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::backend::{BulkDocument, BulkItemResult, SearchBackend};
+use crate::geonames::{AdminLookup, Location};
+use crate::output::CommandReport;
+use crate::progress::Progress;
+use crate::seed::{write_error_log, SeedError};
+
+// Document fields rewritten from the admin code files
+const ADMIN_FIELDS: [&str; 2] = ["admin1", "admin2"];
+
+#[derive(Serialize, Default)]
+pub struct AdminUpdateReport {
+    pub index: String,
+    // Rows read from the dump
+    pub scanned: u64,
+    pub modified: u64,
+    pub unchanged: u64,
+    // Rows with no document in the index, left alone
+    pub missing: u64,
+    pub duration_ms: u64,
+}
+
+impl CommandReport for AdminUpdateReport {
+    fn print_human(&self) {
+        println!(
+            "Scanned {} records in {}ms: {} modified, {} already correct, {} not in {}",
+            self.scanned, self.duration_ms, self.modified, self.unchanged, self.missing, self.index
+        );
+    }
+}
+
+// Fetch the current admin names for one batch and send updates for the
+// documents whose names differ
+async fn update_batch<B: SearchBackend>(
+    backend: &B,
+    index: &str,
+    batch: Vec<BulkDocument>,
+    error_log: &Path,
+    report: &mut AdminUpdateReport,
+) -> Result<(), Box<dyn Error>> {
+    let ids: Vec<String> = batch.iter().map(|doc| doc.id.clone()).collect();
+    let current = backend.get_fields(index, &ids, &ADMIN_FIELDS).await?;
+
+    let mut changed = Vec::new();
+    for doc in batch {
+        let Some(existing) = current.get(&doc.id) else {
+            report.missing += 1;
+            continue;
+        };
+        let same = ADMIN_FIELDS
+            .iter()
+            .all(|field| existing.get(*field).unwrap_or(&Value::Null) == &doc.body[*field]);
+        match same {
+            true => report.unchanged += 1,
+            false => changed.push(doc),
+        }
+    }
+
+    if changed.is_empty() {
+        return Ok(());
+    }
+
+    let count = changed.len() as u64;
+    let results = backend.bulk_update(index, changed).await?;
+    let failed: Vec<&BulkItemResult> = results.iter().filter(|item| !item.is_success()).collect();
+    if !failed.is_empty() {
+        write_error_log(&failed, error_log)?;
+        return Err(SeedError::Rejected {
+            failed: failed.len(),
+            error_log: error_log.to_path_buf(),
+        }
+        .into());
+    }
+    report.modified += count;
+
+    Ok(())
+}
+
+// Stream a dump and refresh only the admin names of documents already in the
+// index, for when the admin code files change but places don't
+pub async fn update_admin_fields<B: SearchBackend, R: Read>(
+    backend: &B,
+    index: &str,
+    (admin1, admin2): (&AdminLookup, &AdminLookup),
+    rdr: &mut csv::Reader<R>,
+    buffer: usize,
+    error_log: &Path,
+    progress: &dyn Progress,
+) -> Result<AdminUpdateReport, Box<dyn Error>> {
+    let started = Instant::now();
+    let task = progress.start_task(&format!("Updating admin names in {}", index), None);
+    let mut report = AdminUpdateReport {
+        index: index.to_string(),
+        ..AdminUpdateReport::default()
+    };
+    let mut batch = Vec::with_capacity(buffer);
+
+    for result in rdr.deserialize() {
+        let record: Location = result?;
+        let (admin1_name, admin2_name) = record.admin_names(admin1, admin2);
+        batch.push(BulkDocument {
+            id: record.id.to_string(),
+            body: json!({"admin1": admin1_name, "admin2": admin2_name}),
+        });
+        report.scanned += 1;
+
+        if batch.len() == buffer {
+            update_batch(backend, index, batch, error_log, &mut report).await?;
+            batch = Vec::with_capacity(buffer);
+            task.update(buffer as u64);
+        }
+    }
+
+    if !batch.is_empty() {
+        let count = batch.len() as u64;
+        update_batch(backend, index, batch, error_log, &mut report).await?;
+        task.update(count);
+    }
+    task.finish("done");
+
+    report.duration_ms = started.elapsed().as_millis() as u64;
+    Ok(report)
+}
@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::deploy::TrunkToml;
+use crate::doctor::Status;
+use crate::metadata::{Metadata, MetadataError, ProjectSite, SiteType};
+use crate::output::CommandReport;
+
+// The project toml with each site left as it is, so one site that does not
+// parse is reported on its own instead of failing the whole file
+#[derive(Deserialize)]
+struct RawMetadata {
+    source_dir: Option<PathBuf>,
+    #[serde(default)]
+    sites: Vec<toml::Value>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SiteValidation {
+    pub name: String,
+    // None when the site did not parse
+    pub site_type: Option<String>,
+    pub source: Option<PathBuf>,
+    pub status: Status,
+    pub problems: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ValidateReport {
+    pub project_toml: PathBuf,
+    pub sites: Vec<SiteValidation>,
+    // Problems that are not about a single site, such as dependency cycles
+    pub problems: Vec<String>,
+}
+
+impl ValidateReport {
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty() && self.sites.iter().all(|site| site.status == Status::Pass)
+    }
+}
+
+impl CommandReport for ValidateReport {
+    fn print_human(&self) {
+        println!("{}", self.project_toml.display());
+        println!("{:<20} {:<8} {:<6} problems", "site", "type", "status");
+        for site in &self.sites {
+            let status = match site.status {
+                Status::Pass => "pass",
+                Status::Warn => "warn",
+                Status::Fail => "FAIL",
+            };
+            println!(
+                "{:<20} {:<8} {:<6} {}",
+                site.name,
+                site.site_type.as_deref().unwrap_or("-"),
+                status,
+                site.problems.join("; ")
+            );
+        }
+        for problem in &self.problems {
+            println!("FAIL {}", problem);
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self.passed() {
+            true => 0,
+            false => 1,
+        }
+    }
+}
+
+// A static site is built by trunk from the Trunk.toml in its source, which
+// needs a [build] section for the dist directory
+fn check_trunk_toml(source: &Path) -> Option<String> {
+    let trunk_toml = source.join("Trunk.toml");
+    let file = match fs::read_to_string(&trunk_toml) {
+        Ok(file) => file,
+        Err(_) => return Some(format!("no Trunk.toml in {}", source.display())),
+    };
+    toml::from_str::<TrunkToml>(&file)
+        .err()
+        .map(|err| format!("{}: {}", trunk_toml.display(), err.message().trim_end()))
+}
+
+// Check every site of a project toml: that it parses, including its
+// site_type, that its name is unique, that its source exists and, for static
+// sites, that the source has a usable Trunk.toml
+pub fn validate_project(project_toml: &Path) -> Result<ValidateReport, Box<dyn Error>> {
+    let file = fs::read_to_string(project_toml)?;
+    let raw = toml::from_str::<RawMetadata>(&file)
+        .map_err(|err| MetadataError::parse(project_toml, &file, err))?;
+    let project_dir = raw.source_dir.clone().unwrap_or_else(|| {
+        project_toml
+            .parent()
+            .unwrap_or(Path::new("."))
+            .to_path_buf()
+    });
+
+    let mut report = ValidateReport {
+        project_toml: project_toml.to_path_buf(),
+        sites: Vec::new(),
+        problems: Vec::new(),
+    };
+    if raw.sites.is_empty() {
+        report.problems.push("no [[sites]] defined".to_string());
+    }
+
+    let mut names: HashMap<String, usize> = HashMap::new();
+    for site in &raw.sites {
+        if let Some(name) = site.get("name").and_then(toml::Value::as_str) {
+            *names.entry(name.to_string()).or_default() += 1;
+        }
+    }
+
+    let mut parsed = Vec::new();
+    for (i, site) in raw.sites.into_iter().enumerate() {
+        let name = site.get("name").and_then(toml::Value::as_str);
+        let mut validation = SiteValidation {
+            name: name.map_or_else(|| format!("<site {}>", i + 1), String::from),
+            site_type: None,
+            source: None,
+            status: Status::Pass,
+            problems: Vec::new(),
+        };
+        if let Some(count @ 2..) = name.and_then(|name| names.get(name).copied()) {
+            validation
+                .problems
+                .push(format!("name is used by {} sites", count));
+        }
+
+        match site.try_into::<ProjectSite>() {
+            Ok(site) => {
+                let source = project_dir.join(&site.source);
+                validation.site_type = Some(
+                    match site.site_type {
+                        SiteType::Static => "static",
+                        SiteType::Api => "api",
+                    }
+                    .to_string(),
+                );
+                if !source.is_dir() {
+                    validation
+                        .problems
+                        .push(format!("source {} does not exist", source.display()));
+                } else if let SiteType::Static = site.site_type {
+                    validation.problems.extend(check_trunk_toml(&source));
+                }
+                validation.source = Some(source);
+                parsed.push(site);
+            }
+            Err(err) => validation
+                .problems
+                .push(err.message().trim_end().to_string()),
+        }
+
+        if !validation.problems.is_empty() {
+            validation.status = Status::Fail;
+        }
+        report.sites.push(validation);
+    }
+
+    // Dependencies can only be followed once every site parsed
+    if parsed.len() == report.sites.len() {
+        let metadata = Metadata {
+            source_dir: raw.source_dir,
+            sites: parsed,
+        };
+        if let Err(err) = metadata.check_dependencies() {
+            report.problems.push(err.to_string());
+        }
+    }
+
+    Ok(report)
+}